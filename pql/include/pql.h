@@ -0,0 +1,55 @@
+#ifndef PQL_H
+#define PQL_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#if defined(PQL_FFI)
+/*
+ Parses `query`, returning the JSON serialization of the PQLQuery, or NULL if it has a syntax
+ error.
+
+ # Safety
+
+ `query` must be NULL or a valid NUL-terminated string.
+ */
+char *pql_parse(const char *query);
+#endif
+
+#if defined(PQL_FFI)
+/*
+ Computes the sensitivity and noise scale of each column released by `query`, given the JSON
+ policy file `policies`. Returns `{"ok": true, "statements": [...]}`, or NULL if the query or
+ policies are invalid.
+
+ # Safety
+
+ `query` and `policies` must be NULL or valid NUL-terminated strings.
+ */
+char *pql_sensitivity(const char *query, const char *policies);
+#endif
+
+#if defined(PQL_FFI)
+/*
+ Returns the message of the last error on this thread, or NULL if the last call succeeded. The
+ string is owned by the library and is valid until the next call on this thread; don't free it.
+ */
+const char *pql_last_error(void);
+#endif
+
+#if defined(PQL_FFI)
+/*
+ Frees a string returned by pql_parse or pql_sensitivity. Does nothing if `s` is NULL.
+
+ # Safety
+
+ `s` must be NULL or a string returned by this library that hasn't been freed yet.
+ */
+void pql_free(char *s);
+#endif
+
+#endif  /* PQL_H */
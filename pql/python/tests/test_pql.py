@@ -0,0 +1,106 @@
+# Tests for the Python bindings. Build them first with `maturin develop`, then run
+# `python -m unittest discover python/tests`.
+import math
+import unittest
+
+import pql
+
+QUERY = """SPLIT cam1 BEGIN 0 END 60 BY TIME 5sec INTO chunks1;
+PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 10 ROWS
+    WITH SCHEMA (plate:STRING=null, speed:NUMBER=0) INTO table1;
+SELECT count(plate), sum(speed,l=0,u=10) FROM table1;
+"""
+
+POLICIES = {"cam1": {"k_segments": 1, "rho": "30sec", "epsilon": 0.5, "budget": 1.0}}
+
+
+class TestBuild(unittest.TestCase):
+    def test_build(self):
+        query = pql.build(QUERY)
+        self.assertEqual(len(query["select_stmts"]), 1)
+        self.assertEqual(len(query["split_stmts"]), 1)
+
+    def test_syntax_error(self):
+        with self.assertRaises(pql.PQLError) as cm:
+            pql.build("SPLIT cam1 BEGIN;")
+        self.assertIn("--> 1:", str(cm.exception))
+
+    def test_validate(self):
+        self.assertEqual(pql.validate(QUERY), [])
+        diags = pql.validate(QUERY.replace("count(plate)", "sum(plate)"), POLICIES)
+        self.assertEqual(len(diags), 2)
+        self.assertIn("requires a NUMBER column", diags[0]["message"])
+        self.assertEqual(diags[0]["span"]["line"], 4)
+
+
+class TestSensitivity(unittest.TestCase):
+    def test_sensitivity(self):
+        [stmt] = pql.sensitivity(QUERY, POLICIES)
+        self.assertEqual(stmt["cameras"], ["cam1"])
+        self.assertEqual(stmt["epsilon"], 0.5)
+        self.assertEqual(
+            stmt["columns"],
+            [
+                {
+                    "name": "count(plate)",
+                    "sensitivity": 70.0,
+                    "l2_sensitivity": 70.0,
+                    "epsilon": 0.25,
+                    "noise_scale": 280.0,
+                },
+                {
+                    "name": "sum(speed,l=0,u=10)",
+                    "sensitivity": 700.0,
+                    "l2_sensitivity": 700.0,
+                    "epsilon": 0.25,
+                    "noise_scale": 2800.0,
+                },
+            ],
+        )
+
+    def test_missing_policy(self):
+        with self.assertRaises(pql.PQLError) as cm:
+            pql.sensitivity(QUERY.replace("cam1", "cam9"), POLICIES)
+        self.assertIn("no privacy policy for camera `cam9`", str(cm.exception))
+
+    def test_invalid_policy(self):
+        with self.assertRaises(pql.PQLError):
+            pql.sensitivity(QUERY, {"cam1": {"k_segments": 0, "rho": "30sec", "epsilon": 0.5}})
+
+    def test_accuracy(self):
+        [stmt] = pql.accuracy(QUERY, POLICIES, epsilon=1.0)
+        count = stmt["columns"][0]
+        self.assertEqual(count["noise_scale"], 140.0)
+        self.assertEqual([i["confidence"] for i in count["intervals"]], [0.9, 0.95, 0.99])
+        self.assertAlmostEqual(count["intervals"][2]["half_width"], 140.0 * math.log(100))
+
+        [stmt] = pql.accuracy(QUERY, POLICIES, epsilon=1.0, mechanism="gaussian")
+        count = stmt["columns"][0]
+        self.assertAlmostEqual(count["noise_scale"], 140.0)
+        self.assertAlmostEqual(count["intervals"][1]["half_width"], 140.0 * 1.959964, places=3)
+        with self.assertRaises(pql.PQLError):
+            pql.accuracy(QUERY, POLICIES, mechanism="exponential")
+
+
+class TestBudgetLedger(unittest.TestCase):
+    def test_charge(self):
+        ledger = pql.BudgetLedger.from_policies(POLICIES)
+        [stmt] = pql.sensitivity(QUERY, POLICIES)
+        ledger.charge({cam: stmt["epsilon"] for cam in stmt["cameras"]})
+        self.assertEqual(ledger.remaining("cam1"), 0.5)
+        self.assertEqual(ledger.spent("cam1"), 0.5)
+        ledger.charge({"cam1": 0.5})
+        with self.assertRaises(pql.PQLError):
+            ledger.charge({"cam1": 0.5})
+        self.assertEqual(ledger.remaining("cam1"), 0.0)
+
+    def test_atomic(self):
+        ledger = pql.BudgetLedger({"cam1": 1.0, "cam2": 0.1})
+        with self.assertRaises(pql.PQLError):
+            ledger.charge({"cam1": 0.5, "cam2": 0.5})
+        self.assertEqual(ledger.remaining("cam1"), 1.0)
+        self.assertIsNone(ledger.remaining("cam3"))
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,256 @@
+/*
+ * Privacy accounting: how the cost of many releases from the same camera adds up. With pure
+ * composition the epsilons are summed, which quickly exhausts the budget of cameras with many
+ * windowed releases. Advanced composition and zero-concentrated DP (zCDP) give much tighter
+ * totals, at the price of a small failure probability `delta`. zCDP is also the natural way to
+ * account for the Gaussian mechanism.
+ *
+ * Each release has a PrivacyCost, and an Accountant turns the costs of every release so far into
+ * the total epsilon spent, which the BudgetLedger compares to the budget of the camera.
+ */
+
+// Privacy cost of a single release
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum PrivacyCost {
+    // epsilon-DP, e.g. the Laplace mechanism
+    Pure { epsilon: f64 },
+    // rho-zCDP, e.g. the Gaussian mechanism
+    Concentrated { rho: f64 },
+}
+
+impl PrivacyCost {
+    // Every epsilon-DP release is also (epsilon^2 / 2)-zCDP
+    fn rho(&self) -> f64 {
+        match self {
+            PrivacyCost::Pure { epsilon } => epsilon * epsilon / 2.0,
+            PrivacyCost::Concentrated { rho } => *rho,
+        }
+    }
+}
+
+// Noise added to a released value
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Mechanism {
+    // Scaled to the L1 sensitivity, epsilon-DP
+    #[default]
+    Laplace,
+    // Scaled to the L2 sensitivity, (epsilon^2 / 2)-zCDP, so as private as a single Laplace
+    // release but composing much better under zCDP accounting
+    Gaussian,
+    // Scaled to the L2 sensitivity so that a single release is (epsilon, delta)-DP. Much less
+    // noise than the Laplace mechanism for releases with many values.
+    ApproxGaussian {
+        delta: f64,
+    },
+}
+
+impl Mechanism {
+    /// The mechanism called `name`, `laplace` or `gaussian`. A Gaussian mechanism with `delta`
+    /// is ApproxGaussian, (epsilon, delta)-DP.
+    pub fn from_name(name: &str, delta: Option<f64>) -> Result<Mechanism, String> {
+        match (name, delta) {
+            ("laplace", None) => Ok(Mechanism::Laplace),
+            ("laplace", Some(_)) => Err(String::from("the laplace mechanism has no delta")),
+            ("gaussian", None) => Ok(Mechanism::Gaussian),
+            ("gaussian", Some(delta)) if delta > 0.0 && delta < 1.0 => {
+                Ok(Mechanism::ApproxGaussian { delta })
+            }
+            ("gaussian", Some(_)) => Err(String::from("delta must be between 0 and 1")),
+            (name, _) => Err(format!(
+                "unknown mechanism '{}', expected laplace or gaussian",
+                name
+            )),
+        }
+    }
+
+    /// Cost of a release made with this mechanism, for the epsilon of its policy
+    pub fn cost(&self, epsilon: f64) -> PrivacyCost {
+        match self {
+            Mechanism::Laplace => PrivacyCost::Pure { epsilon },
+            Mechanism::Gaussian => PrivacyCost::Concentrated {
+                rho: epsilon * epsilon / 2.0,
+            },
+            Mechanism::ApproxGaussian { delta } => PrivacyCost::Concentrated {
+                rho: zcdp_rho(epsilon, *delta),
+            },
+        }
+    }
+}
+
+/// Largest rho such that rho-zCDP implies (epsilon, delta)-DP, i.e. the solution of
+/// rho + 2 sqrt(rho ln(1/delta)) = epsilon
+pub fn zcdp_rho(epsilon: f64, delta: f64) -> f64 {
+    let log = (1.0 / delta).ln();
+    ((epsilon + log).sqrt() - log.sqrt()).powi(2)
+}
+
+/// Standard deviation of the Gaussian noise that makes a release with the given L2 sensitivity
+/// rho-zCDP
+pub fn gaussian_sigma(l2_sensitivity: f64, rho: f64) -> f64 {
+    l2_sensitivity / (2.0 * rho).sqrt()
+}
+
+pub trait Accountant {
+    /// Total epsilon spent by releases with the given costs. Infinite if the costs can't be
+    /// accounted for, e.g. Gaussian releases under pure composition.
+    fn epsilon(&self, costs: &[PrivacyCost]) -> f64;
+}
+
+// Sum of the epsilons, (epsilon, 0)-DP
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PureComposition;
+
+impl Accountant for PureComposition {
+    fn epsilon(&self, costs: &[PrivacyCost]) -> f64 {
+        costs
+            .iter()
+            .map(|c| match c {
+                PrivacyCost::Pure { epsilon } => *epsilon,
+                PrivacyCost::Concentrated { .. } => f64::INFINITY,
+            })
+            .sum()
+    }
+}
+
+// Advanced composition theorem (Dwork, Rothblum and Vadhan) for pure releases, (epsilon, delta)-DP.
+// Never worse than pure composition.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdvancedComposition {
+    pub delta: f64,
+}
+
+impl Accountant for AdvancedComposition {
+    fn epsilon(&self, costs: &[PrivacyCost]) -> f64 {
+        let pure = PureComposition.epsilon(costs);
+        if !pure.is_finite() {
+            return pure;
+        }
+        let (mut squares, mut excess) = (0.0, 0.0);
+        for c in costs {
+            if let PrivacyCost::Pure { epsilon } = c {
+                squares += epsilon * epsilon;
+                excess += epsilon * epsilon.exp_m1();
+            }
+        }
+        let advanced = (2.0 * (1.0 / self.delta).ln() * squares).sqrt() + excess;
+        pure.min(advanced)
+    }
+}
+
+// Sum of the rhos of every release under zCDP, converted to (epsilon, delta)-DP
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZcdpComposition {
+    pub delta: f64,
+}
+
+impl Accountant for ZcdpComposition {
+    fn epsilon(&self, costs: &[PrivacyCost]) -> f64 {
+        let rho: f64 = costs.iter().map(|c| c.rho()).sum();
+        let zcdp = rho + 2.0 * (rho * (1.0 / self.delta).ln()).sqrt();
+        // Pure releases alone are never worse than their sum
+        zcdp.min(PureComposition.epsilon(costs))
+    }
+}
+
+// Accountant used by a BudgetLedger
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Accounting {
+    #[default]
+    Pure,
+    Advanced {
+        delta: f64,
+    },
+    Zcdp {
+        delta: f64,
+    },
+}
+
+impl Accountant for Accounting {
+    fn epsilon(&self, costs: &[PrivacyCost]) -> f64 {
+        match self {
+            Accounting::Pure => PureComposition.epsilon(costs),
+            Accounting::Advanced { delta } => AdvancedComposition { delta: *delta }.epsilon(costs),
+            Accounting::Zcdp { delta } => ZcdpComposition { delta: *delta }.epsilon(costs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounting::*;
+
+    fn releases(n: usize, cost: PrivacyCost) -> Vec<PrivacyCost> {
+        vec![cost; n]
+    }
+
+    #[test]
+    fn composition() {
+        let laplace = releases(100, Mechanism::Laplace.cost(0.1));
+        assert!((PureComposition.epsilon(&laplace) - 10.0).abs() < 1e-9);
+
+        // sqrt(2 ln(1e6) * 100 * 0.01) + 100 * 0.1 * (e^0.1 - 1)
+        let advanced = AdvancedComposition { delta: 1e-6 }.epsilon(&laplace);
+        assert!((advanced - 6.308).abs() < 1e-3, "{}", advanced);
+
+        // rho = 100 * 0.005 = 0.5, 0.5 + 2 sqrt(0.5 ln(1e6))
+        let zcdp = ZcdpComposition { delta: 1e-6 }.epsilon(&laplace);
+        assert!((zcdp - 5.756).abs() < 1e-3, "{}", zcdp);
+
+        // A handful of releases are cheaper under pure composition
+        let few = releases(2, Mechanism::Laplace.cost(0.1));
+        assert_eq!(AdvancedComposition { delta: 1e-6 }.epsilon(&few), 0.2);
+        assert_eq!(ZcdpComposition { delta: 1e-6 }.epsilon(&few), 0.2);
+    }
+
+    #[test]
+    fn gaussian() {
+        let gaussian = releases(100, Mechanism::Gaussian.cost(0.1));
+        assert_eq!(PureComposition.epsilon(&gaussian), f64::INFINITY);
+        assert_eq!(
+            AdvancedComposition { delta: 1e-6 }.epsilon(&gaussian),
+            f64::INFINITY
+        );
+        let zcdp = Accounting::Zcdp { delta: 1e-6 }.epsilon(&gaussian);
+        assert!((zcdp - 5.756).abs() < 1e-3, "{}", zcdp);
+
+        // sigma = sensitivity / epsilon
+        assert!((gaussian_sigma(2.0, 0.1 * 0.1 / 2.0) - 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mechanism_names() {
+        assert_eq!(
+            Mechanism::from_name("laplace", None),
+            Ok(Mechanism::Laplace)
+        );
+        assert_eq!(
+            Mechanism::from_name("gaussian", None),
+            Ok(Mechanism::Gaussian)
+        );
+        assert_eq!(
+            Mechanism::from_name("gaussian", Some(1e-6)),
+            Ok(Mechanism::ApproxGaussian { delta: 1e-6 })
+        );
+        assert!(Mechanism::from_name("laplace", Some(1e-6)).is_err());
+        assert!(Mechanism::from_name("gaussian", Some(2.0)).is_err());
+        assert!(Mechanism::from_name("exponential", None).is_err());
+    }
+
+    #[test]
+    fn approx_gaussian() {
+        let rho = match (Mechanism::ApproxGaussian { delta: 1e-6 }).cost(1.0) {
+            PrivacyCost::Concentrated { rho } => rho,
+            cost => panic!("expected a zCDP cost, got {:?}", cost),
+        };
+        // A single release is exactly (1, 1e-6)-DP
+        let epsilon = ZcdpComposition { delta: 1e-6 }.epsilon(&[PrivacyCost::Concentrated { rho }]);
+        assert!((epsilon - 1.0).abs() < 1e-9, "{}", epsilon);
+        assert!((rho - 0.01747).abs() < 1e-4, "{}", rho);
+    }
+}
@@ -0,0 +1,312 @@
+/*
+ * Expected accuracy of a query, computed before running it so that queries whose results would
+ * be swamped by noise can be rejected or tuned without spending any budget. With the Laplace
+ * mechanism, each released column gets noise with the noise scale b computed by the sensitivity
+ * calculation, so the noise is within b ln(1 / (1 - c)) of the true value with probability c. With
+ * the Gaussian mechanisms, it gets normal noise of standard deviation sigma (gaussian_sigma of its
+ * L2 sensitivity), which is within sigma z((1 + c) / 2) with probability c, z being the quantile
+ * function of the standard normal distribution.
+ *
+ * For mean(), the error is also given relative to the range of the averaged values: its
+ * sensitivity is divided by the number of values averaged (the size constraint of its table), so
+ * this shows whether there are enough values for the mean to be useful.
+ */
+use crate::accounting::{gaussian_sigma, Mechanism, PrivacyCost};
+use crate::aggregation::AstNode;
+use crate::parser::PQLQuery;
+use crate::policy::PolicyMap;
+use crate::sensitivity::{
+    query_sensitivity, release_sensitivity, sensitivity_composition, ColumnInfo, ColumnRelease,
+};
+
+// Confidence levels of the intervals reported for each column
+pub const CONFIDENCE_LEVELS: [f64; 3] = [0.90, 0.95, 0.99];
+
+// The released value is within `half_width` of the true value with probability `confidence`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConfidenceInterval {
+    pub confidence: f64,
+    pub half_width: f64,
+    // half_width as a fraction of the range of the averaged values, for mean() only
+    pub relative_error: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ColumnAccuracy {
+    pub name: String,
+    // Scale of the Laplace noise, or standard deviation of the Gaussian noise. None for columns
+    // released without noise (e.g. bin() and GROUP BY .. WITH KEYS keys).
+    pub noise_scale: Option<f64>,
+    pub intervals: Vec<ConfidenceInterval>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StatementAccuracy {
+    // The statement, formatted as canonical PQL
+    pub query: String,
+    pub epsilon: Option<f64>,
+    pub columns: Vec<ColumnAccuracy>,
+}
+
+/// Half width of the interval around 0 containing Laplace noise of scale `noise_scale` with
+/// probability `confidence`
+pub fn laplace_half_width(noise_scale: f64, confidence: f64) -> f64 {
+    -noise_scale * (1.0 - confidence).ln()
+}
+
+/// Half width of the interval around 0 containing normal noise of standard deviation `sigma` with
+/// probability `confidence`
+pub fn gaussian_half_width(sigma: f64, confidence: f64) -> f64 {
+    sigma * normal_quantile((1.0 + confidence) / 2.0)
+}
+
+// Quantile function of the standard normal distribution for 0 < p < 1, with Acklam's rational
+// approximation (relative error below 1.2e-9)
+fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969_683_028_665_376e1,
+        2.209_460_984_245_205e2,
+        -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2,
+        -3.066_479_806_614_716e1,
+        2.506_628_277_459_239,
+    ];
+    const B: [f64; 5] = [
+        -5.447_609_879_822_406e1,
+        1.615_858_368_580_409e2,
+        -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1,
+        -1.328_068_155_288_572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784_894_002_430_293e-3,
+        -3.223_964_580_411_365e-1,
+        -2.400_758_277_161_838,
+        -2.549_732_539_343_734,
+        4.374_664_141_464_968,
+        2.938_163_982_698_783,
+    ];
+    const D: [f64; 4] = [
+        7.784_695_709_041_462e-3,
+        3.224_671_290_700_398e-1,
+        2.445_134_137_142_996,
+        3.754_408_661_907_416,
+    ];
+    // Tails, the upper one by symmetry
+    let tail = |q: f64| {
+        let q = (-2.0 * q.ln()).sqrt();
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+    if p < 0.02425 {
+        tail(p)
+    } else if p > 1.0 - 0.02425 {
+        -tail(1.0 - p)
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    }
+}
+
+/// Expected error of every column released by each SELECT in `query`, in order. With `epsilon`,
+/// every statement is estimated as if it had `WITH EPSILON epsilon`, otherwise with the epsilon it
+/// would be allocated (see sensitivity::allocate_epsilon). Each column is estimated as released
+/// on its own with noise from `mechanism` (see release::release_with). The query should have been
+/// validated first.
+pub fn estimate_accuracy(
+    query: &PQLQuery,
+    policies: &PolicyMap,
+    epsilon: Option<f64>,
+    mechanism: Mechanism,
+) -> Vec<StatementAccuracy> {
+    let statements = match epsilon {
+        Some(e) => query
+            .select_stmts
+            .iter()
+            .map(|stmt| release_sensitivity(stmt, policies, Some(e)))
+            .collect(),
+        None => query_sensitivity(query, policies),
+    };
+    query
+        .select_stmts
+        .iter()
+        .zip(statements)
+        .map(|(stmt, sens)| StatementAccuracy {
+            columns: sens
+                .columns
+                .iter()
+                .map(|c| {
+                    let width = mean_range(stmt, &c.name, policies).map(|(l, u)| u - l);
+                    let noise = noise(c, mechanism);
+                    ColumnAccuracy {
+                        name: c.name.clone(),
+                        noise_scale: noise.map(|(scale, _)| scale),
+                        intervals: noise.map_or(vec![], |(scale, half_width)| {
+                            intervals(scale, half_width, width)
+                        }),
+                    }
+                })
+                .collect(),
+            query: sens.query,
+            epsilon: sens.epsilon,
+        })
+        .collect()
+}
+
+type HalfWidth = fn(f64, f64) -> f64;
+
+// Scale of the noise `mechanism` adds to column `c`, and the half width of its intervals
+fn noise(c: &ColumnRelease, mechanism: Mechanism) -> Option<(f64, HalfWidth)> {
+    match mechanism.cost(c.epsilon?) {
+        PrivacyCost::Pure { .. } => Some((c.noise_scale?, laplace_half_width)),
+        PrivacyCost::Concentrated { rho } => {
+            Some((gaussian_sigma(c.l2_sensitivity?, rho), gaussian_half_width))
+        }
+    }
+}
+
+fn intervals(
+    noise_scale: f64,
+    half_width: HalfWidth,
+    width: Option<f64>,
+) -> Vec<ConfidenceInterval> {
+    CONFIDENCE_LEVELS
+        .iter()
+        .map(|&confidence| {
+            let half_width = half_width(noise_scale, confidence);
+            ConfidenceInterval {
+                confidence,
+                half_width,
+                relative_error: width.filter(|w| *w > 0.0).map(|w| half_width / w),
+            }
+        })
+        .collect()
+}
+
+// Bounds of the values averaged by the column `name` of `stmt`, if it is a mean()
+fn mean_range(stmt: &AstNode, name: &str, policies: &PolicyMap) -> Option<(f64, f64)> {
+    let (exprs, from) = match stmt {
+        AstNode::Select { exprs, from, .. } => (exprs, from),
+        _ => return None,
+    };
+    exprs.iter().find_map(|e| match e {
+        AstNode::Aggregation {
+            function,
+            inner,
+            range,
+            alias,
+            ..
+        } if alias == name && function.to_lowercase() == "mean" => {
+            range.or_else(
+                || match sensitivity_composition(from, policies).columns.get(inner) {
+                    Some(ColumnInfo::Range(range)) => Some(*range),
+                    _ => None,
+                },
+            )
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounting::gaussian_sigma;
+    use crate::accuracy::*;
+    use crate::parser::build;
+    use crate::policy::PrivacyPolicy;
+
+    const TABLE: &str = "SPLIT cam1 BEGIN 0 END 60 BY TIME 5sec INTO chunks1;
+PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 10 ROWS
+    WITH SCHEMA (plate:STRING=null, speed:NUMBER=0) INTO table1;
+";
+
+    fn policies() -> PolicyMap {
+        vec![(
+            String::from("cam1"),
+            PrivacyPolicy::Static {
+                k_segments: 1,
+                rho_ms: 30_000,
+                epsilon: 0.5,
+            },
+        )]
+        .into_iter()
+        .collect()
+    }
+
+    fn estimate(select: &str, epsilon: Option<f64>) -> Vec<StatementAccuracy> {
+        let query = build(&format!("{}{}", TABLE, select)).unwrap();
+        estimate_accuracy(&query, &policies(), epsilon, Mechanism::Laplace)
+    }
+
+    #[test]
+    fn laplace_intervals() {
+        // The 95% interval of Laplace(1) is about +-3
+        assert!((laplace_half_width(1.0, 0.95) - 2.9957).abs() < 1e-4);
+
+        let accuracy = estimate("SELECT count(plate) FROM table1;", None);
+        let column = &accuracy[0].columns[0];
+        assert_eq!(column.noise_scale, Some(140.0));
+        let confidence: Vec<f64> = column.intervals.iter().map(|i| i.confidence).collect();
+        assert_eq!(confidence, CONFIDENCE_LEVELS.to_vec());
+        assert!((column.intervals[2].half_width - 140.0 * 100f64.ln()).abs() < 1e-9);
+        assert_eq!(column.intervals[2].relative_error, None);
+
+        // Twice the epsilon halves the error
+        let accuracy = estimate("SELECT count(plate) FROM table1;", Some(1.0));
+        assert_eq!(accuracy[0].columns[0].noise_scale, Some(70.0));
+    }
+
+    #[test]
+    fn gaussian_intervals() {
+        // The 95% interval of N(0, 1) is about +-1.96
+        assert!((gaussian_half_width(1.0, 0.95) - 1.959964).abs() < 1e-6);
+        assert!((gaussian_half_width(2.0, 0.99) - 2.0 * 2.575829).abs() < 1e-5);
+        assert!((normal_quantile(0.01) + 2.326348).abs() < 1e-6);
+
+        let query = build(&format!("{}SELECT count(plate) FROM table1;", TABLE)).unwrap();
+        let gaussian = estimate_accuracy(&query, &policies(), Some(1.0), Mechanism::Gaussian);
+        let column = &gaussian[0].columns[0];
+        // Sensitivity 70, rho = 1/2, so sigma = 70
+        assert_eq!(column.noise_scale, Some(70.0));
+        assert!((column.intervals[1].half_width - 70.0 * 1.959964).abs() < 1e-3);
+
+        // (1, 1e-6)-DP needs more noise than 1/2-zCDP
+        let approx = estimate_accuracy(
+            &query,
+            &policies(),
+            Some(1.0),
+            Mechanism::ApproxGaussian { delta: 1e-6 },
+        );
+        let sigma = approx[0].columns[0].noise_scale.unwrap();
+        assert!(
+            (sigma - gaussian_sigma(70.0, 0.01747)).abs() < 1.0,
+            "{}",
+            sigma
+        );
+    }
+
+    #[test]
+    fn mean_relative_error() {
+        let accuracy = estimate(
+            "SELECT mean(speed,l=0,u=10) FROM table1 LIMIT 1000;",
+            Some(1.0),
+        );
+        let column = &accuracy[0].columns[0];
+        // 10 * 70 / 1000
+        assert_eq!(column.noise_scale, Some(0.7));
+        let relative = column.intervals[1].relative_error.unwrap();
+        assert!((relative - 0.07 * 20f64.ln()).abs() < 1e-9, "{}", relative);
+
+        let keys = estimate(
+            "SELECT plate, count(speed) FROM table1 GROUP BY plate WITH KEYS (abc, def);",
+            None,
+        );
+        assert_eq!(keys[0].columns[0].noise_scale, None);
+        assert!(keys[0].columns[0].intervals.is_empty());
+    }
+}
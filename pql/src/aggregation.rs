@@ -2,7 +2,11 @@ use crate::parser::*;
 use crate::process::ProcessStatement;
 use crate::split::SplitStatement;
 
+use std::collections::HashMap;
+
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum BooleanOp {
     GreaterThan,
     GreaterThanOrEqualTo,
@@ -16,6 +20,8 @@ pub enum BooleanOp {
 }
 
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum ArithmeticOp {
     Plus,
     Minus,
@@ -25,6 +31,8 @@ pub enum ArithmeticOp {
 }
 
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum GroupByAttr {
     Column(String),
     Bin {
@@ -35,24 +43,30 @@ pub enum GroupByAttr {
 }
 
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum AstNode {
     Select {
         exprs: Vec<AstNode>,
         from: Box<AstNode>,
         qualifiers: Vec<AstNode>,
         // TODO add window
+        span: Span,
     },
     Intersect {
         tables: Vec<AstNode>,
         on: Box<AstNode>,
+        span: Span,
     },
     Union {
         tables: Vec<AstNode>,
         on: Box<AstNode>,
+        span: Span,
     },
     Equijoin {
         tables: Vec<AstNode>,
         on: Box<AstNode>,
+        span: Span,
     },
     Aggregation {
         function: String,
@@ -60,47 +74,230 @@ pub enum AstNode {
         inner: String,
         range: Option<(f64, f64)>,
         alias: String,
+        // Share of the statement's epsilon set with `EPSILON`, if any
+        epsilon: Option<f64>,
+        span: Span,
     },
     UserF {
         function: String,
         column: String,
         range: Option<(f64, f64)>,
         alias: String,
+        epsilon: Option<f64>,
+        span: Span,
     },
     AliasExpr {
         lhs: Box<AstNode>,
         op: ArithmeticOp,
         rhs: Box<AstNode>,
         alias: String,
+        span: Span,
     },
     GroupBy {
         // TODO make string or special? (e.g. day(chunk))
         attrs: Vec<GroupByAttr>,
+        span: Span,
     },
     GroupByWithKeys {
         attr: String,
         keys: Vec<String>,
+        span: Span,
     },
     Limit {
         rows: u64,
+        span: Span,
     },
     Where {
         predicate: Box<AstNode>,
+        span: Span,
     },
     Predicate {
         lhs: Box<AstNode>,
         op: BooleanOp,
         rhs: Box<AstNode>,
+        span: Span,
     },
-    TableNames(Vec<String>),
-    Tables(Vec<AstNode>),
+    TableNames(Vec<String>, Span),
+    Tables(Vec<AstNode>, Span),
+    // Spans for base tables are stored in their statements
     Table(SplitStatement, ProcessStatement),
-    Column(String),
-    Value(f64),
-    DurationMs(u64),
+    Column(String, Span),
+    Value(f64, Span),
+    DurationMs(u64, Span),
+    // WITH EPSILON, the total epsilon of a statement
+    Epsilon(f64, Span),
+}
+
+impl AstNode {
+    // Location of this node in the original query string
+    pub fn span(&self) -> Span {
+        match self {
+            AstNode::Select { span, .. }
+            | AstNode::Intersect { span, .. }
+            | AstNode::Union { span, .. }
+            | AstNode::Equijoin { span, .. }
+            | AstNode::Aggregation { span, .. }
+            | AstNode::UserF { span, .. }
+            | AstNode::AliasExpr { span, .. }
+            | AstNode::GroupBy { span, .. }
+            | AstNode::GroupByWithKeys { span, .. }
+            | AstNode::Limit { span, .. }
+            | AstNode::Where { span, .. }
+            | AstNode::Predicate { span, .. }
+            | AstNode::TableNames(_, span)
+            | AstNode::Tables(_, span)
+            | AstNode::Column(_, span)
+            | AstNode::Value(_, span)
+            | AstNode::DurationMs(_, span)
+            | AstNode::Epsilon(_, span) => *span,
+            AstNode::Table(_, ps) => ps.span,
+        }
+    }
+
+    // Copy of this node with every span reset, to compare ASTs parsed from different strings
+    #[cfg(test)]
+    pub(crate) fn without_spans(&self) -> AstNode {
+        let mut node = self.clone();
+        node.clear_spans();
+        node
+    }
+
+    #[cfg(test)]
+    fn clear_spans(&mut self) {
+        match self {
+            AstNode::Select {
+                exprs,
+                from,
+                qualifiers,
+                span,
+            } => {
+                *span = Span::default();
+                from.clear_spans();
+                for node in exprs.iter_mut().chain(qualifiers.iter_mut()) {
+                    node.clear_spans();
+                }
+            }
+            AstNode::Intersect { tables, on, span }
+            | AstNode::Union { tables, on, span }
+            | AstNode::Equijoin { tables, on, span } => {
+                *span = Span::default();
+                on.clear_spans();
+                for t in tables {
+                    t.clear_spans();
+                }
+            }
+            AstNode::AliasExpr { lhs, rhs, span, .. }
+            | AstNode::Predicate { lhs, rhs, span, .. } => {
+                *span = Span::default();
+                lhs.clear_spans();
+                rhs.clear_spans();
+            }
+            AstNode::Where { predicate, span } => {
+                *span = Span::default();
+                predicate.clear_spans();
+            }
+            AstNode::Tables(tables, span) => {
+                *span = Span::default();
+                for t in tables {
+                    t.clear_spans();
+                }
+            }
+            AstNode::Table(ss, ps) => {
+                ss.span = Span::default();
+                ps.span = Span::default();
+            }
+            AstNode::Aggregation { span, .. }
+            | AstNode::UserF { span, .. }
+            | AstNode::GroupBy { span, .. }
+            | AstNode::GroupByWithKeys { span, .. }
+            | AstNode::Limit { span, .. }
+            | AstNode::TableNames(_, span)
+            | AstNode::Column(_, span)
+            | AstNode::Value(_, span)
+            | AstNode::DurationMs(_, span)
+            | AstNode::Epsilon(_, span) => *span = Span::default(),
+        }
+    }
+
+    // Names of the cameras whose video this node reads, sorted and without duplicates
+    pub fn cameras(&self) -> Vec<String> {
+        let mut cameras = vec![];
+        self.collect_cameras(&mut cameras);
+        cameras.sort();
+        cameras.dedup();
+        cameras
+    }
+
+    fn collect_cameras(&self, cameras: &mut Vec<String>) {
+        match self {
+            AstNode::Table(ss, _) => cameras.push(ss.camera_name.clone()),
+            AstNode::Tables(tables, _)
+            | AstNode::Intersect { tables, .. }
+            | AstNode::Union { tables, .. }
+            | AstNode::Equijoin { tables, .. } => {
+                for t in tables {
+                    t.collect_cameras(cameras);
+                }
+            }
+            AstNode::Select { from, .. } => from.collect_cameras(cameras),
+            _ => {}
+        }
+    }
+
+    // Total epsilon of a Select, if set with WITH EPSILON
+    pub fn statement_epsilon(&self) -> Option<f64> {
+        match self {
+            AstNode::Select { qualifiers, .. } => qualifiers.iter().find_map(|q| match q {
+                AstNode::Epsilon(e, _) => Some(*e),
+                _ => None,
+            }),
+            _ => None,
+        }
+    }
+
+    // Epsilon of the output columns of a Select that set it with EPSILON, by column name
+    pub fn column_epsilons(&self) -> HashMap<String, f64> {
+        match self {
+            AstNode::Select { exprs, .. } => exprs
+                .iter()
+                .filter_map(|e| match e {
+                    AstNode::Aggregation {
+                        alias,
+                        epsilon: Some(epsilon),
+                        ..
+                    }
+                    | AstNode::UserF {
+                        alias,
+                        epsilon: Some(epsilon),
+                        ..
+                    } => Some((alias.clone(), *epsilon)),
+                    _ => None,
+                })
+                .collect(),
+            _ => HashMap::new(),
+        }
+    }
+
+    // Names of the columns output by a Select, in the order they are listed
+    pub fn output_columns(&self) -> Vec<String> {
+        match self {
+            AstNode::Select { exprs, .. } => exprs
+                .iter()
+                .filter_map(|e| match e {
+                    AstNode::Column(c, _) => Some(c.clone()),
+                    AstNode::Aggregation { alias, .. }
+                    | AstNode::UserF { alias, .. }
+                    | AstNode::AliasExpr { alias, .. } => Some(alias.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
 }
 
 pub fn build_agg_ast(pair: pest::iterators::Pair<Rule>) -> AstNode {
+    let span = Span::from(pair.as_span());
     match pair.as_rule() {
         Rule::aggregation_stmt => {
             let mut pairs = pair.into_inner();
@@ -112,21 +309,22 @@ pub fn build_agg_ast(pair: pest::iterators::Pair<Rule>) -> AstNode {
                 .collect();
             let table = build_agg_ast(pairs.next().unwrap());
             let qualifiers = pairs
-                .into_iter()
                 .map(|pair| build_agg_ast(pair.into_inner().next().unwrap()))
                 .collect();
             AstNode::Select {
                 exprs,
                 from: Box::new(table),
                 qualifiers,
+                span,
             }
         }
         Rule::table => {
             let pair = pair.into_inner().next().unwrap();
             match pair.as_rule() {
-                Rule::table_list => {
-                    AstNode::TableNames(pair.into_inner().map(|t| t.as_str().to_string()).collect())
-                }
+                Rule::table_list => AstNode::TableNames(
+                    pair.into_inner().map(|t| t.as_str().to_string()).collect(),
+                    span,
+                ),
                 _ => build_agg_ast(pair),
             }
         }
@@ -134,34 +332,46 @@ pub fn build_agg_ast(pair: pest::iterators::Pair<Rule>) -> AstNode {
             let mut pairs = pair.into_inner().rev();
             let on = Box::new(build_agg_ast(pairs.next().unwrap()));
             let tables = pairs.map(build_agg_ast).collect();
-            AstNode::Intersect { tables, on }
+            AstNode::Intersect { tables, on, span }
         }
         Rule::table_union => {
             let mut pairs = pair.into_inner().rev();
             let on = Box::new(build_agg_ast(pairs.next().unwrap()));
             let tables = pairs.map(build_agg_ast).collect();
-            AstNode::Union { tables, on }
+            AstNode::Union { tables, on, span }
         }
         Rule::table_equijoin => {
             let mut pairs = pair.into_inner().rev();
             let on = Box::new(build_agg_ast(pairs.next().unwrap()));
             let tables = pairs.map(build_agg_ast).collect();
-            AstNode::Equijoin { tables, on }
+            AstNode::Equijoin { tables, on, span }
         }
         Rule::expr_list => unreachable!("parse error: should not be "),
         Rule::expr => {
-            let pair = pair.into_inner().next().unwrap();
-            match pair.as_rule() {
+            let mut pairs = pair.into_inner();
+            let pair = pairs.next().unwrap();
+            let mut node = match pair.as_rule() {
                 Rule::alias_expr => build_agg_ast(pair),
                 Rule::aggfunc => build_agg_ast(pair),
                 Rule::userfunc => build_agg_ast(pair),
-                Rule::column_ident => AstNode::Column(pair.as_str().to_string()),
+                Rule::column_ident => AstNode::Column(pair.as_str().to_string(), span),
                 _ => unreachable!(
                     "parse error: expr expected alias|agg|ident, but got {:#?}: {}",
                     pair.as_rule(),
                     pair.as_str()
                 ),
+            };
+            // The grammar only allows EPSILON after aggregations and user functions
+            if let Some(column_epsilon) = pairs.next() {
+                let value = column_epsilon.into_inner().as_str().parse::<f64>().unwrap();
+                match &mut node {
+                    AstNode::Aggregation { epsilon, .. } | AstNode::UserF { epsilon, .. } => {
+                        *epsilon = Some(value)
+                    }
+                    _ => unreachable!("parse error: EPSILON on a column that is not released"),
+                }
             }
+            node
         }
         Rule::userfunc => {
             let mut pairs = pair.into_inner();
@@ -182,6 +392,8 @@ pub fn build_agg_ast(pair: pest::iterators::Pair<Rule>) -> AstNode {
                 column,
                 range,
                 alias,
+                epsilon: None,
+                span,
             }
         }
         Rule::alias_expr => {
@@ -191,12 +403,15 @@ pub fn build_agg_ast(pair: pest::iterators::Pair<Rule>) -> AstNode {
                     function,
                     inner,
                     range,
-                    alias: _,
+                    epsilon,
+                    ..
                 } => AstNode::Aggregation {
                     function,
                     inner,
                     range,
                     alias: pair.next().unwrap().as_str().to_string(),
+                    epsilon,
+                    span,
                 },
                 _ => unreachable!("aliasexpr must have agg inside"),
             }
@@ -219,13 +434,15 @@ pub fn build_agg_ast(pair: pest::iterators::Pair<Rule>) -> AstNode {
                 inner,
                 range,
                 alias,
+                epsilon: None,
+                span,
             }
         }
-        Rule::column_ident => AstNode::Column(pair.as_str().to_owned()),
+        Rule::column_ident => AstNode::Column(pair.as_str().to_owned(), span),
         Rule::whereclause => {
             let mut pairs = pair.into_inner();
             let predicate = Box::new(build_agg_ast(pairs.next().unwrap()));
-            AstNode::Where { predicate }
+            AstNode::Where { predicate, span }
         }
         Rule::predicate => {
             let mut pairs = pair.into_inner();
@@ -243,7 +460,7 @@ pub fn build_agg_ast(pair: pest::iterators::Pair<Rule>) -> AstNode {
                 _ => unreachable!("unsupported boolean operator: {}", op_str),
             };
             let rhs = Box::new(build_agg_ast(pairs.next().unwrap()));
-            AstNode::Predicate { lhs, op, rhs }
+            AstNode::Predicate { lhs, op, rhs, span }
         }
         Rule::groupbyclause => {
             let pairs = pair.into_inner();
@@ -274,23 +491,36 @@ pub fn build_agg_ast(pair: pest::iterators::Pair<Rule>) -> AstNode {
                         }
                     })
                     .collect(),
+                span,
             }
         }
         Rule::groupbywithkeys => {
-            unimplemented!("group by with keys not implemented yet");
+            let mut pairs = pair.into_inner();
+            let attr = pairs.next().unwrap().as_str().to_string();
+            let keys = pairs
+                .next()
+                .unwrap()
+                .into_inner()
+                .map(|k| k.as_str().to_string())
+                .collect();
+            AstNode::GroupByWithKeys { attr, keys, span }
         }
         Rule::windowclause => {
             let mut pairs = pair.into_inner();
             let mut inner = pairs.next().unwrap().into_inner();
-            AstNode::DurationMs(parse_duration_to_ms(&mut inner))
+            AstNode::DurationMs(parse_duration_to_ms(&mut inner), span)
+        }
+        Rule::epsilonclause => {
+            AstNode::Epsilon(pair.into_inner().as_str().parse::<f64>().unwrap(), span)
         }
         Rule::limitclause => {
             let pairs = pair.into_inner();
             AstNode::Limit {
                 rows: pairs.as_str().parse::<u64>().unwrap(),
+                span,
             }
         }
-        Rule::number => AstNode::Value(pair.as_str().parse::<f64>().unwrap()),
+        Rule::number => AstNode::Value(pair.as_str().parse::<f64>().unwrap(), span),
         _ => unreachable!(
             "parse error: unexpected rule, got {:#?}: {}",
             pair.as_rule(),
@@ -312,7 +542,9 @@ mod tests {
         for pair in pairs.next().unwrap().into_inner() {
             aggregations.push(build_agg_ast(pair));
         }
-        if expected.len() > 0 {
+        if !expected.is_empty() {
+            let aggregations: Vec<AstNode> =
+                aggregations.iter().map(|a| a.without_spans()).collect();
             assert_eq!(aggregations, expected);
         } else {
             println!("output: {:#?}", aggregations);
@@ -368,11 +600,24 @@ mod tests {
         parse_and_build_aggregation(query_string, vec![]);
     }
 
-    //#[test]
-    //fn parse_two_aggregations() {
-    //    let query_string = "SELECT count(people) FROM t1 AND SELECT cout(people) FROM t2";
-    //    parse_and_build_aggregation(query_string, vec![]);
-    //}
+    #[test]
+    fn parse_two_aggregations() {
+        let query_string = "SELECT count(people) FROM t1; SELECT count(people) FROM t2;";
+        let select = |table: &str| Select {
+            exprs: vec![Aggregation {
+                function: String::from("count"),
+                inner: String::from("people"),
+                range: None,
+                alias: String::from("count(people)"),
+                epsilon: None,
+                span: Span::default(),
+            }],
+            from: Box::new(TableNames(vec![String::from(table)], Span::default())),
+            qualifiers: vec![],
+            span: Span::default(),
+        };
+        parse_and_build_aggregation(query_string, vec![select("t1"), select("t2")]);
+    }
 
     #[test]
     fn parse_expr() {
@@ -398,9 +643,76 @@ mod tests {
                     inner: String::from("plate"),
                     range: None,
                     alias: String::from("sum(plate)"),
+                    epsilon: None,
+                    span: Span::default(),
                 }],
-                from: Box::new(TableNames(vec![String::from("cars")])),
-                qualifiers: vec![Limit { rows: 100 }],
+                from: Box::new(TableNames(vec![String::from("cars")], Span::default())),
+                qualifiers: vec![Limit {
+                    rows: 100,
+                    span: Span::default(),
+                }],
+                span: Span::default(),
+            }],
+        );
+    }
+
+    #[test]
+    fn parse_group_by_with_keys() {
+        let query_string =
+            "SELECT plate,count(plate) FROM cars GROUP BY plate WITH KEYS (ABC123, XYZ9);";
+        parse_and_build_aggregation(
+            query_string,
+            vec![Select {
+                exprs: vec![
+                    Column(String::from("plate"), Span::default()),
+                    Aggregation {
+                        function: String::from("count"),
+                        inner: String::from("plate"),
+                        range: None,
+                        alias: String::from("count(plate)"),
+                        epsilon: None,
+                        span: Span::default(),
+                    },
+                ],
+                from: Box::new(TableNames(vec![String::from("cars")], Span::default())),
+                qualifiers: vec![GroupByWithKeys {
+                    attr: String::from("plate"),
+                    keys: vec![String::from("ABC123"), String::from("XYZ9")],
+                    span: Span::default(),
+                }],
+                span: Span::default(),
+            }],
+        );
+    }
+
+    #[test]
+    fn epsilon() {
+        let query_string =
+            "SELECT count(plate) EPSILON 0.2, sum(speed,l=0,u=10) FROM t1 WITH EPSILON 0.5;";
+        parse_and_build_aggregation(
+            query_string,
+            vec![Select {
+                exprs: vec![
+                    Aggregation {
+                        function: String::from("count"),
+                        inner: String::from("plate"),
+                        range: None,
+                        alias: String::from("count(plate)"),
+                        epsilon: Some(0.2),
+                        span: Span::default(),
+                    },
+                    Aggregation {
+                        function: String::from("sum"),
+                        inner: String::from("speed"),
+                        range: Some((0.0, 10.0)),
+                        alias: String::from("sum(speed,l=0,u=10)"),
+                        epsilon: None,
+                        span: Span::default(),
+                    },
+                ],
+                from: Box::new(TableNames(vec![String::from("t1")], Span::default())),
+                qualifiers: vec![Epsilon(0.5, Span::default())],
+                span: Span::default(),
             }],
         );
     }
@@ -416,15 +728,19 @@ mod tests {
                     inner: String::from("people"),
                     range: Some((0.0, 10.0)),
                     alias: String::from("sum(people,l=0,u=10)"),
+                    epsilon: None,
+                    span: Span::default(),
                 }],
                 from: Box::new(AstNode::Intersect {
                     tables: vec![
-                        AstNode::TableNames(vec![String::from("t2")]),
-                        AstNode::TableNames(vec![String::from("t1")]),
+                        AstNode::TableNames(vec![String::from("t2")], Span::default()),
+                        AstNode::TableNames(vec![String::from("t1")], Span::default()),
                     ],
-                    on: Box::new(AstNode::Column(String::from("plate"))),
+                    on: Box::new(AstNode::Column(String::from("plate"), Span::default())),
+                    span: Span::default(),
                 }),
                 qualifiers: vec![],
+                span: Span::default(),
             }],
         );
     }
@@ -0,0 +1,714 @@
+/*
+ * Policy files describe the privacy policy (and optionally the total privacy budget) of each
+ * camera, so that policies don't have to be constructed in Rust. Files can be written in TOML:
+ *
+ *   [cameras.cam1]
+ *   k_segments = 2
+ *   rho = "60sec"      # or rho_ms = 60000
+ *   epsilon = 1.0
+ *   budget = 10.0      # optional
+ *
+ *   [cameras.lobby]
+ *   mask = true
+ *
+ * Persistence can depend on the time of day (in UTC), with a schedule overriding k_segments and
+ * rho (each defaults to the camera's) at some times and, optionally, on some days:
+ *
+ *   [[cameras.cam1.schedule]]
+ *   days = ["mon", "tue", "wed", "thu", "fri"]
+ *   from = "07:00"
+ *   to = "10:00"
+ *   rho = "5min"
+ *
+ * Cameras that an individual can appear in one after the other (e.g. along the same street) can
+ * be grouped into a site, whose policy bounds an event across all of its cameras together. The
+ * cameras of a site share its policy and budget, and can't also be listed under [cameras]:
+ *
+ *   [sites.main_street]
+ *   cameras = ["cam2", "cam3"]
+ *   k_segments = 2
+ *   rho = "60sec"
+ *   epsilon = 1.0
+ *   budget = 10.0      # optional, shared by every camera of the site
+ *
+ * Budgets are spent with pure composition unless the file chooses another accountant, e.g.
+ * `accounting = { zcdp = { delta = 1e-6 } }` (or `advanced` with a delta, or `"pure"`).
+ *
+ * or the equivalent JSON: {"cameras": {"cam1": {"k_segments": 2, "rho": "60sec", ...}}}
+ */
+use crate::accounting::Accounting;
+use crate::parser::parse_duration_str;
+use crate::policy::{BudgetLedger, PolicyMap, PrivacyBudget, PrivacyPolicy, ScheduleEntry};
+
+use chrono::Weekday;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum PolicyFileError {
+    Io(std::io::Error),
+    // The file is not valid TOML/JSON, or doesn't have the expected structure
+    Syntax(String),
+    // The file is well-formed, but some policies are invalid. Contains every problem found.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for PolicyFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyFileError::Io(e) => write!(f, "could not read policy file: {}", e),
+            PolicyFileError::Syntax(e) => write!(f, "could not parse policy file: {}", e),
+            PolicyFileError::Invalid(errors) => {
+                write!(f, "invalid policy file:")?;
+                for e in errors {
+                    write!(f, "\n  {}", e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PolicyFileError {}
+
+// Durations can be given in milliseconds or with units, like in queries
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDuration {
+    Ms(u64),
+    Text(String),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCameraPolicy {
+    #[serde(default)]
+    mask: bool,
+    k_segments: Option<u64>,
+    rho: Option<RawDuration>,
+    rho_ms: Option<u64>,
+    epsilon: Option<f64>,
+    budget: Option<f64>,
+    schedule: Option<Vec<RawScheduleEntry>>,
+}
+
+// k_segments and rho between two times of day, defaulting to those of the camera
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawScheduleEntry {
+    #[serde(default)]
+    days: Vec<String>,
+    from: String,
+    to: String,
+    k_segments: Option<u64>,
+    rho: Option<RawDuration>,
+    rho_ms: Option<u64>,
+}
+
+// The policy of a site, shared by each of its cameras
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSitePolicy {
+    cameras: Vec<String>,
+    k_segments: Option<u64>,
+    rho: Option<RawDuration>,
+    rho_ms: Option<u64>,
+    epsilon: Option<f64>,
+    budget: Option<f64>,
+    schedule: Option<Vec<RawScheduleEntry>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPolicyFile {
+    #[serde(default)]
+    cameras: BTreeMap<String, RawCameraPolicy>,
+    #[serde(default)]
+    sites: BTreeMap<String, RawSitePolicy>,
+    accounting: Option<Accounting>,
+}
+
+// Policies and budgets read from a policy file
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyConfig {
+    pub policies: PolicyMap,
+    // Only contains the cameras (and sites) that declared a `budget`
+    pub budgets: BudgetLedger,
+}
+
+// `name` is how errors refer to the policy, e.g. "camera 'cam1'"
+fn build_policy(
+    name: &str,
+    raw: RawCameraPolicy,
+    errors: &mut Vec<String>,
+) -> Option<PrivacyPolicy> {
+    let err = |msg: &str| format!("{}: {}", name, msg);
+    let n_errors = errors.len();
+
+    if let Some(budget) = raw.budget {
+        if !(budget.is_finite() && budget >= 0.0) {
+            errors.push(err("budget must be a non-negative number"));
+        }
+    }
+
+    if raw.mask {
+        if raw.k_segments.is_some()
+            || raw.rho.is_some()
+            || raw.rho_ms.is_some()
+            || raw.epsilon.is_some()
+            || raw.schedule.is_some()
+        {
+            errors.push(err(
+                "mask policies can't set k_segments, rho, epsilon or schedule, only budget",
+            ));
+        }
+        return if errors.len() == n_errors {
+            Some(PrivacyPolicy::Mask {})
+        } else {
+            None
+        };
+    }
+
+    let k_segments = match raw.k_segments {
+        Some(0) => {
+            errors.push(err("k_segments must be at least 1"));
+            None
+        }
+        Some(k) => Some(k),
+        None => {
+            errors.push(err("missing k_segments"));
+            None
+        }
+    };
+    let epsilon = match raw.epsilon {
+        Some(e) if e.is_finite() && e > 0.0 => {
+            if raw.budget.is_some_and(|b| b < e) {
+                errors.push(err(
+                    "budget is smaller than epsilon, no query could ever run",
+                ));
+            }
+            Some(e)
+        }
+        Some(_) => {
+            errors.push(err("epsilon must be a positive number"));
+            None
+        }
+        None => {
+            errors.push(err("missing epsilon"));
+            None
+        }
+    };
+    let rho_ms = parse_rho(raw.rho, raw.rho_ms, &err, errors);
+    let schedule: Option<Vec<ScheduleEntry>> = raw.schedule.map(|entries| {
+        entries
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, entry)| {
+                let err = |msg: &str| format!("{}: schedule entry {}: {}", name, i, msg);
+                build_schedule_entry(entry, k_segments, rho_ms, &err, errors)
+            })
+            .collect()
+    });
+
+    if errors.len() != n_errors {
+        return None;
+    }
+    match (k_segments, epsilon, rho_ms, schedule) {
+        (Some(k_segments), Some(epsilon), Some(rho_ms), None) => Some(PrivacyPolicy::Static {
+            k_segments,
+            epsilon,
+            rho_ms,
+        }),
+        (Some(k_segments), Some(epsilon), Some(rho_ms), Some(schedule)) => {
+            Some(PrivacyPolicy::Scheduled {
+                k_segments,
+                epsilon,
+                rho_ms,
+                schedule,
+            })
+        }
+        _ => None,
+    }
+}
+
+// `rho` (with units) or `rho_ms`, exactly one of which must be set
+fn parse_rho(
+    rho: Option<RawDuration>,
+    rho_ms: Option<u64>,
+    err: &dyn Fn(&str) -> String,
+    errors: &mut Vec<String>,
+) -> Option<u64> {
+    match (rho, rho_ms) {
+        (Some(_), Some(_)) => {
+            errors.push(err("only one of rho and rho_ms can be set"));
+            None
+        }
+        (None, None) => {
+            errors.push(err("missing rho (or rho_ms)"));
+            None
+        }
+        (None, Some(ms)) | (Some(RawDuration::Ms(ms)), None) => Some(ms),
+        (Some(RawDuration::Text(text)), None) => match parse_duration_str(&text) {
+            Ok(ms) => Some(ms),
+            Err(_) => {
+                errors.push(err(&format!(
+                    "invalid rho '{}', expected a duration like '60sec'",
+                    text
+                )));
+                None
+            }
+        },
+    }
+}
+
+// Time of day like "07:30", in milliseconds since midnight. "24:00" is the end of the day.
+fn parse_time_of_day(time: &str) -> Option<u64> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u64>().ok()?, minutes.parse::<u64>().ok()?);
+    if minutes >= 60 || hours > 24 || (hours == 24 && minutes > 0) {
+        return None;
+    }
+    Some((hours * 60 + minutes) * 60 * 1000)
+}
+
+fn build_schedule_entry(
+    raw: RawScheduleEntry,
+    k_segments: Option<u64>,
+    rho_ms: Option<u64>,
+    err: &dyn Fn(&str) -> String,
+    errors: &mut Vec<String>,
+) -> Option<ScheduleEntry> {
+    let n_errors = errors.len();
+    let mut days = vec![];
+    for day in &raw.days {
+        match day.parse::<Weekday>() {
+            Ok(day) => days.push(day.num_days_from_monday()),
+            Err(_) => errors.push(err(&format!(
+                "invalid day '{}', expected a day of the week like 'mon'",
+                day
+            ))),
+        }
+    }
+    let mut time = |time: &str| {
+        let ms = parse_time_of_day(time);
+        if ms.is_none() {
+            errors.push(err(&format!(
+                "invalid time '{}', expected a time of day like '07:30'",
+                time
+            )));
+        }
+        ms
+    };
+    let (start_ms, end_ms) = (time(&raw.from), time(&raw.to));
+    if let (Some(start), Some(end)) = (start_ms, end_ms) {
+        if start >= end {
+            errors.push(err(
+                "`from` must be before `to`, split entries that cross midnight in two",
+            ));
+        }
+    }
+    if raw.k_segments == Some(0) {
+        errors.push(err("k_segments must be at least 1"));
+    }
+    let entry_rho_ms = match (raw.rho, raw.rho_ms) {
+        (None, None) => rho_ms,
+        (rho, ms) => parse_rho(rho, ms, err, errors),
+    };
+
+    if errors.len() != n_errors {
+        return None;
+    }
+    Some(ScheduleEntry {
+        days,
+        start_ms: start_ms?,
+        end_ms: end_ms?,
+        k_segments: raw.k_segments.or(k_segments)?,
+        rho_ms: entry_rho_ms?,
+    })
+}
+
+fn build_config(raw: RawPolicyFile) -> Result<PolicyConfig, PolicyFileError> {
+    let mut errors = vec![];
+    let mut policies = PolicyMap::new();
+    let mut budgets = BudgetLedger::new();
+
+    match raw.accounting {
+        Some(Accounting::Advanced { delta }) | Some(Accounting::Zcdp { delta })
+            if !(delta > 0.0 && delta < 1.0) =>
+        {
+            errors.push(String::from("accounting: delta must be between 0 and 1"))
+        }
+        Some(accounting) => budgets.set_accounting(accounting),
+        None => {}
+    }
+
+    // Site of each camera that is part of one
+    let mut camera_sites: BTreeMap<&str, &str> = BTreeMap::new();
+    for (site, raw_site) in &raw.sites {
+        let err = |msg: &str| format!("site '{}': {}", site, msg);
+        if raw_site.cameras.is_empty() {
+            errors.push(err("a site needs at least one camera"));
+        }
+        if raw.cameras.contains_key(site) {
+            errors.push(err(
+                "a camera has the same name, which would share its budget",
+            ));
+        }
+        for camera in &raw_site.cameras {
+            if raw.cameras.contains_key(camera) {
+                errors.push(err(&format!(
+                    "camera '{}' also has its own policy under [cameras]",
+                    camera
+                )));
+            } else if let Some(other) = camera_sites.insert(camera, site) {
+                errors.push(err(&format!(
+                    "camera '{}' is already part of site '{}'",
+                    camera, other
+                )));
+            }
+        }
+    }
+
+    for (site, raw_site) in raw.sites {
+        let budget = raw_site.budget;
+        let raw_policy = RawCameraPolicy {
+            mask: false,
+            k_segments: raw_site.k_segments,
+            rho: raw_site.rho,
+            rho_ms: raw_site.rho_ms,
+            epsilon: raw_site.epsilon,
+            budget,
+            schedule: raw_site.schedule,
+        };
+        if let Some(policy) = build_policy(&format!("site '{}'", site), raw_policy, &mut errors) {
+            if let Some(total) = budget {
+                budgets.insert(&site, PrivacyBudget::new(total));
+            }
+            for camera in raw_site.cameras {
+                let policy = PrivacyPolicy::Site {
+                    site: site.clone(),
+                    policy: Box::new(policy.clone()),
+                };
+                policies.insert(camera, policy);
+            }
+        }
+    }
+
+    for (camera, raw) in raw.cameras {
+        let budget = raw.budget;
+        if let Some(policy) = build_policy(&format!("camera '{}'", camera), raw, &mut errors) {
+            if let Some(total) = budget {
+                budgets.insert(&camera, PrivacyBudget::new(total));
+            }
+            policies.insert(camera, policy);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(PolicyConfig { policies, budgets })
+    } else {
+        Err(PolicyFileError::Invalid(errors))
+    }
+}
+
+impl PolicyConfig {
+    pub fn from_toml(contents: &str) -> Result<Self, PolicyFileError> {
+        build_config(toml::from_str(contents).map_err(|e| PolicyFileError::Syntax(e.to_string()))?)
+    }
+
+    pub fn from_json(contents: &str) -> Result<Self, PolicyFileError> {
+        build_config(
+            serde_json::from_str(contents).map_err(|e| PolicyFileError::Syntax(e.to_string()))?,
+        )
+    }
+
+    /// Reads a policy file, using its extension to decide whether it is JSON (`.json`) or TOML
+    /// (anything else)
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PolicyFileError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(PolicyFileError::Io)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => PolicyConfig::from_json(&contents),
+            _ => PolicyConfig::from_toml(&contents),
+        }
+    }
+}
+
+/// Reads the PolicyMap from a TOML or JSON policy file, ignoring budgets
+pub fn load_policies<P: AsRef<Path>>(path: P) -> Result<PolicyMap, PolicyFileError> {
+    PolicyConfig::load(path).map(|config| config.policies)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+
+    #[test]
+    fn parse_toml() {
+        let config = PolicyConfig::from_toml(
+            r#"
+            [cameras.cam1]
+            k_segments = 2
+            rho = "1.5 min"
+            epsilon = 1.0
+            budget = 10.0
+
+            [cameras.cam2]
+            k_segments = 1
+            rho_ms = 30000
+            epsilon = 0.5
+
+            [cameras.lobby]
+            mask = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.policies.get("cam1"),
+            Some(&PrivacyPolicy::Static {
+                k_segments: 2,
+                epsilon: 1.0,
+                rho_ms: 90_000,
+            })
+        );
+        assert_eq!(
+            config.policies.get("cam2"),
+            Some(&PrivacyPolicy::Static {
+                k_segments: 1,
+                epsilon: 0.5,
+                rho_ms: 30_000,
+            })
+        );
+        assert_eq!(config.policies.get("lobby"), Some(&PrivacyPolicy::Mask {}));
+        assert_eq!(config.budgets.remaining("cam1"), Some(10.0));
+        assert_eq!(config.budgets.remaining("cam2"), None);
+    }
+
+    #[test]
+    fn parse_json() {
+        let config = PolicyConfig::from_json(
+            r#"{"cameras": {"cam1": {"k_segments": 1, "rho": "60sec", "epsilon": 1.0}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.policies.get("cam1"),
+            Some(&PrivacyPolicy::Static {
+                k_segments: 1,
+                epsilon: 1.0,
+                rho_ms: 60_000,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_sites() {
+        let config = PolicyConfig::from_toml(
+            r#"
+            [sites.street]
+            cameras = ["cam1", "cam2"]
+            k_segments = 2
+            rho = "60sec"
+            epsilon = 1.0
+            budget = 5.0
+
+            [cameras.cam3]
+            k_segments = 1
+            rho_ms = 30000
+            epsilon = 1.0
+            "#,
+        )
+        .unwrap();
+        let site = PrivacyPolicy::Site {
+            site: String::from("street"),
+            policy: Box::new(PrivacyPolicy::Static {
+                k_segments: 2,
+                epsilon: 1.0,
+                rho_ms: 60_000,
+            }),
+        };
+        assert_eq!(config.policies.get("cam1"), Some(&site));
+        assert_eq!(config.policies.get("cam2"), Some(&site));
+        assert_eq!(config.policies["cam3"].site(), None);
+        // The budget is shared by the cameras of the site
+        assert_eq!(config.budgets.remaining("street"), Some(5.0));
+        assert_eq!(config.budgets.remaining("cam1"), None);
+
+        let err = PolicyConfig::from_toml(
+            r#"
+            [sites.street]
+            cameras = ["cam1", "cam2"]
+            k_segments = 1
+            rho = "60sec"
+            epsilon = 1.0
+
+            [sites.square]
+            cameras = ["cam2"]
+            rho = "60sec"
+            epsilon = 1.0
+
+            [cameras.cam1]
+            k_segments = 1
+            rho = "60sec"
+            epsilon = 1.0
+            "#,
+        )
+        .unwrap_err();
+        match err {
+            PolicyFileError::Invalid(errors) => assert_eq!(
+                errors,
+                vec![
+                    "site 'street': camera 'cam1' also has its own policy under [cameras]",
+                    "site 'street': camera 'cam2' is already part of site 'square'",
+                    "site 'square': missing k_segments",
+                ]
+            ),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_schedule() {
+        let config = PolicyConfig::from_toml(
+            r#"
+            [cameras.cam1]
+            k_segments = 1
+            rho = "30sec"
+            epsilon = 1.0
+
+            [[cameras.cam1.schedule]]
+            days = ["mon", "Friday"]
+            from = "07:00"
+            to = "10:30"
+            rho = "5min"
+
+            [[cameras.cam1.schedule]]
+            from = "22:00"
+            to = "24:00"
+            k_segments = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.policies.get("cam1"),
+            Some(&PrivacyPolicy::Scheduled {
+                k_segments: 1,
+                epsilon: 1.0,
+                rho_ms: 30_000,
+                schedule: vec![
+                    ScheduleEntry {
+                        days: vec![0, 4],
+                        start_ms: 7 * 3_600_000,
+                        end_ms: 10 * 3_600_000 + 30 * 60_000,
+                        k_segments: 1,
+                        rho_ms: 300_000,
+                    },
+                    ScheduleEntry {
+                        days: vec![],
+                        start_ms: 22 * 3_600_000,
+                        end_ms: 24 * 3_600_000,
+                        k_segments: 2,
+                        rho_ms: 30_000,
+                    },
+                ],
+            })
+        );
+
+        let err = PolicyConfig::from_toml(
+            r#"
+            [cameras.cam1]
+            k_segments = 1
+            rho = "30sec"
+            epsilon = 1.0
+
+            [[cameras.cam1.schedule]]
+            days = ["someday"]
+            from = "23:00"
+            to = "01:00"
+            "#,
+        )
+        .unwrap_err();
+        match err {
+            PolicyFileError::Invalid(errors) => assert_eq!(
+                errors,
+                vec![
+                    "camera 'cam1': schedule entry 0: invalid day 'someday', expected a day of the week like 'mon'",
+                    "camera 'cam1': schedule entry 0: `from` must be before `to`, split entries that cross midnight in two",
+                ]
+            ),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn reports_all_errors() {
+        let err = PolicyConfig::from_toml(
+            r#"
+            [cameras.cam1]
+            rho = "60 parsecs"
+            epsilon = 0.0
+
+            [cameras.cam2]
+            k_segments = 1
+            rho = "60sec"
+            rho_ms = 60000
+            epsilon = 2.0
+            budget = 1.0
+            "#,
+        )
+        .unwrap_err();
+        match err {
+            PolicyFileError::Invalid(errors) => assert_eq!(
+                errors,
+                vec![
+                    "camera 'cam1': missing k_segments",
+                    "camera 'cam1': epsilon must be a positive number",
+                    "camera 'cam1': invalid rho '60 parsecs', expected a duration like '60sec'",
+                    "camera 'cam2': budget is smaller than epsilon, no query could ever run",
+                    "camera 'cam2': only one of rho and rho_ms can be set",
+                ]
+            ),
+            _ => panic!("expected invalid policy file, got {}", err),
+        }
+    }
+
+    #[test]
+    fn accounting() {
+        let config = PolicyConfig::from_toml(
+            r#"
+            accounting = { zcdp = { delta = 1e-6 } }
+
+            [cameras.cam1]
+            k_segments = 1
+            rho = "60sec"
+            epsilon = 1.0
+            budget = 10.0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.budgets.accounting(),
+            Accounting::Zcdp { delta: 1e-6 }
+        );
+
+        let config = PolicyConfig::from_json(r#"{"accounting": "pure", "cameras": {}}"#).unwrap();
+        assert_eq!(config.budgets.accounting(), Accounting::Pure);
+
+        match PolicyConfig::from_toml("accounting = { advanced = { delta = 2.0 } }") {
+            Err(PolicyFileError::Invalid(errors)) => {
+                assert_eq!(errors, vec!["accounting: delta must be between 0 and 1"])
+            }
+            res => panic!("expected invalid policy file, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn unknown_field() {
+        match PolicyConfig::from_toml("[cameras.cam1]\nk = 1\n") {
+            Err(PolicyFileError::Syntax(_)) => {}
+            res => panic!("expected syntax error, got {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,937 @@
+/*
+ * Evaluates SELECT statements over the rows output by each chunk, producing the raw (pre-noise)
+ * result of each statement. Columns are named the same way as in sensitivity_composition: base
+ * table columns are qualified by their table name (`table1.plate`), while the fixed `chunk`
+ * column, aliases and GROUP BY keys are not, so an unqualified name can be used wherever it is
+ * unambiguous.
+ */
+use crate::aggregation::{AstNode, BooleanOp, GroupByAttr};
+use crate::parser::{PQLQuery, Span};
+use crate::process::Column;
+use crate::sensitivity::{aggregated_rows, bin_range, column_range};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(untagged))]
+pub enum Value {
+    Number(f64),
+    String(String),
+}
+
+impl Value {
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::String(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+// Column name to value
+pub type Row = HashMap<String, Value>;
+
+// Rows output by processing a single chunk, keyed by the (unqualified) names in the schema of
+// the PROCESS statement. Missing columns take the default value from the schema.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChunkRows {
+    // Index of the chunk within its split, starting at 0
+    pub chunk: u64,
+    pub rows: Vec<Row>,
+}
+
+// Output of every chunk, keyed by the name of the table produced by the PROCESS statement
+pub type TableData = HashMap<String, Vec<ChunkRows>>;
+
+// Result of a statement, with one entry per output column in each row
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResultTable {
+    pub columns: Vec<String>,
+    pub rows: Vec<Row>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl ExecError {
+    fn new(message: String, span: Span) -> Self {
+        ExecError { message, span }
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (at {}:{})",
+            self.message, self.span.line, self.span.col
+        )
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+// Function applied to a single value by `user_<name>(column)` expressions
+pub type UserFunction = Box<dyn Fn(&Value) -> Value>;
+
+pub struct Executor<'a> {
+    data: &'a TableData,
+    // Keyed by name without the `user_` prefix, as stored in AstNode::UserF
+    functions: HashMap<String, UserFunction>,
+}
+
+// Returns the value of `name` in `row`, falling back to a qualified column (`table.name`) if the
+// bare name is not in the row
+fn resolve<'r>(row: &'r Row, name: &str) -> Option<&'r Value> {
+    row.get(name).or_else(|| {
+        row.iter()
+            .find(|(k, _)| k.split('.').nth(1) == Some(name))
+            .map(|(_, v)| v)
+    })
+}
+
+fn column<'r>(row: &'r Row, name: &str, span: Span) -> Result<&'r Value, ExecError> {
+    resolve(row, name)
+        .ok_or_else(|| ExecError::new(format!("column `{}` does not exist", name), span))
+}
+
+fn number(row: &Row, name: &str, function: &str, span: Span) -> Result<f64, ExecError> {
+    column(row, name, span)?.as_number().ok_or_else(|| {
+        ExecError::new(
+            format!(
+                "{}() requires a NUMBER column, but `{}` is a STRING",
+                function, name
+            ),
+            span,
+        )
+    })
+}
+
+fn clamp(x: f64, range: &Option<(f64, f64)>) -> f64 {
+    match range {
+        Some((l, u)) => x.max(*l).min(*u),
+        None => x,
+    }
+}
+
+fn compare(lhs: &Value, op: &BooleanOp, rhs: &Value, span: Span) -> Result<bool, ExecError> {
+    let ordering = match (lhs, rhs) {
+        (Value::Number(l), Value::Number(r)) => l.partial_cmp(r),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        _ => {
+            return Err(ExecError::new(
+                format!("cannot compare `{}` with `{}`", lhs, rhs),
+                span,
+            ))
+        }
+    };
+    let ordering = match ordering {
+        Some(o) => o,
+        // NaN compares unequal to everything
+        None => return Ok(*op == BooleanOp::NotEqual),
+    };
+    Ok(match op {
+        BooleanOp::GreaterThan => ordering.is_gt(),
+        BooleanOp::GreaterThanOrEqualTo => ordering.is_ge(),
+        BooleanOp::LessThan => ordering.is_lt(),
+        BooleanOp::LessThanOrEqualTo => ordering.is_le(),
+        BooleanOp::Equal => ordering.is_eq(),
+        BooleanOp::NotEqual => ordering.is_ne(),
+        _ => unreachable!("not a comparison operator: {:?}", op),
+    })
+}
+
+fn eval_term(node: &AstNode, row: &Row) -> Result<Value, ExecError> {
+    match node {
+        AstNode::Column(c, span) => column(row, c, *span).cloned(),
+        AstNode::Value(v, _) => Ok(Value::Number(*v)),
+        _ => Err(ExecError::new(
+            String::from("expected a column or a number"),
+            node.span(),
+        )),
+    }
+}
+
+fn eval_predicate(node: &AstNode, row: &Row) -> Result<bool, ExecError> {
+    match node {
+        AstNode::Predicate { lhs, op, rhs, span } => match op {
+            BooleanOp::And => Ok(eval_predicate(lhs, row)? && eval_predicate(rhs, row)?),
+            BooleanOp::Or => Ok(eval_predicate(lhs, row)? || eval_predicate(rhs, row)?),
+            BooleanOp::Not => Ok(!eval_predicate(lhs, row)?),
+            _ => compare(&eval_term(lhs, row)?, op, &eval_term(rhs, row)?, *span),
+        },
+        _ => Err(ExecError::new(
+            String::from("expected a predicate"),
+            node.span(),
+        )),
+    }
+}
+
+// Rows grouped by the value of their GROUP BY keys
+struct Groups {
+    keys: Vec<String>,
+    // Value of the keys of each group
+    values: Vec<Row>,
+    groups: Vec<Vec<Row>>,
+}
+
+// Groups in order of first appearance of the keys that aren't bins. Each of them has a group for
+// every bin in `bins`, in order, even if no row is in it, so which bins are output doesn't depend
+// on the data.
+fn group_rows(rows: Vec<Row>, keys: Vec<String>, bins: &[(String, Range<u64>)]) -> Groups {
+    let is_bin = |k: &String| bins.iter().any(|(alias, _)| alias == k);
+    let mut index: HashMap<Vec<String>, usize> = HashMap::new();
+    let mut others: Vec<Row> = vec![];
+    let mut grouped: Vec<Vec<Row>> = vec![];
+    for row in rows {
+        // Keys were inserted into every row by the GROUP BY, so they are always present
+        let key: Vec<String> = keys.iter().map(|k| format!("{:?}", row[k])).collect();
+        let other: Row = keys
+            .iter()
+            .filter(|k| !is_bin(k))
+            .map(|k| (k.clone(), row[k].clone()))
+            .collect();
+        if !others.contains(&other) {
+            others.push(other);
+        }
+        match index.get(&key) {
+            Some(&i) => grouped[i].push(row),
+            None => {
+                index.insert(key, grouped.len());
+                grouped.push(vec![row]);
+            }
+        }
+    }
+    // Without other keys, every bin is output even if there are no rows at all
+    if others.is_empty() && keys.iter().all(is_bin) {
+        others.push(Row::new());
+    }
+
+    let mut values = vec![];
+    let mut groups = vec![];
+    for other in others {
+        let mut combinations = vec![other];
+        for (alias, range) in bins {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|row| {
+                    range.clone().map(move |bin| {
+                        let mut row = row.clone();
+                        row.insert(alias.clone(), Value::Number(bin as f64));
+                        row
+                    })
+                })
+                .collect();
+        }
+        for row in combinations {
+            let key: Vec<String> = keys.iter().map(|k| format!("{:?}", row[k])).collect();
+            groups.push(
+                index
+                    .get(&key)
+                    .map_or_else(Vec::new, |&i| std::mem::take(&mut grouped[i])),
+            );
+            values.push(row);
+        }
+    }
+    Groups {
+        keys,
+        values,
+        groups,
+    }
+}
+
+// One group per listed key, in order, even if no row has it. Rows whose key isn't listed were
+// already dropped. Keys are output as listed, whatever the type of the column.
+fn group_listed(rows: Vec<Row>, attr: String, listed: &[String]) -> Groups {
+    let mut groups: Vec<Vec<Row>> = vec![vec![]; listed.len()];
+    for row in rows {
+        let key = row[&attr].to_string();
+        if let Some(i) = listed.iter().position(|k| *k == key) {
+            groups[i].push(row);
+        }
+    }
+    Groups {
+        values: listed
+            .iter()
+            .map(|k| {
+                vec![(attr.clone(), Value::String(k.clone()))]
+                    .into_iter()
+                    .collect()
+            })
+            .collect(),
+        keys: vec![attr],
+        groups,
+    }
+}
+
+// `size` is the number of rows mean(), var() and stddev() are computed over, the one their
+// sensitivity assumes: values past it are dropped, and missing ones take the lowest magnitude
+// value in range
+fn aggregate(
+    function: &str,
+    inner: &str,
+    range: &Option<(f64, f64)>,
+    size: Option<u64>,
+    rows: &[Row],
+    span: Span,
+) -> Result<Value, ExecError> {
+    let function = function.to_lowercase();
+    let values = || -> Result<Vec<f64>, ExecError> {
+        rows.iter()
+            .map(|r| number(r, inner, &function, span).map(|x| clamp(x, range)))
+            .collect()
+    };
+    let padded = || -> Result<Vec<f64>, ExecError> {
+        let size = match size {
+            Some(size) if size > 0 => size as usize,
+            _ => {
+                return Err(ExecError::new(
+                    format!(
+                        "{}() is over an unknown number of rows, add LIMIT to the statement",
+                        function
+                    ),
+                    span,
+                ))
+            }
+        };
+        let mut values = values()?;
+        values.resize(size, clamp(0.0, range));
+        Ok(values)
+    };
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+    let var = |values: &[f64]| {
+        let m = mean(values);
+        values.iter().map(|x| (x - m) * (x - m)).sum::<f64>() / values.len() as f64
+    };
+    Ok(Value::Number(match function.as_str() {
+        "count" => {
+            for r in rows {
+                column(r, inner, span)?;
+            }
+            rows.len() as f64
+        }
+        "sum" => values()?.iter().sum(),
+        "mean" => mean(&padded()?),
+        "var" => var(&padded()?),
+        "stddev" => var(&padded()?).sqrt(),
+        _ => {
+            return Err(ExecError::new(
+                format!("{}() is not supported by the executor yet", function),
+                span,
+            ))
+        }
+    }))
+}
+
+impl<'a> Executor<'a> {
+    pub fn new(data: &'a TableData) -> Self {
+        Executor {
+            data,
+            functions: HashMap::new(),
+        }
+    }
+
+    /// Registers the function called by `user_<name>(column)` expressions
+    pub fn register(&mut self, name: &str, f: UserFunction) {
+        self.functions.insert(name.to_owned(), f);
+    }
+
+    /// Evaluates every SELECT statement in `query`, in order
+    pub fn execute_query(&self, query: &PQLQuery) -> Result<Vec<ResultTable>, ExecError> {
+        query
+            .select_stmts
+            .iter()
+            .map(|stmt| self.execute(stmt))
+            .collect()
+    }
+
+    /// Evaluates a single SELECT statement
+    pub fn execute(&self, stmt: &AstNode) -> Result<ResultTable, ExecError> {
+        let rows = self.rows(stmt)?;
+        Ok(ResultTable {
+            columns: stmt.output_columns(),
+            rows,
+        })
+    }
+
+    // Evaluates any node that produces a table
+    fn rows(&self, node: &AstNode) -> Result<Vec<Row>, ExecError> {
+        match node {
+            AstNode::Table(ss, ps) => {
+                let chunks = self.data.get(&ps.output_table_name).ok_or_else(|| {
+                    ExecError::new(
+                        format!("no chunk output for table `{}`", ps.output_table_name),
+                        ps.span,
+                    )
+                })?;
+                let mut rows = vec![];
+                for chunk in chunks {
+                    let (_, start_ms, _) = ss.chunk(chunk.chunk).ok_or_else(|| {
+                        ExecError::new(
+                            format!(
+                                "table `{}` has output for chunk {}, but its split only has {} chunks",
+                                ps.output_table_name,
+                                chunk.chunk,
+                                ss.num_chunks()
+                            ),
+                            ps.span,
+                        )
+                    })?;
+                    for out in &chunk.rows {
+                        let row = ps
+                            .schema
+                            .iter()
+                            .map(|c| match c {
+                                Column::String(sc) => (
+                                    ps.output_table_name.clone() + "." + sc.name.as_str(),
+                                    out.get(&sc.name)
+                                        .cloned()
+                                        .unwrap_or_else(|| Value::String(sc.default.clone())),
+                                ),
+                                Column::Number(nc) => (
+                                    ps.output_table_name.clone() + "." + nc.name.as_str(),
+                                    out.get(&nc.name)
+                                        .cloned()
+                                        .unwrap_or(Value::Number(nc.default)),
+                                ),
+                                Column::Fixed(fc) => {
+                                    (fc.name.clone(), Value::Number(start_ms as f64))
+                                }
+                            })
+                            .collect();
+                        rows.push(row);
+                    }
+                }
+                Ok(rows)
+            }
+            AstNode::Tables(tables, _) => {
+                let mut rows = vec![];
+                for t in tables {
+                    rows.extend(self.rows(t)?);
+                }
+                Ok(rows)
+            }
+            AstNode::Union { tables, on, .. } => self.combine(tables, on, false),
+            AstNode::Intersect { tables, on, .. } => self.combine(tables, on, true),
+            AstNode::Equijoin { tables, on, .. } => self.equijoin(tables, on),
+            AstNode::Select {
+                exprs,
+                from,
+                qualifiers,
+                ..
+            } => self.select(exprs, from, qualifiers),
+            _ => Err(ExecError::new(
+                String::from("unexpected node in FROM clause"),
+                node.span(),
+            )),
+        }
+    }
+
+    // Evaluates each table, adding the unqualified `on` column to each of their rows
+    fn keyed_tables(
+        &self,
+        tables: &[AstNode],
+        on: &AstNode,
+    ) -> Result<(String, Vec<Vec<Row>>), ExecError> {
+        let (on, span) = match on {
+            AstNode::Column(c, span) => (c.clone(), *span),
+            _ => unreachable!("ON must be a column"),
+        };
+        let mut keyed = vec![];
+        for t in tables {
+            let mut rows = self.rows(t)?;
+            for row in rows.iter_mut() {
+                let key = column(row, &on, span)?.clone();
+                row.insert(on.clone(), key);
+            }
+            keyed.push(rows);
+        }
+        Ok((on, keyed))
+    }
+
+    // UNION keeps the rows of every table, INTERSECT only those whose key appears in every table
+    fn combine(
+        &self,
+        tables: &[AstNode],
+        on: &AstNode,
+        intersect: bool,
+    ) -> Result<Vec<Row>, ExecError> {
+        let (on, keyed) = self.keyed_tables(tables, on)?;
+        let in_all = |key: &Value| keyed.iter().all(|rows| rows.iter().any(|r| &r[&on] == key));
+        let mut rows = vec![];
+        for table in &keyed {
+            for row in table {
+                if !intersect || in_all(&row[&on]) {
+                    rows.push(row.clone());
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    // Joins the rows of every table that share the same key, once per key: the first row with it
+    // in each table, in order of first appearance in the first table. A row of an individual then
+    // changes at most one joined row, which is what sensitivity_composition assumes for EQUIJOIN.
+    // Joined rows may come from different chunks, so they don't have a `chunk` column.
+    fn equijoin(&self, tables: &[AstNode], on: &AstNode) -> Result<Vec<Row>, ExecError> {
+        let (on, keyed) = self.keyed_tables(tables, on)?;
+        let mut keyed = keyed.into_iter();
+        let mut rows: Vec<Row> = vec![];
+        for row in keyed.next().unwrap_or_default() {
+            if !rows.iter().any(|r| r[&on] == row[&on]) {
+                rows.push(row);
+            }
+        }
+        for table in keyed {
+            rows = rows
+                .into_iter()
+                .filter_map(|mut lhs| {
+                    let rhs = table.iter().find(|r| r[&on] == lhs[&on])?;
+                    lhs.extend(rhs.clone());
+                    Some(lhs)
+                })
+                .collect();
+        }
+        for row in rows.iter_mut() {
+            row.remove("chunk");
+        }
+        Ok(rows)
+    }
+
+    fn select(
+        &self,
+        exprs: &[AstNode],
+        from: &AstNode,
+        qualifiers: &[AstNode],
+    ) -> Result<Vec<Row>, ExecError> {
+        let mut rows = self.rows(from)?;
+        let mut keys: Option<Vec<String>> = None;
+        // Every key of each bin() of the GROUP BY
+        let mut bins: Vec<(String, Range<u64>)> = vec![];
+        // Column and keys of GROUP BY .. WITH KEYS
+        let mut listed: Option<(&String, &Vec<String>)> = None;
+
+        for q in qualifiers {
+            match q {
+                AstNode::Where { predicate, .. } => {
+                    let mut filtered = vec![];
+                    for row in rows {
+                        if eval_predicate(predicate, &row)? {
+                            filtered.push(row);
+                        }
+                    }
+                    rows = filtered;
+                }
+                AstNode::GroupBy { attrs, span } => {
+                    if listed.is_some() {
+                        return Err(ExecError::new(
+                            String::from(
+                                "GROUP BY .. WITH KEYS can't be combined with another GROUP BY",
+                            ),
+                            *span,
+                        ));
+                    }
+                    let mut names = vec![];
+                    for attr in attrs {
+                        let name = match attr {
+                            GroupByAttr::Column(c) => c,
+                            GroupByAttr::Bin {
+                                column: c,
+                                size,
+                                alias,
+                            } => {
+                                let range = bin_range(from, c, *size).ok_or_else(|| {
+                                    ExecError::new(
+                                        format!(
+                                            "bin() requires a time column such as `chunk`, but `{}` is not one",
+                                            c
+                                        ),
+                                        *span,
+                                    )
+                                })?;
+                                bins.push((alias.clone(), range));
+                                alias
+                            }
+                        };
+                        for row in rows.iter_mut() {
+                            let key = match attr {
+                                GroupByAttr::Column(c) => column(row, c, *span)?.clone(),
+                                GroupByAttr::Bin {
+                                    column: c, size, ..
+                                } => {
+                                    let ms = number(row, c, "bin", *span)?;
+                                    Value::Number((ms / *size as f64).floor())
+                                }
+                            };
+                            row.insert(name.clone(), key);
+                        }
+                        names.push(name.clone());
+                    }
+                    keys = Some(names);
+                }
+                AstNode::GroupByWithKeys {
+                    attr,
+                    keys: values,
+                    span,
+                } => {
+                    if keys.is_some() {
+                        return Err(ExecError::new(
+                            String::from(
+                                "GROUP BY .. WITH KEYS can't be combined with another GROUP BY",
+                            ),
+                            *span,
+                        ));
+                    }
+                    let mut kept = vec![];
+                    for mut row in rows {
+                        let key = column(&row, attr, *span)?.clone();
+                        if values.contains(&key.to_string()) {
+                            row.insert(attr.clone(), key);
+                            kept.push(row);
+                        }
+                    }
+                    rows = kept;
+                    listed = Some((attr, values));
+                }
+                AstNode::Limit { rows: n, .. } => rows.truncate(*n as usize),
+                // Only changes how much noise is added on release
+                AstNode::Epsilon(..) => {}
+                _ => {
+                    return Err(ExecError::new(
+                        String::from("qualifier is not supported by the executor yet"),
+                        q.span(),
+                    ))
+                }
+            }
+        }
+
+        let aggregating = exprs
+            .iter()
+            .any(|e| matches!(e, AstNode::Aggregation { .. }));
+        if !aggregating && keys.is_none() && listed.is_none() {
+            return rows.iter().map(|row| self.project(exprs, row)).collect();
+        }
+
+        // Without GROUP BY, aggregations are over a single group containing every row
+        let groups = match (keys, listed) {
+            (Some(keys), _) => group_rows(rows, keys, &bins),
+            (None, Some((attr, values))) => group_listed(rows, attr.clone(), values),
+            (None, None) => Groups {
+                keys: vec![],
+                values: vec![Row::new()],
+                groups: vec![rows],
+            },
+        };
+        let size = aggregated_rows(from, qualifiers);
+        let mut out = vec![];
+        for (group, values) in groups.groups.iter().zip(&groups.values) {
+            let mut row = Row::new();
+            for expr in exprs {
+                match expr {
+                    AstNode::Column(c, span) => {
+                        if !groups.keys.contains(c) {
+                            return Err(ExecError::new(
+                                format!(
+                                    "column `{}` must appear in GROUP BY to be selected with an aggregation",
+                                    c
+                                ),
+                                *span,
+                            ));
+                        }
+                        row.insert(c.clone(), values[c].clone());
+                    }
+                    AstNode::Aggregation {
+                        function,
+                        inner,
+                        range,
+                        alias,
+                        span,
+                        ..
+                    } => {
+                        // Values of the inner column may be bounded without explicit bounds
+                        let range = range.or_else(|| column_range(from, inner));
+                        row.insert(
+                            alias.clone(),
+                            aggregate(function, inner, &range, size, group, *span)?,
+                        );
+                    }
+                    _ => {
+                        return Err(ExecError::new(
+                            String::from(
+                                "only columns and aggregations can be selected with GROUP BY",
+                            ),
+                            expr.span(),
+                        ))
+                    }
+                }
+            }
+            out.push(row);
+        }
+        Ok(out)
+    }
+
+    // Evaluates the expressions of a non-aggregating SELECT on a single row
+    fn project(&self, exprs: &[AstNode], row: &Row) -> Result<Row, ExecError> {
+        let mut out = Row::new();
+        for expr in exprs {
+            match expr {
+                AstNode::Column(c, span) => {
+                    out.insert(c.clone(), column(row, c, *span)?.clone());
+                }
+                AstNode::UserF {
+                    function,
+                    column: c,
+                    range,
+                    alias,
+                    span,
+                    ..
+                } => {
+                    let f = self.functions.get(function).ok_or_else(|| {
+                        ExecError::new(format!("unknown user function `user_{}`", function), *span)
+                    })?;
+                    let value = match f(column(row, c, *span)?) {
+                        // Outputs are clamped to the declared range, which the sensitivity
+                        // calculation relies on
+                        Value::Number(x) => Value::Number(clamp(x, range)),
+                        Value::String(_) if range.is_some() => {
+                            return Err(ExecError::new(
+                                format!("user_{} returned a STRING but declares a range", function),
+                                *span,
+                            ))
+                        }
+                        value => value,
+                    };
+                    out.insert(alias.clone(), value);
+                }
+                _ => {
+                    return Err(ExecError::new(
+                        String::from("expression is not supported by the executor yet"),
+                        expr.span(),
+                    ))
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::execute::*;
+    use crate::parser::build;
+
+    const TABLES: &str = "SPLIT cam1 BEGIN 0 END 60 BY TIME 30sec INTO chunks1;
+PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 2 ROWS
+    WITH SCHEMA (plate:STRING=null, speed:NUMBER=0) INTO t1;
+SPLIT cam2 BEGIN 0 END 60 BY TIME 30sec INTO chunks2;
+PROCESS chunks2 USING yolov3 TIMEOUT 1sec PRODUCING 2 ROWS
+    WITH SCHEMA (plate:STRING=null, speed:NUMBER=0) INTO t2;
+";
+
+    fn row(plate: &str, speed: f64) -> Row {
+        vec![
+            (String::from("plate"), Value::String(plate.to_owned())),
+            (String::from("speed"), Value::Number(speed)),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn data() -> TableData {
+        let mut data = TableData::new();
+        data.insert(
+            String::from("t1"),
+            vec![
+                ChunkRows {
+                    chunk: 0,
+                    rows: vec![row("abc", 30.0), row("def", 50.0)],
+                },
+                ChunkRows {
+                    chunk: 1,
+                    rows: vec![row("abc", 70.0)],
+                },
+            ],
+        );
+        data.insert(
+            String::from("t2"),
+            vec![ChunkRows {
+                chunk: 0,
+                rows: vec![row("abc", 10.0), row("xyz", 20.0)],
+            }],
+        );
+        data
+    }
+
+    fn execute(select: &str) -> Result<Vec<Row>, ExecError> {
+        let query = build(&(String::from(TABLES) + select)).unwrap();
+        let data = data();
+        let mut executor = Executor::new(&data);
+        executor.register(
+            "double",
+            Box::new(|v| Value::Number(v.as_number().unwrap() * 2.0)),
+        );
+        executor
+            .execute(&query.select_stmts[0])
+            .map(|table| table.rows)
+    }
+
+    fn num(row: &Row, column: &str) -> f64 {
+        row[column].as_number().unwrap()
+    }
+
+    #[test]
+    fn aggregations() {
+        let rows = execute(
+            "SELECT count(plate), sum(speed,l=0,u=60), mean(speed), stddev(speed) FROM t1;",
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(num(&rows[0], "count(plate)"), 3.0);
+        // 70 is clamped to 60
+        assert_eq!(num(&rows[0], "sum(speed,l=0,u=60)"), 140.0);
+        // Over the 4 rows the 2 chunks can output, the missing one being 0
+        assert_eq!(num(&rows[0], "mean(speed)"), 37.5);
+        assert!((num(&rows[0], "stddev(speed)") - 25.8602).abs() < 1e-3);
+    }
+
+    #[test]
+    fn mean_size() {
+        let mean = |select: &str| {
+            let rows = execute(select).unwrap();
+            num(&rows[0], &rows[0].keys().next().unwrap().clone())
+        };
+        // LIMIT drops the values past it, and pads the missing ones with the bound nearest to 0
+        assert_eq!(mean("SELECT mean(speed,l=0,u=100) FROM t1 LIMIT 2;"), 40.0);
+        assert_eq!(mean("SELECT mean(speed,l=0,u=100) FROM t1 LIMIT 5;"), 30.0);
+        assert_eq!(
+            mean("SELECT mean(speed,l=10,u=100) FROM t1 WHERE speed > 100 LIMIT 2;"),
+            10.0
+        );
+        // Over the rows of both tables, 2 chunks of 2 rows each
+        assert_eq!(
+            mean("SELECT mean(speed,l=0,u=100) FROM (t1 UNION t2 ON plate);"),
+            22.5
+        );
+        // USER_ bounds apply to the values and the padding
+        assert_eq!(
+            mean("SELECT mean(s) FROM (SELECT USER_double(speed,l=70,u=100) as s FROM t1);"),
+            85.0
+        );
+    }
+
+    #[test]
+    fn where_and_group_by() {
+        let rows =
+            execute("SELECT plate, sum(speed,l=0,u=100) FROM t1 WHERE speed > 40 GROUP BY plate;")
+                .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["plate"], Value::String(String::from("def")));
+        assert_eq!(num(&rows[0], "sum(speed,l=0,u=100)"), 50.0);
+        assert_eq!(num(&rows[1], "sum(speed,l=0,u=100)"), 70.0);
+
+        // Every second of the split, even those without a chunk starting in them
+        let rows = execute("SELECT sec, count(plate) FROM t1 GROUP BY bin(chunk, sec);").unwrap();
+        assert_eq!(rows.len(), 31);
+        assert_eq!(num(&rows[0], "count(plate)"), 2.0);
+        assert_eq!(num(&rows[1], "sec"), 1.0);
+        assert_eq!(num(&rows[1], "count(plate)"), 0.0);
+        assert_eq!(num(&rows[30], "sec"), 30.0);
+        assert_eq!(num(&rows[30], "count(plate)"), 1.0);
+
+        // A WHERE matching no row still outputs every bin
+        let rows =
+            execute("SELECT min, count(plate) FROM t1 WHERE speed > 100 GROUP BY bin(chunk, min);")
+                .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(num(&rows[0], "min"), 0.0);
+        assert_eq!(num(&rows[0], "count(plate)"), 0.0);
+
+        // The mean of each bin is over the 2 rows of the single chunk that can start in it
+        let rows =
+            execute("SELECT sec, mean(speed,l=0,u=100) FROM t1 GROUP BY bin(chunk, sec);").unwrap();
+        assert_eq!(num(&rows[0], "mean(speed,l=0,u=100)"), 40.0);
+        assert_eq!(num(&rows[30], "mean(speed,l=0,u=100)"), 35.0);
+    }
+
+    #[test]
+    fn group_by_with_keys() {
+        // One row per listed key, in order, and none for the others
+        let rows = execute(
+            "SELECT plate, count(plate), sum(speed,l=0,u=100) FROM t1 GROUP BY plate WITH KEYS (xyz, abc);",
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["plate"], Value::String(String::from("xyz")));
+        assert_eq!(num(&rows[0], "count(plate)"), 0.0);
+        assert_eq!(num(&rows[0], "sum(speed,l=0,u=100)"), 0.0);
+        assert_eq!(rows[1]["plate"], Value::String(String::from("abc")));
+        assert_eq!(num(&rows[1], "count(plate)"), 2.0);
+        assert_eq!(num(&rows[1], "sum(speed,l=0,u=100)"), 100.0);
+    }
+
+    #[test]
+    fn combine_tables() {
+        let count = |select: &str| num(&execute(select).unwrap()[0], "count(plate)");
+        assert_eq!(
+            count("SELECT count(plate) FROM (t1 UNION t2 ON plate);"),
+            5.0
+        );
+        assert_eq!(
+            count("SELECT count(plate) FROM (t1 INTERSECT t2 ON plate);"),
+            3.0
+        );
+        // abc is in both, but only joined once
+        assert_eq!(
+            count("SELECT count(plate) FROM (t1 EQUIJOIN t2 ON plate);"),
+            1.0
+        );
+        // Not the 5 pairs of rows with the same plate
+        assert_eq!(
+            count("SELECT count(plate) FROM (t1 EQUIJOIN t1 ON plate);"),
+            2.0
+        );
+    }
+
+    #[test]
+    fn nested_select() {
+        let rows = execute(
+            "SELECT sum(fast,l=0,u=200) FROM (SELECT user_double(t1.speed,l=0,u=120) as fast FROM t1);",
+        )
+        .unwrap();
+        // 60 + 100 + 120 (140 clamped)
+        assert_eq!(num(&rows[0], "sum(fast,l=0,u=200)"), 280.0);
+    }
+
+    #[test]
+    fn errors() {
+        let err = execute("SELECT count(plate) FROM t1 WHERE plate > 10;").unwrap_err();
+        assert_eq!(err.message, "cannot compare `abc` with `10`");
+        assert_eq!(err.span.line, 7);
+
+        let err = execute("SELECT sum(plate,l=0,u=1) FROM t1;").unwrap_err();
+        assert_eq!(
+            err.message,
+            "sum() requires a NUMBER column, but `plate` is a STRING"
+        );
+
+        let err = execute("SELECT user_halve(t1.speed) as s FROM t1;").unwrap_err();
+        assert_eq!(err.message, "unknown user function `user_halve`");
+    }
+}
@@ -0,0 +1,193 @@
+/*
+ * C API, for embedding the parser and sensitivity calculator in other languages. The header is
+ * include/pql.h (regenerate it with `cbindgen --config cbindgen.toml --output include/pql.h`),
+ * whose declarations are enabled by defining PQL_FFI, the C counterpart of the `ffi` feature.
+ *
+ * Results are exchanged as JSON strings in the same format as the `pql` command-line tool's
+ * `--json` output. Functions return NULL on error, after which pql_last_error describes what went
+ * wrong. Every non-NULL string returned must be released with pql_free.
+ */
+use crate::config::PolicyConfig;
+use crate::parser::{build, PQLQuery};
+use crate::sensitivity::query_sensitivity;
+use crate::validate::{validate, Diagnostic};
+
+use serde_json::json;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+thread_local! {
+    // Message of the last error on this thread, returned by pql_last_error
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // Interior NULs would truncate the message in C, so replace them
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+fn clear_last_error() {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+}
+
+unsafe fn read_str<'a>(s: *const c_char, name: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{} is NULL", name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| format!("{} is not valid UTF-8", name))
+}
+
+fn parse(query: &str) -> Result<PQLQuery, String> {
+    build(query).map_err(|e| Diagnostic::from(&e).render(query))
+}
+
+fn render_all(source: &str, diags: &[Diagnostic]) -> String {
+    let rendered: Vec<String> = diags.iter().map(|d| d.render(source)).collect();
+    rendered.join("\n")
+}
+
+// Runs `f`, converting its result (or a panic) into the string returned over the C API
+fn to_c(f: impl FnOnce() -> Result<String, String>) -> *mut c_char {
+    clear_last_error();
+    let res = catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(String::from("internal error while computing sensitivity")));
+    match res {
+        Ok(json) => CString::new(json).unwrap().into_raw(),
+        Err(message) => {
+            set_last_error(message);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Parses `query`, returning the JSON serialization of the PQLQuery, or NULL if it has a syntax
+/// error.
+///
+/// # Safety
+///
+/// `query` must be NULL or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pql_parse(query: *const c_char) -> *mut c_char {
+    to_c(|| {
+        let query = parse(read_str(query, "query")?)?;
+        serde_json::to_string(&query).map_err(|e| e.to_string())
+    })
+}
+
+/// Computes the sensitivity and noise scale of each column released by `query`, given the JSON
+/// policy file `policies`. Returns `{"ok": true, "statements": [...]}`, or NULL if the query or
+/// policies are invalid.
+///
+/// # Safety
+///
+/// `query` and `policies` must be NULL or valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn pql_sensitivity(
+    query: *const c_char,
+    policies: *const c_char,
+) -> *mut c_char {
+    to_c(|| {
+        let source = read_str(query, "query")?;
+        let policies = PolicyConfig::from_json(read_str(policies, "policies")?)
+            .map_err(|e| e.to_string())?
+            .policies;
+        let query = parse(source)?;
+        let diags = validate(&query, &policies);
+        if !diags.is_empty() {
+            return Err(render_all(source, &diags));
+        }
+        let statements = query_sensitivity(&query, &policies);
+        Ok(json!({ "ok": true, "statements": statements }).to_string())
+    })
+}
+
+/// Returns the message of the last error on this thread, or NULL if the last call succeeded. The
+/// string is owned by the library and is valid until the next call on this thread; don't free it.
+#[no_mangle]
+pub extern "C" fn pql_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+/// Frees a string returned by pql_parse or pql_sensitivity. Does nothing if `s` is NULL.
+///
+/// # Safety
+///
+/// `s` must be NULL or a string returned by this library that hasn't been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn pql_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ffi::*;
+
+    const QUERY: &str = "SPLIT cam1 BEGIN 0 END 60 BY TIME 5sec INTO chunks1;
+PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 10 ROWS
+    WITH SCHEMA (plate:STRING=null, speed:NUMBER=0) INTO table1;
+SELECT count(plate), sum(speed,l=0,u=10) FROM table1;
+";
+
+    const POLICIES: &str =
+        r#"{"cameras": {"cam1": {"k_segments": 1, "rho": "30sec", "epsilon": 0.5}}}"#;
+
+    // Calls a C API function, returning its result or the last error
+    unsafe fn call(res: *mut c_char) -> Result<serde_json::Value, String> {
+        if res.is_null() {
+            return Err(CStr::from_ptr(pql_last_error())
+                .to_str()
+                .unwrap()
+                .to_owned());
+        }
+        let json = serde_json::from_str(CStr::from_ptr(res).to_str().unwrap()).unwrap();
+        pql_free(res);
+        assert!(pql_last_error().is_null());
+        Ok(json)
+    }
+
+    #[test]
+    fn sensitivity() {
+        let query = CString::new(QUERY).unwrap();
+        let policies = CString::new(POLICIES).unwrap();
+        let out = unsafe { call(pql_sensitivity(query.as_ptr(), policies.as_ptr())) }.unwrap();
+        assert_eq!(
+            out["statements"][0]["columns"][1],
+            json!({
+                "name": "sum(speed,l=0,u=10)",
+                "sensitivity": 700.0,
+                "l2_sensitivity": 700.0,
+                "epsilon": 0.25,
+                "noise_scale": 2800.0
+            })
+        );
+
+        let parsed = unsafe { call(pql_parse(query.as_ptr())) }.unwrap();
+        assert_eq!(parsed["select_stmts"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn errors() {
+        let invalid = CString::new(QUERY.replace("cam1", "cam9")).unwrap();
+        let policies = CString::new(POLICIES).unwrap();
+        let err = unsafe { call(pql_sensitivity(invalid.as_ptr(), policies.as_ptr())) };
+        assert!(err
+            .unwrap_err()
+            .contains("no privacy policy for camera `cam9`"));
+
+        let syntax = CString::new("SPLIT cam1 BEGIN;").unwrap();
+        let err = unsafe { call(pql_parse(syntax.as_ptr())) };
+        assert!(err.unwrap_err().contains("--> 1:"));
+
+        let err = unsafe { call(pql_parse(ptr::null())) };
+        assert_eq!(err, Err(String::from("query is NULL")));
+        unsafe { pql_free(ptr::null_mut()) };
+    }
+}
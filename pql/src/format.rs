@@ -0,0 +1,391 @@
+use crate::aggregation::{ArithmeticOp, AstNode, BooleanOp, GroupByAttr};
+use crate::parser::PQLQuery;
+use crate::process::{Column, ProcessStatement};
+use crate::split::SplitStatement;
+
+use std::fmt;
+
+// Largest unit that evenly divides `ms`, so durations are printed the way an analyst would write
+// them (e.g. 7200000 -> 2hr). `month` is skipped because it is not a fixed number of weeks.
+pub fn fmt_duration(ms: u64) -> String {
+    let units = [
+        ("week", 1_000 * 60 * 60 * 24 * 7),
+        ("day", 1_000 * 60 * 60 * 24),
+        ("hr", 1_000 * 60 * 60),
+        ("min", 1_000 * 60),
+        ("sec", 1_000),
+    ];
+    for (unit, size) in units.iter() {
+        if ms > 0 && ms % size == 0 {
+            return format!("{}{}", ms / size, unit);
+        }
+    }
+    format!("{}ms", ms)
+}
+
+fn fmt_range(range: &Option<(f64, f64)>) -> String {
+    match range {
+        Some((l, u)) => format!(",l={},u={}", l, u),
+        None => String::new(),
+    }
+}
+
+fn fmt_epsilon(epsilon: &Option<f64>) -> String {
+    match epsilon {
+        Some(e) => format!(" EPSILON {}", e),
+        None => String::new(),
+    }
+}
+
+fn fmt_list<T: fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl fmt::Display for BooleanOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BooleanOp::GreaterThan => ">",
+            BooleanOp::GreaterThanOrEqualTo => ">=",
+            BooleanOp::LessThan => "<",
+            BooleanOp::LessThanOrEqualTo => "<=",
+            BooleanOp::Equal => "==",
+            BooleanOp::NotEqual => "!=",
+            BooleanOp::And => "&&",
+            BooleanOp::Or => "||",
+            BooleanOp::Not => "!",
+        })
+    }
+}
+
+impl fmt::Display for ArithmeticOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ArithmeticOp::Plus => "+",
+            ArithmeticOp::Minus => "-",
+            ArithmeticOp::Multiply => "*",
+            ArithmeticOp::Divide => "/",
+            ArithmeticOp::Mod => "%",
+        })
+    }
+}
+
+impl fmt::Display for GroupByAttr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GroupByAttr::Column(c) => f.write_str(c),
+            GroupByAttr::Bin { column, alias, .. } => write!(f, "bin({},{})", column, alias),
+        }
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Column::String(sc) => write!(f, "{}:STRING={}", sc.name, sc.default),
+            Column::Number(nc) => write!(f, "{}:NUMBER={}", nc.name, nc.default),
+            // Fixed columns are added by the parser and can't be written in a query
+            Column::Fixed(fc) => f.write_str(&fc.name),
+        }
+    }
+}
+
+impl fmt::Display for SplitStatement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SPLIT {}\n    BEGIN {}\n    END {}\n    BY TIME {}\n    INTO {}",
+            self.camera_name,
+            self.start_time,
+            self.end_time,
+            fmt_duration(self.chunk_length_ms),
+            self.output_name
+        )
+    }
+}
+
+impl fmt::Display for ProcessStatement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let schema: Vec<&Column> = self
+            .schema
+            .iter()
+            .filter(|c| !matches!(c, Column::Fixed(_)))
+            .collect();
+        write!(
+            f,
+            "PROCESS {}\n    USING {}\n    TIMEOUT {}\n    PRODUCING {} ROWS\n    WITH SCHEMA ({})\n    INTO {}",
+            self.input_name,
+            self.model_name,
+            fmt_duration(self.chunk_timeout_ms),
+            self.maxrow,
+            fmt_list(&schema),
+            self.output_table_name
+        )
+    }
+}
+
+impl AstNode {
+    // Predicates nested inside another predicate must be wrapped in parens, the grammar does not
+    // have operator precedence
+    fn fmt_term(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AstNode::Predicate { .. } => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+
+    fn fmt_combine(
+        f: &mut fmt::Formatter,
+        kind: &str,
+        tables: &[AstNode],
+        on: &AstNode,
+    ) -> fmt::Result {
+        // The parser builds the list of tables in reverse order
+        let tables: Vec<String> = tables.iter().rev().map(|t| t.to_string()).collect();
+        write!(
+            f,
+            "({} {} {} ON {})",
+            tables[0],
+            kind,
+            tables[1..].join(" "),
+            on
+        )
+    }
+
+    /// Formats this node as canonical PQL text
+    pub fn to_pql(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for AstNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AstNode::Select {
+                exprs,
+                from,
+                qualifiers,
+                ..
+            } => {
+                write!(f, "SELECT {} FROM ", fmt_list(exprs))?;
+                match **from {
+                    AstNode::Select { .. } => write!(f, "({})", from)?,
+                    _ => write!(f, "{}", from)?,
+                }
+                for q in qualifiers {
+                    write!(f, " {}", q)?;
+                }
+                Ok(())
+            }
+            AstNode::Intersect { tables, on, .. } => {
+                AstNode::fmt_combine(f, "INTERSECT", tables, on)
+            }
+            AstNode::Union { tables, on, .. } => AstNode::fmt_combine(f, "UNION", tables, on),
+            AstNode::Equijoin { tables, on, .. } => AstNode::fmt_combine(f, "EQUIJOIN", tables, on),
+            AstNode::Aggregation {
+                function,
+                inner,
+                range,
+                epsilon,
+                ..
+            } => write!(
+                f,
+                "{}({}{}){}",
+                function,
+                inner,
+                fmt_range(range),
+                fmt_epsilon(epsilon)
+            ),
+            AstNode::UserF {
+                function,
+                column,
+                range,
+                alias,
+                epsilon,
+                ..
+            } => write!(
+                f,
+                "USER_{}({}{}) as {}{}",
+                function,
+                column,
+                fmt_range(range),
+                alias,
+                fmt_epsilon(epsilon)
+            ),
+            AstNode::AliasExpr {
+                lhs,
+                op,
+                rhs,
+                alias,
+                ..
+            } => write!(f, "({} {} {}) as {}", lhs, op, rhs, alias),
+            AstNode::GroupBy { attrs, .. } => write!(f, "GROUP BY {}", fmt_list(attrs)),
+            AstNode::GroupByWithKeys { attr, keys, .. } => {
+                write!(f, "GROUP BY {} WITH KEYS ({})", attr, fmt_list(keys))
+            }
+            AstNode::Limit { rows, .. } => write!(f, "LIMIT {}", rows),
+            AstNode::Where { predicate, .. } => write!(f, "WHERE {}", predicate),
+            AstNode::Predicate { lhs, op, rhs, .. } => {
+                lhs.fmt_term(f)?;
+                write!(f, " {} ", op)?;
+                rhs.fmt_term(f)
+            }
+            AstNode::TableNames(names, _) => f.write_str(&names.join(", ")),
+            AstNode::Tables(tables, _) => f.write_str(&fmt_list(tables)),
+            AstNode::Table(_, ps) => f.write_str(&ps.output_table_name),
+            AstNode::Column(c, _) => f.write_str(c),
+            AstNode::Value(v, _) => write!(f, "{}", v),
+            AstNode::DurationMs(ms, _) => write!(f, "WITH WINDOW {}", fmt_duration(*ms)),
+            AstNode::Epsilon(epsilon, _) => write!(f, "WITH EPSILON {}", epsilon),
+        }
+    }
+}
+
+impl PQLQuery {
+    /// Formats this query as canonical PQL text. Statements are sorted by name, with each SPLIT
+    /// followed by the PROCESSes that read its chunks, so two queries that build to the same
+    /// PQLQuery are formatted identically.
+    pub fn to_pql(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for PQLQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut splits: Vec<&SplitStatement> = self.split_stmts.values().collect();
+        splits.sort_by(|a, b| a.output_name.cmp(&b.output_name));
+        let mut processes: Vec<&ProcessStatement> = self.process_stmts.values().collect();
+        processes.sort_by(|a, b| a.output_table_name.cmp(&b.output_table_name));
+
+        for ss in &splits {
+            writeln!(f, "{};", ss)?;
+            for ps in processes
+                .iter()
+                .filter(|ps| ps.input_name == ss.output_name)
+            {
+                writeln!(f, "{};", ps)?;
+            }
+            writeln!(f)?;
+        }
+        let orphans: Vec<_> = processes
+            .iter()
+            .filter(|ps| !self.split_stmts.contains_key(&ps.input_name))
+            .collect();
+        for ps in &orphans {
+            writeln!(f, "{};", ps)?;
+        }
+        if !orphans.is_empty() {
+            writeln!(f)?;
+        }
+        for stmt in &self.select_stmts {
+            writeln!(f, "{};", stmt)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::build;
+
+    fn tables() -> &'static str {
+        "SPLIT cam1
+            BEGIN 0
+            END 0
+            BY TIME 5sec
+            INTO chunks1;
+
+            PROCESS chunks1 USING yolov3 TIMEOUT 1sec
+            PRODUCING 10 ROWS
+            WITH SCHEMA (plate:STRING=null, speed:NUMBER=0.75)
+            INTO table1;
+
+        SPLIT cam2
+            BEGIN 0
+            END 0
+            BY TIME 1sec
+            INTO chunks2;
+
+        PROCESS chunks2 USING yolov3 TIMEOUT 1sec
+            PRODUCING 2 ROWS
+            WITH SCHEMA (plate:STRING=null, speed:NUMBER=0)
+            INTO table2;
+
+        SPLIT auburn
+            BEGIN 0
+            END 43200
+            BY TIME 10.5sec
+            INTO auburn_chunks;
+
+        PROCESS auburn_chunks USING auburn.py TIMEOUT 1sec
+            PRODUCING 1 ROWS
+            WITH SCHEMA (ppl:NUMBER=0)
+            INTO auburnPpl;
+        "
+    }
+
+    fn check_round_trip(select_str: &str) {
+        let query_string = format!("{} {}", tables(), select_str);
+        let query = build(query_string.as_str()).unwrap();
+        let formatted = query.to_pql();
+        let rebuilt = build(formatted.as_str()).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(
+            rebuilt.without_spans(),
+            query.without_spans(),
+            "formatted:\n{}",
+            formatted
+        );
+        // Formatting is canonical, so formatting again doesn't change anything
+        assert_eq!(rebuilt.to_pql(), formatted);
+    }
+
+    #[test]
+    fn round_trip() {
+        for q in &[
+            "SELECT count(plate) FROM table1;",
+            "SELECT sum(speed,l=0,u=10) FROM table1;",
+            "SELECT count(plate) FROM (table1 UNION table2 ON plate);",
+            "SELECT count(plate) FROM (table1 INTERSECT table2 auburnPpl ON plate);",
+            "SELECT count(plate) FROM ((table1 INTERSECT table2 ON plate) UNION (auburnPpl INTERSECT table1 ON plate) ON plate);",
+            "SELECT mean(speed) FROM (SELECT user_f(speed,l=30,u=60) as speed FROM (table1 EQUIJOIN table2 ON plate));",
+            "SELECT count(plate) FROM table1 WHERE ((t1_day > t2_day) && (x > 10.5)) || (y < 3);",
+            "SELECT state,count(state) FROM (SELECT user_get_state(plate) as state FROM table1) GROUP BY state;",
+            "SELECT plate,day,USER_shift(chunk,l=0,u=16) as shift FROM table1 GROUP BY plate,bin(chunk,day);",
+            "SELECT plate,count(plate) FROM table1 GROUP BY plate WITH KEYS (ABC123,XYZ9);",
+            "SELECT sum(plate) FROM table1 LIMIT 100;",
+            "SELECT count(plate) EPSILON 0.2,USER_shift(chunk,l=0,u=16) as shift EPSILON 0.1 FROM table1 WITH EPSILON 0.5;",
+            "SELECT sum(ppl,l=0,u=6) FROM auburnPpl WITH WINDOW 2 hrs ;",
+            "SELECT count(plate) FROM table1; SELECT sum(ppl,l=0,u=6) FROM auburnPpl;",
+        ] {
+            check_round_trip(q);
+        }
+    }
+
+    #[test]
+    fn canonical_text() {
+        let query_string = "SPLIT cam1 BEGIN 0 END 10 BY TIME 10.5sec INTO chunks1;
+            PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 10 ROWS
+            WITH SCHEMA (plate:STRING=null, speed:NUMBER=0.75) INTO table1;
+            SELECT   count(plate)  FROM (table1   UNION table1 ON plate) GROUP BY bin(chunk, hr);";
+        assert_eq!(
+            build(query_string).unwrap().to_pql(),
+            "SPLIT cam1
+    BEGIN 0
+    END 10
+    BY TIME 10500ms
+    INTO chunks1;
+PROCESS chunks1
+    USING yolov3
+    TIMEOUT 1sec
+    PRODUCING 10 ROWS
+    WITH SCHEMA (plate:STRING=null, speed:NUMBER=0.75)
+    INTO table1;
+
+SELECT count(plate) FROM (table1 UNION table1 ON plate) GROUP BY bin(chunk,hr);
+"
+        );
+    }
+}
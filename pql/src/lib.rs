@@ -4,9 +4,32 @@ extern crate pest_derive;
 
 extern crate chrono;
 
+pub mod accounting;
+pub mod accuracy;
 pub mod aggregation;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod execute;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod format;
+pub mod model;
+#[cfg(feature = "online")]
+pub mod online;
 pub mod parser;
+pub mod planner;
 pub mod policy;
 pub mod process;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "release")]
+pub mod release;
+pub mod runner;
+#[cfg(all(feature = "sandbox", unix))]
+pub mod sandbox;
+pub mod sanitize;
 pub mod sensitivity;
 pub mod split;
+pub mod validate;
+#[cfg(feature = "wasm")]
+pub mod wasm;
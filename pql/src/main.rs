@@ -0,0 +1,726 @@
+use pql::accounting::{Accountant, Mechanism, PureComposition};
+use pql::accuracy::estimate_accuracy;
+use pql::config::PolicyConfig;
+use pql::format::fmt_duration;
+use pql::parser::{build, parse_duration_str, PQLQuery};
+use pql::planner::{self, PlanSpace};
+use pql::policy::PolicyMap;
+use pql::sensitivity::{query_costs, query_sensitivity};
+use pql::validate::{validate, validate_query, Diagnostic};
+
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+const USAGE: &str = "usage: pql <command> [options] <query.pql>
+
+Commands:
+    check          parse and validate a query (against --policies, if given)
+    sensitivity    compute the sensitivity and noise scale of each released column
+    accuracy       estimate the error of each released column before running the query
+    plan           search chunk lengths, rows per chunk and epsilons for the best trade-offs
+                   between noise and budget
+    fmt            print a query in canonical form
+
+Options:
+    --policies <file>   TOML or JSON policy file, required by `sensitivity` and `accuracy`
+    --epsilon <value>   epsilon of every statement for `accuracy` (default: allocated from the
+                        policies)
+    --mechanism <name>  noise estimated by `accuracy`: laplace (default) or gaussian
+    --delta <value>     delta of the gaussian mechanism, making it (epsilon, delta)-DP instead of
+                        zCDP
+    --chunk-lengths <durations>, --rows <counts>, --epsilons <values>
+                        comma-separated values tried by `plan` for the BY TIME of every SPLIT,
+                        the PRODUCING of every PROCESS and the epsilon of every policy (default:
+                        as in the query and policies)
+    --target <noise scale>
+                        largest acceptable noise scale, for `plan`
+    --json              print machine-readable JSON
+    -h, --help          print this message
+
+Use `-` as the query path to read the query from stdin.
+
+Exit status is 0 on success, 1 if the query is invalid, and 2 for any other error.";
+
+// Exit codes
+const OK: i32 = 0;
+const INVALID_QUERY: i32 = 1;
+const ERROR: i32 = 2;
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Check,
+    Sensitivity,
+    Accuracy,
+    Plan,
+    Fmt,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    command: Command,
+    query_path: String,
+    policies_path: Option<String>,
+    epsilon: Option<f64>,
+    mechanism: Mechanism,
+    space: PlanSpace,
+    target: Option<f64>,
+    json: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut args = args.iter();
+    let command = match args.next().map(|a| a.as_str()) {
+        Some("check") => Command::Check,
+        Some("sensitivity") => Command::Sensitivity,
+        Some("accuracy") => Command::Accuracy,
+        Some("plan") => Command::Plan,
+        Some("fmt") => Command::Fmt,
+        Some(c) => return Err(format!("unknown command '{}'", c)),
+        None => return Err(String::from("missing command")),
+    };
+
+    let mut query_path = None;
+    let mut policies_path = None;
+    let mut epsilon = None;
+    let mut mechanism = String::from("laplace");
+    let mut delta = None;
+    let mut space = PlanSpace::default();
+    let mut target = None;
+    let mut json = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--policies" => match args.next() {
+                Some(p) => policies_path = Some(p.clone()),
+                None => return Err(String::from("--policies requires a file")),
+            },
+            "--epsilon" => match args.next().map(|e| e.parse::<f64>()) {
+                Some(Ok(e)) if e > 0.0 => epsilon = Some(e),
+                _ => return Err(String::from("--epsilon requires a positive number")),
+            },
+            "--mechanism" => match args.next() {
+                Some(m) => mechanism = m.clone(),
+                None => return Err(String::from("--mechanism requires laplace or gaussian")),
+            },
+            "--delta" => match args.next().map(|d| d.parse::<f64>()) {
+                Some(Ok(d)) => delta = Some(d),
+                _ => return Err(String::from("--delta requires a number")),
+            },
+            "--chunk-lengths" => {
+                space.chunk_lengths_ms = parse_list(args.next(), |d| {
+                    parse_duration_str(d).ok().filter(|ms| *ms > 0)
+                })
+                .ok_or_else(|| String::from("--chunk-lengths requires durations like 5sec,30sec"))?
+            }
+            "--rows" => {
+                space.max_rows =
+                    parse_list(args.next(), |r| r.parse::<u64>().ok().filter(|r| *r > 0))
+                        .ok_or_else(|| String::from("--rows requires positive integers"))?
+            }
+            "--epsilons" => {
+                space.epsilons =
+                    parse_list(args.next(), |e| e.parse::<f64>().ok().filter(|e| *e > 0.0))
+                        .ok_or_else(|| String::from("--epsilons requires positive numbers"))?
+            }
+            "--target" => match args.next().map(|t| t.parse::<f64>()) {
+                Some(Ok(t)) if t > 0.0 => target = Some(t),
+                _ => return Err(String::from("--target requires a positive number")),
+            },
+            "--json" => json = true,
+            a if a.starts_with("--") => return Err(format!("unknown option '{}'", a)),
+            a if query_path.is_none() => query_path = Some(a.to_owned()),
+            a => return Err(format!("unexpected argument '{}'", a)),
+        }
+    }
+
+    let query_path = query_path.ok_or_else(|| String::from("missing query file"))?;
+    let mechanism = Mechanism::from_name(&mechanism, delta)?;
+    if policies_path.is_none() {
+        match command {
+            Command::Sensitivity => return Err(String::from("sensitivity requires --policies")),
+            Command::Accuracy => return Err(String::from("accuracy requires --policies")),
+            Command::Plan => return Err(String::from("plan requires --policies")),
+            _ => {}
+        }
+    }
+    Ok(Options {
+        command,
+        query_path,
+        policies_path,
+        epsilon,
+        mechanism,
+        space,
+        target,
+        json,
+    })
+}
+
+// Comma-separated list of values, None if it is missing or any value is invalid
+fn parse_list<T>(arg: Option<&String>, parse: impl Fn(&str) -> Option<T>) -> Option<Vec<T>> {
+    arg?.split(',').map(|v| parse(v.trim())).collect()
+}
+
+// Where output goes, so that tests can capture it
+struct Output<'a> {
+    out: &'a mut dyn Write,
+    err: &'a mut dyn Write,
+    json: bool,
+}
+
+impl Output<'_> {
+    // Reports an error that isn't a problem with the query itself
+    fn error(&mut self, message: String) -> i32 {
+        if self.json {
+            let _ = writeln!(self.out, "{}", json!({ "ok": false, "error": message }));
+        } else {
+            let _ = writeln!(self.err, "error: {}", message);
+        }
+        ERROR
+    }
+
+    fn diagnostics(&mut self, source: &str, diags: &[Diagnostic]) -> i32 {
+        if self.json {
+            let _ = writeln!(self.out, "{}", json!({ "ok": false, "diagnostics": diags }));
+        } else {
+            for d in diags {
+                let _ = writeln!(self.err, "{}", d.render(source));
+            }
+            let _ = writeln!(
+                self.err,
+                "error: query has {} problem{}",
+                diags.len(),
+                if diags.len() == 1 { "" } else { "s" }
+            );
+        }
+        INVALID_QUERY
+    }
+}
+
+fn read_query(path: &str) -> io::Result<String> {
+    if path == "-" {
+        let mut query = String::new();
+        io::stdin().read_to_string(&mut query)?;
+        Ok(query)
+    } else {
+        std::fs::read_to_string(path)
+    }
+}
+
+fn check(out: &mut Output, source: &str, query: &PQLQuery, policies: Option<&PolicyMap>) -> i32 {
+    let diags = match policies {
+        Some(policies) => validate(query, policies),
+        None => validate_query(query),
+    };
+    if !diags.is_empty() {
+        return out.diagnostics(source, &diags);
+    }
+    if out.json {
+        let _ = writeln!(out.out, "{}", json!({ "ok": true, "diagnostics": [] }));
+    } else {
+        let _ = writeln!(out.out, "ok");
+    }
+    OK
+}
+
+fn sensitivity(out: &mut Output, source: &str, query: &PQLQuery, policies: &PolicyMap) -> i32 {
+    let diags = validate(query, policies);
+    if !diags.is_empty() {
+        return out.diagnostics(source, &diags);
+    }
+
+    let statements = query_sensitivity(query, policies);
+    // Epsilon spent on each camera by releasing every statement with Laplace noise
+    let cameras: BTreeMap<String, f64> = query_costs(&statements, policies, Mechanism::Laplace)
+        .into_iter()
+        .map(|(camera, costs)| (camera, PureComposition.epsilon(&costs)))
+        .collect();
+    if out.json {
+        let _ = writeln!(
+            out.out,
+            "{}",
+            json!({ "ok": true, "statements": statements, "cameras": cameras })
+        );
+        return OK;
+    }
+    for stmt in &statements {
+        let _ = writeln!(out.out, "{};", stmt.query);
+        let _ = writeln!(
+            out.out,
+            "    cameras: {}, epsilon: {}",
+            stmt.cameras.join(", "),
+            stmt.epsilon.map_or(String::from("-"), |e| e.to_string())
+        );
+        for (camera, hash) in &stmt.policy_hashes {
+            let _ = writeln!(out.out, "    policy of {}: {}", camera, hash);
+        }
+        for c in &stmt.columns {
+            match (c.sensitivity, c.epsilon, c.noise_scale) {
+                (Some(s), Some(e), Some(b)) => {
+                    let _ = writeln!(
+                        out.out,
+                        "    {}: sensitivity {}, epsilon {}, noise scale {}",
+                        c.name, s, e, b
+                    );
+                }
+                // Columns without a bound are only released as keys of other columns
+                _ => {
+                    let _ = writeln!(out.out, "    {}: not released with noise", c.name);
+                }
+            }
+        }
+        if let (Some(l1), Some(l2)) = (stmt.l1_sensitivity, stmt.l2_sensitivity) {
+            let _ = writeln!(
+                out.out,
+                "    all columns: L1 sensitivity {}, L2 sensitivity {}",
+                l1, l2
+            );
+        }
+    }
+    for (camera, epsilon) in &cameras {
+        let _ = writeln!(out.out, "camera {}: epsilon {} in total", camera, epsilon);
+    }
+    OK
+}
+
+fn accuracy(
+    out: &mut Output,
+    source: &str,
+    query: &PQLQuery,
+    policies: &PolicyMap,
+    epsilon: Option<f64>,
+    mechanism: Mechanism,
+) -> i32 {
+    let diags = validate(query, policies);
+    if !diags.is_empty() {
+        return out.diagnostics(source, &diags);
+    }
+
+    let statements = estimate_accuracy(query, policies, epsilon, mechanism);
+    if out.json {
+        let _ = writeln!(
+            out.out,
+            "{}",
+            json!({ "ok": true, "statements": statements })
+        );
+        return OK;
+    }
+    for stmt in &statements {
+        let _ = writeln!(out.out, "{};", stmt.query);
+        let _ = writeln!(
+            out.out,
+            "    epsilon: {}",
+            stmt.epsilon.map_or(String::from("-"), |e| e.to_string())
+        );
+        for c in &stmt.columns {
+            if c.intervals.is_empty() {
+                let _ = writeln!(out.out, "    {}: not released with noise", c.name);
+                continue;
+            }
+            let intervals: Vec<String> = c
+                .intervals
+                .iter()
+                .map(|i| match i.relative_error {
+                    Some(r) => format!(
+                        "{}%: +-{:.3} ({:.2}% of the range)",
+                        i.confidence * 100.0,
+                        i.half_width,
+                        r * 100.0
+                    ),
+                    None => format!("{}%: +-{:.3}", i.confidence * 100.0, i.half_width),
+                })
+                .collect();
+            let _ = writeln!(out.out, "    {}: {}", c.name, intervals.join(", "));
+        }
+    }
+    OK
+}
+
+fn plan(
+    out: &mut Output,
+    source: &str,
+    query: &PQLQuery,
+    policies: &PolicyMap,
+    space: &PlanSpace,
+    target: Option<f64>,
+) -> i32 {
+    let diags = validate(query, policies);
+    if !diags.is_empty() {
+        return out.diagnostics(source, &diags);
+    }
+
+    let plans = planner::plan(query, policies, space);
+    let best = target.and_then(|t| planner::cheapest(&plans, t));
+    let min_epsilon = target.and_then(|t| planner::min_epsilon(query, policies, t));
+    if out.json {
+        let _ = writeln!(
+            out.out,
+            "{}",
+            json!({ "ok": true, "plans": plans, "best": best, "min_epsilon": min_epsilon })
+        );
+        return OK;
+    }
+    for p in &plans {
+        let mut settings = vec![];
+        if let Some(ms) = p.chunk_length_ms {
+            settings.push(format!("BY TIME {}", fmt_duration(ms)));
+        }
+        if let Some(rows) = p.max_rows {
+            settings.push(format!("PRODUCING {} ROWS", rows));
+        }
+        if let Some(epsilon) = p.epsilon {
+            settings.push(format!("epsilon {}", epsilon));
+        }
+        if settings.is_empty() {
+            settings.push(String::from("as written"));
+        }
+        let _ = writeln!(
+            out.out,
+            "{}{}: noise scale {}, cost {}",
+            if best == Some(p) { "* " } else { "  " },
+            settings.join(", "),
+            p.noise_scale,
+            p.cost
+        );
+    }
+    if let Some(target) = target {
+        if best.is_none() {
+            let _ = writeln!(out.out, "no plan reaches a noise scale of {}", target);
+        }
+        if let Some(epsilon) = min_epsilon {
+            let _ = writeln!(
+                out.out,
+                "the query as written needs epsilon {} to reach a noise scale of {}",
+                epsilon, target
+            );
+        }
+    }
+    OK
+}
+
+fn fmt(out: &mut Output, query: &PQLQuery) -> i32 {
+    if out.json {
+        let _ = writeln!(
+            out.out,
+            "{}",
+            json!({ "ok": true, "formatted": query.to_pql() })
+        );
+    } else {
+        let _ = write!(out.out, "{}", query.to_pql());
+    }
+    OK
+}
+
+fn run(args: &[String], out: &mut dyn Write, err: &mut dyn Write) -> i32 {
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        let _ = writeln!(out, "{}", USAGE);
+        return OK;
+    }
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            let _ = writeln!(err, "error: {}\n\n{}", e, USAGE);
+            return ERROR;
+        }
+    };
+    let mut out = Output {
+        out,
+        err,
+        json: options.json,
+    };
+
+    let source = match read_query(&options.query_path) {
+        Ok(source) => source,
+        Err(e) => return out.error(format!("could not read {}: {}", options.query_path, e)),
+    };
+    let policies = match &options.policies_path {
+        Some(path) => match PolicyConfig::load(path) {
+            Ok(config) => Some(config.policies),
+            Err(e) => return out.error(e.to_string()),
+        },
+        None => None,
+    };
+    let query = match build(&source) {
+        Ok(query) => query,
+        Err(e) => return out.diagnostics(&source, &[Diagnostic::from(&e)]),
+    };
+
+    match options.command {
+        Command::Check => check(&mut out, &source, &query, policies.as_ref()),
+        Command::Sensitivity => sensitivity(&mut out, &source, &query, policies.as_ref().unwrap()),
+        Command::Accuracy => accuracy(
+            &mut out,
+            &source,
+            &query,
+            policies.as_ref().unwrap(),
+            options.epsilon,
+            options.mechanism,
+        ),
+        Command::Plan => plan(
+            &mut out,
+            &source,
+            &query,
+            policies.as_ref().unwrap(),
+            &options.space,
+            options.target,
+        ),
+        Command::Fmt => fmt(&mut out, &query),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let code = run(&args, &mut io::stdout(), &mut io::stderr());
+    std::process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const QUERY: &str = "SPLIT cam1 BEGIN 0 END 60 BY TIME 5sec INTO chunks1;
+PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 10 ROWS
+    WITH SCHEMA (plate:STRING=null, speed:NUMBER=0) INTO table1;
+SELECT count(plate), sum(speed,l=0,u=10) FROM table1;
+";
+
+    const POLICIES: &str = "[cameras.cam1]
+k_segments = 1
+rho = \"30sec\"
+epsilon = 0.5
+";
+
+    // Writes `contents` to a file in a fresh temporary directory, returning its path. Every call
+    // gets its own directory, since tests run in parallel and reuse the same file names.
+    fn write_tmp(name: &str, contents: &str) -> String {
+        static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "pql-cli-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn run_cli(args: &[&str]) -> (i32, String, String) {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let (mut out, mut err) = (vec![], vec![]);
+        let code = run(&args, &mut out, &mut err);
+        (
+            code,
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(err).unwrap(),
+        )
+    }
+
+    #[test]
+    fn parse_options() {
+        let args: Vec<String> = vec!["sensitivity", "q.pql", "--json", "--policies", "p.toml"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            parse_args(&args),
+            Ok(Options {
+                command: Command::Sensitivity,
+                query_path: String::from("q.pql"),
+                policies_path: Some(String::from("p.toml")),
+                epsilon: None,
+                mechanism: Mechanism::Laplace,
+                space: PlanSpace::default(),
+                target: None,
+                json: true,
+            })
+        );
+        assert!(parse_args(&args[..2]).is_err());
+        assert_eq!(run_cli(&["frobnicate", "q.pql"]).0, ERROR);
+    }
+
+    #[test]
+    fn check_query() {
+        let query = write_tmp("check.pql", QUERY);
+        assert_eq!(
+            run_cli(&["check", &query]),
+            (OK, String::from("ok\n"), String::new())
+        );
+
+        let invalid = write_tmp("invalid.pql", &QUERY.replace("count(plate)", "sum(plate)"));
+        let (code, _, err) = run_cli(&["check", &invalid]);
+        assert_eq!(code, INVALID_QUERY);
+        assert!(err.contains("sum() requires a NUMBER column"));
+        assert!(err.contains("^^^^^^^^^^"));
+
+        let (code, out, _) = run_cli(&["check", &invalid, "--json"]);
+        assert_eq!(code, INVALID_QUERY);
+        let out: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(out["ok"], false);
+        assert_eq!(out["diagnostics"][0]["span"]["line"], 4);
+    }
+
+    #[test]
+    fn syntax_error() {
+        let query = write_tmp("syntax.pql", "SPLIT cam1 BEGIN;");
+        let (code, _, err) = run_cli(&["fmt", &query]);
+        assert_eq!(code, INVALID_QUERY);
+        assert!(err.contains("--> 1:"));
+    }
+
+    #[test]
+    fn sensitivity_json() {
+        let query = write_tmp("sensitivity.pql", QUERY);
+        let policies = write_tmp("policies.toml", POLICIES);
+        let (code, out, _) = run_cli(&["sensitivity", &query, "--policies", &policies, "--json"]);
+        assert_eq!(code, OK);
+        let out: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(
+            out["statements"][0]["columns"],
+            json!([
+                {
+                    "name": "count(plate)",
+                    "sensitivity": 70.0,
+                    "l2_sensitivity": 70.0,
+                    "epsilon": 0.25,
+                    "noise_scale": 280.0
+                },
+                {
+                    "name": "sum(speed,l=0,u=10)",
+                    "sensitivity": 700.0,
+                    "l2_sensitivity": 700.0,
+                    "epsilon": 0.25,
+                    "noise_scale": 2800.0
+                },
+            ])
+        );
+        assert_eq!(out["statements"][0]["cameras"], json!(["cam1"]));
+    }
+
+    #[test]
+    fn sensitivity_statements() {
+        let query = write_tmp(
+            "statements.pql",
+            &format!("{}SELECT count(speed) FROM table1;", QUERY),
+        );
+        let policies = write_tmp("policies.toml", POLICIES);
+        let (code, out, _) = run_cli(&["sensitivity", &query, "--policies", &policies, "--json"]);
+        assert_eq!(code, OK);
+        let out: serde_json::Value = serde_json::from_str(&out).unwrap();
+        // Both statements read cam1, so they share its epsilon
+        assert_eq!(out["statements"][0]["epsilon"], 0.25);
+        assert_eq!(out["statements"][1]["epsilon"], 0.25);
+        assert_eq!(out["cameras"], json!({ "cam1": 0.5 }));
+        // Both depend on the same version of the policy of cam1
+        let hash = out["statements"][0]["policy_hashes"]["cam1"].clone();
+        assert_eq!(hash.as_str().map(|h| h.len()), Some(16));
+        assert_eq!(out["statements"][1]["policy_hashes"]["cam1"], hash);
+
+        let (code, out, _) = run_cli(&["sensitivity", &query, "--policies", &policies]);
+        assert_eq!(code, OK);
+        assert!(
+            out.contains(&format!("    policy of cam1: {}\n", hash.as_str().unwrap())),
+            "{}",
+            out
+        );
+        assert!(
+            out.ends_with("camera cam1: epsilon 0.5 in total\n"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn accuracy() {
+        let query = write_tmp("accuracy.pql", QUERY);
+        let policies = write_tmp("policies.toml", POLICIES);
+        let (code, out, _) = run_cli(&[
+            "accuracy",
+            &query,
+            "--policies",
+            &policies,
+            "--epsilon",
+            "1",
+        ]);
+        assert_eq!(code, OK);
+        // Epsilon 1 is split between the two columns, so count(plate) has a noise scale of 140
+        assert!(
+            out.contains("    count(plate): 90%: +-322.362, 95%: +-419.403, 99%: +-644.724\n"),
+            "{}",
+            out
+        );
+
+        // Gaussian noise of sigma 140 (rho = 0.5^2 / 2 for each column)
+        let (code, out, _) = run_cli(&[
+            "accuracy",
+            &query,
+            "--policies",
+            &policies,
+            "--epsilon",
+            "1",
+            "--mechanism",
+            "gaussian",
+        ]);
+        assert_eq!(code, OK);
+        assert!(
+            out.contains("    count(plate): 90%: +-230.280, 95%: +-274.395, 99%: +-360.616\n"),
+            "{}",
+            out
+        );
+
+        let (code, _, err) = run_cli(&["accuracy", &query, "--epsilon", "0"]);
+        assert_eq!(code, ERROR);
+        assert!(err.contains("--epsilon requires a positive number"));
+        let (code, _, err) = run_cli(&[
+            "accuracy",
+            &query,
+            "--mechanism",
+            "laplace",
+            "--delta",
+            "1e-6",
+        ]);
+        assert_eq!(code, ERROR);
+        assert!(err.contains("the laplace mechanism has no delta"));
+    }
+
+    #[test]
+    fn plan() {
+        let query = write_tmp("plan.pql", QUERY);
+        let policies = write_tmp("policies.toml", POLICIES);
+        let (code, out, _) = run_cli(&[
+            "plan",
+            &query,
+            "--policies",
+            &policies,
+            "--chunk-lengths",
+            "5sec,30sec",
+            "--epsilons",
+            "0.5,1",
+            "--target",
+            "500",
+        ]);
+        assert_eq!(code, OK);
+        // sum(speed) has the largest noise scale: 10 * 10 * (1 + 30sec / 30sec) / (epsilon / 2)
+        assert_eq!(
+            out,
+            "  BY TIME 30sec, epsilon 0.5: noise scale 800, cost 0.5
+* BY TIME 30sec, epsilon 1: noise scale 400, cost 1
+the query as written needs epsilon 2.8 to reach a noise scale of 500
+"
+        );
+
+        let (code, _, err) = run_cli(&["plan", &query, "--policies", &policies, "--rows", "0"]);
+        assert_eq!(code, ERROR);
+        assert!(err.contains("--rows requires positive integers"));
+    }
+
+    #[test]
+    fn sensitivity_missing_policy() {
+        let query = write_tmp("missing.pql", &QUERY.replace("cam1", "cam9"));
+        let policies = write_tmp("policies.toml", POLICIES);
+        let (code, _, err) = run_cli(&["sensitivity", &query, "--policies", &policies]);
+        assert_eq!(code, INVALID_QUERY);
+        assert!(err.contains("no privacy policy for camera `cam9`"));
+    }
+}
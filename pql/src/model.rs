@@ -0,0 +1,394 @@
+/*
+ * Models run by `PROCESS ... USING <model>`. A model turns the input of a single chunk (its video
+ * frames, or rows extracted from it beforehand) into rows for the schema of the PROCESS statement.
+ * The ModelRegistry resolves the model name of each PROCESS statement, and process_tables runs
+ * every chunk of a query through its model (with the TIMEOUT and sanitizing of the ChunkRunner),
+ * producing the TableData evaluated by the executor.
+ *
+ * Chunks run concurrently, each under its own TIMEOUT from when it starts, with at most a fixed
+ * number of threads. A model that overruns its TIMEOUT keeps its thread (threads can't be killed)
+ * and so holds its place in the pool until it returns, delaying the chunks that haven't started.
+ */
+use crate::execute::{Row, TableData};
+use crate::parser::{PQLQuery, Span};
+use crate::process::{Column, ProcessStatement};
+use crate::runner::{ChunkRun, ChunkRunner};
+use crate::sanitize::ChunkOutputSanitizer;
+use crate::split::SplitStatement;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::panic;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+// What a model knows about the chunk it is processing
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChunkInfo {
+    pub camera: String,
+    // Index of the chunk within its split, starting at 0
+    pub chunk: u64,
+    // Time range covered by the chunk, in unix milliseconds
+    pub start_ms: u64,
+    pub end_ms: u64,
+    // TIMEOUT of the PROCESS statement, after which the output of the model is discarded
+    pub timeout_ms: u64,
+    // Schema the model's output must follow, without the fixed columns added by the executor
+    pub schema: Vec<Column>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkInput {
+    // Encoded video frames, in order
+    Frames(Vec<Vec<u8>>),
+    // Rows already extracted from the video, e.g. by an upstream detector
+    Rows(Vec<Row>),
+}
+
+pub trait ChunkModel: Send + Sync {
+    /// Produces the rows output by a single chunk. The output is untrusted: it is sanitized
+    /// against the schema, and replaced by the default rows if this fails or takes too long.
+    fn process(&self, info: &ChunkInfo, input: ChunkInput) -> Result<Vec<Row>, String>;
+}
+
+// Any function with the right signature can be used as a model, which is handy for tests
+impl<F> ChunkModel for F
+where
+    F: Fn(&ChunkInfo, ChunkInput) -> Result<Vec<Row>, String> + Send + Sync,
+{
+    fn process(&self, info: &ChunkInfo, input: ChunkInput) -> Result<Vec<Row>, String> {
+        self(info, input)
+    }
+}
+
+// Provides the input of each chunk, e.g. by reading it from a video store
+pub trait ChunkSource {
+    fn input(&self, info: &ChunkInfo) -> Result<ChunkInput, String>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModelError {
+    // No model registered under the name used by a PROCESS statement
+    UnknownModel {
+        model: String,
+        span: Span,
+    },
+    // The source couldn't provide the input of a chunk
+    Source {
+        camera: String,
+        chunk: u64,
+        message: String,
+    },
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModelError::UnknownModel { model, span } => write!(
+                f,
+                "unknown model `{}` (at {}:{})",
+                model, span.line, span.col
+            ),
+            ModelError::Source {
+                camera,
+                chunk,
+                message,
+            } => write!(
+                f,
+                "could not read chunk {} of camera '{}': {}",
+                chunk, camera, message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+#[derive(Default)]
+pub struct ModelRegistry {
+    models: HashMap<String, Arc<dyn ChunkModel>>,
+}
+
+impl ModelRegistry {
+    pub fn new() -> Self {
+        ModelRegistry {
+            models: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, name: &str, model: Arc<dyn ChunkModel>) {
+        self.models.insert(name.to_owned(), model);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn ChunkModel>> {
+        self.models.get(name).cloned()
+    }
+
+    /// Resolves the model used by a PROCESS statement
+    pub fn resolve(&self, ps: &ProcessStatement) -> Result<Arc<dyn ChunkModel>, ModelError> {
+        self.get(&ps.model_name)
+            .ok_or_else(|| ModelError::UnknownModel {
+                model: ps.model_name.clone(),
+                span: ps.span,
+            })
+    }
+}
+
+/// Metadata of every chunk created by a SPLIT statement, in order
+pub fn split_chunks(ss: &SplitStatement, ps: &ProcessStatement) -> Vec<ChunkInfo> {
+    let schema: Vec<Column> = ps
+        .schema
+        .iter()
+        .filter(|c| !matches!(c, Column::Fixed(_)))
+        .cloned()
+        .collect();
+    ss.chunks()
+        .map(|(chunk, start_ms, end_ms)| ChunkInfo {
+            camera: ss.camera_name.clone(),
+            chunk,
+            start_ms,
+            end_ms,
+            timeout_ms: ps.chunk_timeout_ms,
+            schema: schema.clone(),
+        })
+        .collect()
+}
+
+// Number of chunks run at once that are still running, including the models that overran their
+// TIMEOUT, so that at most `limit` run at once
+struct Pool {
+    running: Mutex<usize>,
+    freed: Condvar,
+    limit: usize,
+}
+
+// A place in the Pool, freed once it is dropped by both the thread holding the chunk until its
+// TIMEOUT and the thread running the model
+struct Place(Arc<Pool>);
+
+impl Pool {
+    fn new(limit: usize) -> Arc<Pool> {
+        Arc::new(Pool {
+            running: Mutex::new(0),
+            freed: Condvar::new(),
+            limit: limit.max(1),
+        })
+    }
+
+    // Waits for a free place
+    fn enter(self: &Arc<Self>) -> Arc<Place> {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.limit {
+            running = self.freed.wait(running).unwrap();
+        }
+        *running += 1;
+        Arc::new(Place(Arc::clone(self)))
+    }
+}
+
+impl Drop for Place {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Runs every chunk of every PROCESS statement in `query` through its model, with as many chunks
+/// at once as there are CPUs (see process_tables_with)
+pub fn process_tables(
+    query: &PQLQuery,
+    registry: &ModelRegistry,
+    source: &dyn ChunkSource,
+) -> Result<HashMap<String, Vec<ChunkRun>>, ModelError> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    process_tables_with(query, registry, source, threads)
+}
+
+/// Runs every chunk of every PROCESS statement in `query` through its model, returning how each
+/// chunk was processed, keyed by table name. At most `threads` chunks run at once, counting the
+/// models still running past their TIMEOUT. The input of a chunk is only read once it can start.
+pub fn process_tables_with(
+    query: &PQLQuery,
+    registry: &ModelRegistry,
+    source: &dyn ChunkSource,
+    threads: usize,
+) -> Result<HashMap<String, Vec<ChunkRun>>, ModelError> {
+    let pool = Pool::new(threads);
+    let runners: Vec<_> = query
+        .process_stmts
+        .iter()
+        .map(|(name, ps)| {
+            let runner = ChunkRunner::new(ChunkOutputSanitizer::new(ps));
+            Ok((name, ps, registry.resolve(ps)?, runner))
+        })
+        .collect::<Result<_, ModelError>>()?;
+    thread::scope(|scope| {
+        let mut handles = vec![];
+        for (name, ps, model, runner) in &runners {
+            // The parser already checked that every PROCESS reads an existing split
+            let ss = &query.split_stmts[&ps.input_name];
+            let mut runs = vec![];
+            for info in split_chunks(ss, ps) {
+                let place = pool.enter();
+                let input = source.input(&info).map_err(|message| ModelError::Source {
+                    camera: info.camera.clone(),
+                    chunk: info.chunk,
+                    message,
+                })?;
+                let model = Arc::clone(model);
+                let chunk = info.chunk;
+                let model_place = Arc::clone(&place);
+                runs.push(scope.spawn(move || {
+                    let run = runner.run(chunk, move || {
+                        let _place = model_place;
+                        model.process(&info, input)
+                    });
+                    drop(place);
+                    run
+                }));
+            }
+            handles.push((name.to_string(), runs));
+        }
+        Ok(handles
+            .into_iter()
+            .map(|(name, runs)| {
+                let runs = runs
+                    .into_iter()
+                    .map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                    .collect();
+                (name, runs)
+            })
+            .collect())
+    })
+}
+
+/// Collects the output of every chunk, ready to be evaluated by the executor
+pub fn table_data(tables: HashMap<String, Vec<ChunkRun>>) -> TableData {
+    tables
+        .into_iter()
+        .map(|(name, runs)| (name, runs.into_iter().map(|r| r.output).collect()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::execute::{Executor, Value};
+    use crate::model::*;
+    use crate::parser::build;
+    use crate::runner::ChunkOutcome;
+
+    use std::time::{Duration, Instant};
+
+    const QUERY: &str = "SPLIT cam1 BEGIN 0 END 90 BY TIME 30sec INTO chunks1;
+PROCESS chunks1 USING counter TIMEOUT 1sec PRODUCING 1 ROWS
+    WITH SCHEMA (people:NUMBER=0) INTO t1;
+SELECT sum(people,l=0,u=10) FROM t1;
+";
+
+    // Input of each chunk is the number of frames, which the stub model counts as people
+    struct Frames;
+    impl ChunkSource for Frames {
+        fn input(&self, info: &ChunkInfo) -> Result<ChunkInput, String> {
+            Ok(ChunkInput::Frames(vec![vec![]; info.chunk as usize + 1]))
+        }
+    }
+
+    fn counter(_: &ChunkInfo, input: ChunkInput) -> Result<Vec<Row>, String> {
+        match input {
+            ChunkInput::Frames(frames) => Ok(vec![vec![(
+                String::from("people"),
+                Value::Number(frames.len() as f64),
+            )]
+            .into_iter()
+            .collect()]),
+            ChunkInput::Rows(_) => Err(String::from("expected frames")),
+        }
+    }
+
+    #[test]
+    fn end_to_end() {
+        let query = build(QUERY).unwrap();
+        let mut registry = ModelRegistry::new();
+        registry.register("counter", Arc::new(counter));
+
+        let tables = process_tables(&query, &registry, &Frames).unwrap();
+        let runs = &tables["t1"];
+        assert_eq!(runs.len(), 3);
+        assert!(runs.iter().all(|r| r.outcome == ChunkOutcome::Completed));
+
+        let data = table_data(tables);
+        let result = Executor::new(&data)
+            .execute(&query.select_stmts[0])
+            .unwrap();
+        // 1 + 2 + 3 people
+        assert_eq!(result.rows[0]["sum(people,l=0,u=10)"], Value::Number(6.0));
+    }
+
+    #[test]
+    fn concurrent_chunks() {
+        // Four chunks with a TIMEOUT of 100ms, run two at a time
+        let query = build(
+            &QUERY
+                .replace("END 90", "END 120")
+                .replace("TIMEOUT 1sec", "TIMEOUT 100ms"),
+        )
+        .unwrap();
+        let running = Arc::new(Mutex::new((0, 0)));
+        let run = |sleep_ms: u64| {
+            let running = Arc::clone(&running);
+            let mut registry = ModelRegistry::new();
+            registry.register(
+                "counter",
+                Arc::new(move |info: &ChunkInfo, input: ChunkInput| {
+                    {
+                        let mut running = running.lock().unwrap();
+                        running.0 += 1;
+                        running.1 = running.1.max(running.0);
+                    }
+                    thread::sleep(Duration::from_millis(sleep_ms));
+                    running.lock().unwrap().0 -= 1;
+                    counter(info, input)
+                }),
+            );
+            let start = Instant::now();
+            let tables = process_tables_with(&query, &registry, &Frames, 2).unwrap();
+            (tables, start.elapsed())
+        };
+
+        // Each chunk is held until its TIMEOUT, so two rounds of 100ms
+        let (tables, elapsed) = run(10);
+        assert!(tables["t1"]
+            .iter()
+            .all(|r| r.outcome == ChunkOutcome::Completed));
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+        assert_eq!(running.lock().unwrap().1, 2);
+
+        // Models running past their TIMEOUT keep their place, so the last two chunks only start
+        // once the first two models return after 300ms
+        let (tables, elapsed) = run(300);
+        assert!(tables["t1"]
+            .iter()
+            .all(|r| r.outcome == ChunkOutcome::TimedOut));
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+        assert_eq!(running.lock().unwrap().1, 2);
+    }
+
+    #[test]
+    fn unknown_model() {
+        let query = build(QUERY).unwrap();
+        let err = process_tables(&query, &ModelRegistry::new(), &Frames).unwrap_err();
+        assert_eq!(err.to_string(), "unknown model `counter` (at 2:1)");
+    }
+
+    #[test]
+    fn chunk_times() {
+        let query = build(&QUERY.replace("END 90", "END 70")).unwrap();
+        let ps = &query.process_stmts["t1"];
+        let chunks = split_chunks(&query.split_stmts["chunks1"], ps);
+        let times: Vec<(u64, u64)> = chunks.iter().map(|c| (c.start_ms, c.end_ms)).collect();
+        assert_eq!(times, vec![(0, 30_000), (30_000, 60_000), (60_000, 70_000)]);
+        assert_eq!(chunks[0].schema.len(), 1);
+    }
+}
@@ -0,0 +1,806 @@
+/*
+ * Online queries, whose SPLITs END in the future. The output of each chunk is pushed as soon as it
+ * has been processed, and whenever a window of the query closes, every SELECT is evaluated over
+ * the chunks of that window only and released with noise, after charging the budget of its
+ * cameras to the ledger passed in. The ledger isn't owned by the query, so that every query
+ * (online or not) reading a camera spends the same budget. A window is released exactly as if it was a separate query whose SPLITs BEGIN and END
+ * at the window boundaries, so its sensitivity and cost are those of that query. Chunks that never
+ * arrived output the default rows, like chunks that timed out.
+ *
+ * The window is set by `WITH WINDOW` or when registering the query, and must be a multiple of the
+ * chunk length of every split so chunks never straddle two windows. Everything needed to carry on
+ * after a restart (the next window and the chunks not released yet) can be saved with `checkpoint`
+ * and restored with `resume`, which checks the checkpoint as thoroughly as `new` checks a query: it
+ * must still validate, and match the hash it was saved with. The hash only detects policy drift,
+ * checkpoints must be stored where they can't be tampered with. The ledger is saved by its owner.
+ *
+ * Policies can be updated between windows (`update_policy`), e.g. once `rho` has been
+ * re-estimated. Each WindowRelease records the version of every policy it used.
+ */
+use crate::accounting::{Mechanism, PrivacyCost};
+use crate::aggregation::AstNode;
+use crate::execute::{ChunkRows, ExecError, Executor, ResultTable, Row, TableData};
+use crate::parser::PQLQuery;
+use crate::policy::{
+    fnv1a, BudgetError, BudgetLedger, PolicyHistory, PolicyMap, PolicyVersion, PrivacyPolicy,
+};
+use crate::release::{release_query, ReleaseError};
+use crate::runner::default_rows;
+use crate::sensitivity::{query_costs, query_sensitivity};
+use crate::validate::{validate, Diagnostic};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnlineError {
+    // The query can't be run online, e.g. because its window doesn't line up with its chunks
+    Window(String),
+    // A window of the query doesn't pass validation
+    Invalid(Vec<Diagnostic>),
+    // A chunk was pushed for a table or chunk index that doesn't exist, or whose window was
+    // already released
+    Chunk(String),
+    Exec(ExecError),
+    Budget(BudgetError),
+    Release(ReleaseError),
+    Checkpoint(String),
+}
+
+impl fmt::Display for OnlineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OnlineError::Window(message)
+            | OnlineError::Chunk(message)
+            | OnlineError::Checkpoint(message) => write!(f, "{}", message),
+            OnlineError::Invalid(diags) => {
+                let messages: Vec<&str> = diags.iter().map(|d| d.message.as_str()).collect();
+                write!(f, "invalid query: {}", messages.join("; "))
+            }
+            OnlineError::Exec(e) => write!(f, "{}", e),
+            OnlineError::Budget(e) => write!(f, "{}", e),
+            OnlineError::Release(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for OnlineError {}
+
+impl From<ExecError> for OnlineError {
+    fn from(e: ExecError) -> Self {
+        OnlineError::Exec(e)
+    }
+}
+
+impl From<ReleaseError> for OnlineError {
+    fn from(e: ReleaseError) -> Self {
+        OnlineError::Release(e)
+    }
+}
+
+impl From<BudgetError> for OnlineError {
+    fn from(e: BudgetError) -> Self {
+        OnlineError::Budget(e)
+    }
+}
+
+// Noisy results released at the end of a window
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WindowRelease {
+    // Index of the window, starting at 0
+    pub window: u64,
+    // Time range covered by the window, in unix milliseconds
+    pub start_ms: u64,
+    pub end_ms: u64,
+    // Result of each SELECT, in order
+    pub results: Vec<ResultTable>,
+    // Cost charged to each camera for this window, one per SELECT reading it
+    pub charged: HashMap<String, Vec<PrivacyCost>>,
+    // Version of the policy of each camera the results depend on, sorted by camera
+    #[serde(default)]
+    pub policies: Vec<PolicyVersion>,
+}
+
+// Chunk outputs not released yet, by table name and then chunk index within its split
+type Pending = HashMap<String, BTreeMap<u64, Vec<Row>>>;
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    query: PQLQuery,
+    policies: PolicyMap,
+    history: PolicyHistory,
+    window_ms: u64,
+    mechanism: Mechanism,
+    next_window: u64,
+    pending: Pending,
+    // `content_hash` of the query and policies
+    hash: String,
+}
+
+// Hash of the query and the policies of its cameras. It isn't keyed, so anyone can recompute it:
+// it only detects a query or policies that drifted from those the checkpoint was saved with (e.g.
+// edited by hand), not deliberate tampering.
+fn content_hash(query: &PQLQuery, policies: &PolicyMap) -> String {
+    let mut cameras: Vec<(&String, &PrivacyPolicy)> = policies.iter().collect();
+    cameras.sort_by(|a, b| a.0.cmp(b.0));
+    let mut canonical = query.to_string();
+    for (camera, policy) in cameras {
+        canonical.push_str(&format!("\n{}={}", camera, policy.content_hash()));
+    }
+    format!("{:016x}", fnv1a(canonical.as_bytes()))
+}
+
+/// Whether `query` reads video past `now_ms` (in unix milliseconds), so it has to be run online
+pub fn is_online(query: &PQLQuery, now_ms: u64) -> bool {
+    query
+        .split_stmts
+        .values()
+        .any(|ss| ss.end_time * 1000 > now_ms)
+}
+
+pub struct OnlineQuery {
+    query: PQLQuery,
+    policies: PolicyMap,
+    history: PolicyHistory,
+    window_ms: u64,
+    mechanism: Mechanism,
+    next_window: u64,
+    pending: Pending,
+    rng: StdRng,
+}
+
+impl OnlineQuery {
+    /// Registers `query` for online execution. The window is taken from `window_ms` if given,
+    /// otherwise from the `WITH WINDOW` of its statements.
+    pub fn new(
+        query: PQLQuery,
+        policies: PolicyMap,
+        window_ms: Option<u64>,
+    ) -> Result<Self, OnlineError> {
+        let window_ms = match window_ms {
+            Some(w) => w,
+            None => query_window(&query)?,
+        };
+        let online = OnlineQuery {
+            query,
+            history: PolicyHistory::from_policies(&policies),
+            policies,
+            window_ms,
+            mechanism: Mechanism::default(),
+            next_window: 0,
+            pending: HashMap::new(),
+            rng: StdRng::from_entropy(),
+        };
+        online.check_window()?;
+        let diags = validate(&online.window_query(0), &online.policies);
+        if !diags.is_empty() {
+            return Err(OnlineError::Invalid(diags));
+        }
+        Ok(online)
+    }
+
+    /// Restores a query saved by `checkpoint`. The checkpoint is rejected if it doesn't match its
+    /// hash, its policies aren't the latest versions in its history or the query no longer
+    /// validates.
+    pub fn resume(checkpoint: &str) -> Result<Self, OnlineError> {
+        let c: Checkpoint = serde_json::from_str(checkpoint)
+            .map_err(|e| OnlineError::Checkpoint(format!("invalid checkpoint: {}", e)))?;
+        if c.hash != content_hash(&c.query, &c.policies) {
+            return Err(OnlineError::Checkpoint(String::from(
+                "checkpoint query or policies don't match its hash",
+            )));
+        }
+        for (camera, policy) in c.policies.iter() {
+            if c.history.current(camera).map(|v| v.hash) != Some(policy.content_hash()) {
+                return Err(OnlineError::Checkpoint(format!(
+                    "checkpoint policy of camera '{}' isn't the latest in its history",
+                    camera
+                )));
+            }
+        }
+        let online = OnlineQuery {
+            query: c.query,
+            history: c.history,
+            policies: c.policies,
+            window_ms: c.window_ms,
+            mechanism: c.mechanism,
+            next_window: c.next_window,
+            pending: c.pending,
+            rng: StdRng::from_entropy(),
+        };
+        online.check_window()?;
+        let diags = validate(&online.window_query(online.next_window), &online.policies);
+        if !diags.is_empty() {
+            return Err(OnlineError::Invalid(diags));
+        }
+        Ok(online)
+    }
+
+    /// Saves the state of the query, as JSON. The random number generator is not saved, so a
+    /// resumed query draws fresh noise.
+    pub fn checkpoint(&self) -> Result<String, OnlineError> {
+        let c = Checkpoint {
+            query: self.query.clone(),
+            policies: self.policies.clone(),
+            history: self.history.clone(),
+            window_ms: self.window_ms,
+            mechanism: self.mechanism,
+            next_window: self.next_window,
+            pending: self.pending.clone(),
+            hash: content_hash(&self.query, &self.policies),
+        };
+        serde_json::to_string(&c).map_err(|e| OnlineError::Checkpoint(e.to_string()))
+    }
+
+    /// Makes the noise reproducible, for tests
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Noise added to the released values, Laplace by default. Use Gaussian noise together with
+    /// zCDP accounting in the ledger to afford many more windows.
+    pub fn set_mechanism(&mut self, mechanism: Mechanism) {
+        self.mechanism = mechanism;
+    }
+
+    /// Every version of the policies used so far
+    pub fn history(&self) -> &PolicyHistory {
+        &self.history
+    }
+
+    /// Replaces the policy of `camera` for the windows not released yet, if they are still valid
+    /// with it. Returns the version of the policy now in use.
+    pub fn update_policy(
+        &mut self,
+        camera: &str,
+        policy: PrivacyPolicy,
+    ) -> Result<PolicyVersion, OnlineError> {
+        let mut policies = self.policies.clone();
+        policies.insert(camera.to_owned(), policy.clone());
+        let diags = validate(&self.window_query(self.next_window), &policies);
+        if !diags.is_empty() {
+            return Err(OnlineError::Invalid(diags));
+        }
+        self.policies = policies;
+        Ok(self.history.update(camera, policy))
+    }
+
+    pub fn window_ms(&self) -> u64 {
+        self.window_ms
+    }
+
+    /// Index of the next window to be released
+    pub fn next_window(&self) -> u64 {
+        self.next_window
+    }
+
+    /// Number of windows until the END of the query, including a last partial window
+    pub fn num_windows(&self) -> u64 {
+        let (begin, end) = self.bounds();
+        ((end - begin) * 1000).div_ceil(self.window_ms)
+    }
+
+    /// `(start_ms, end_ms)` of `window`, in unix milliseconds. The last window is cut short by END.
+    pub fn window_range(&self, window: u64) -> (u64, u64) {
+        let (begin, end) = self.bounds();
+        let start_ms = begin * 1000 + window * self.window_ms;
+        (
+            start_ms,
+            std::cmp::min(start_ms + self.window_ms, end * 1000),
+        )
+    }
+
+    /// Stores the output of a chunk of `table`, as produced by the ChunkRunner
+    pub fn push(&mut self, table: &str, chunk: ChunkRows) -> Result<(), OnlineError> {
+        let ps = self
+            .query
+            .process_stmts
+            .get(table)
+            .ok_or_else(|| OnlineError::Chunk(format!("unknown table `{}`", table)))?;
+        let ss = &self.query.split_stmts[&ps.input_name];
+        if chunk.chunk >= ss.num_chunks() {
+            return Err(OnlineError::Chunk(format!(
+                "table `{}` has no chunk {}",
+                table, chunk.chunk
+            )));
+        }
+        let window = chunk.chunk * ss.chunk_length_ms / self.window_ms;
+        if window < self.next_window {
+            return Err(OnlineError::Chunk(format!(
+                "chunk {} of table `{}` arrived after its window was released",
+                chunk.chunk, table
+            )));
+        }
+        self.pending
+            .entry(table.to_owned())
+            .or_default()
+            .insert(chunk.chunk, chunk.rows);
+        Ok(())
+    }
+
+    /// Releases the next window if it closed before `now_ms`, charging its cost to `ledger`. Call
+    /// it until it returns None to release every closed window. If the budget of a camera runs
+    /// out, nothing is released and the window stays pending.
+    pub fn next_release(
+        &mut self,
+        now_ms: u64,
+        ledger: &mut BudgetLedger,
+    ) -> Result<Option<WindowRelease>, OnlineError> {
+        let window = self.next_window;
+        let (start_ms, end_ms) = self.window_range(window);
+        if window >= self.num_windows() || end_ms > now_ms {
+            return Ok(None);
+        }
+
+        let query = self.window_query(window);
+        let data = self.window_data(&query, window);
+        let results = Executor::new(&data).execute_query(&query)?;
+        let sens = query_sensitivity(&query, &self.policies);
+
+        // Nothing is charged if the results can't be released
+        let results = release_query(results, &sens, self.mechanism, &mut self.rng)?;
+        let charged = query_costs(&sens, &self.policies, self.mechanism);
+        ledger.charge_costs(&charged)?;
+        let mut policies: Vec<PolicyVersion> = sens
+            .iter()
+            .flat_map(|s| &s.policy_hashes)
+            .filter_map(|(camera, hash)| self.history.find(camera, hash))
+            .collect();
+        policies.sort_by(|a, b| a.camera.cmp(&b.camera));
+        policies.dedup();
+        for (name, ps) in &self.query.process_stmts {
+            let ss = &self.query.split_stmts[&ps.input_name];
+            let (_, last) = window_chunks(window, self.window_ms, ss.chunk_length_ms);
+            // Only keep the chunks of later windows
+            if let Some(chunks) = self.pending.get_mut(name) {
+                *chunks = chunks.split_off(&last);
+            }
+        }
+        self.next_window += 1;
+
+        Ok(Some(WindowRelease {
+            window,
+            start_ms,
+            end_ms,
+            results,
+            charged,
+            policies,
+        }))
+    }
+
+    // BEGIN and END shared by every split, in seconds
+    fn bounds(&self) -> (u64, u64) {
+        let begin = self
+            .query
+            .split_stmts
+            .values()
+            .map(|ss| ss.start_time)
+            .min()
+            .unwrap_or(0);
+        let end = self
+            .query
+            .split_stmts
+            .values()
+            .map(|ss| ss.end_time)
+            .max()
+            .unwrap_or(begin);
+        (begin, end)
+    }
+
+    fn check_window(&self) -> Result<(), OnlineError> {
+        if self.window_ms == 0 || self.window_ms % 1000 != 0 {
+            return Err(OnlineError::Window(String::from(
+                "the window of an online query must be a whole number of seconds",
+            )));
+        }
+        let (begin, end) = self.bounds();
+        let mut splits: Vec<_> = self.query.split_stmts.values().collect();
+        splits.sort_by(|a, b| a.output_name.cmp(&b.output_name));
+        for ss in splits {
+            if ss.start_time != begin || ss.end_time != end {
+                return Err(OnlineError::Window(String::from(
+                    "every split of an online query must have the same BEGIN and END",
+                )));
+            }
+            if ss.chunk_length_ms == 0 || self.window_ms % ss.chunk_length_ms != 0 {
+                return Err(OnlineError::Window(format!(
+                    "the window must be a multiple of the chunk length of `{}`",
+                    ss.output_name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // The query restricted to `window`: every split BEGINs and ENDs at the window boundaries
+    fn window_query(&self, window: u64) -> PQLQuery {
+        let (start_ms, end_ms) = self.window_range(window);
+        let (start, end) = (start_ms / 1000, end_ms / 1000);
+        let mut query = self.query.clone();
+        for ss in query.split_stmts.values_mut() {
+            ss.start_time = start;
+            ss.end_time = end;
+        }
+        for stmt in query.select_stmts.iter_mut() {
+            restrict_node(stmt, start, end);
+        }
+        query
+    }
+
+    // Output of the chunks of `window`, indexed within the splits of `query`
+    fn window_data(&self, query: &PQLQuery, window: u64) -> TableData {
+        let mut data = TableData::new();
+        for (name, ps) in &query.process_stmts {
+            let ss = &query.split_stmts[&ps.input_name];
+            let (first, _) = window_chunks(window, self.window_ms, ss.chunk_length_ms);
+            let pending = self.pending.get(name);
+            let chunks = (0..ss.num_chunks())
+                .map(|i| ChunkRows {
+                    chunk: i,
+                    rows: pending
+                        .and_then(|p| p.get(&(first + i)))
+                        .cloned()
+                        .unwrap_or_else(|| default_rows(ps)),
+                })
+                .collect();
+            data.insert(name.clone(), chunks);
+        }
+        data
+    }
+}
+
+// Range of chunk indices (within the original split) covered by `window`
+fn window_chunks(window: u64, window_ms: u64, chunk_length_ms: u64) -> (u64, u64) {
+    let per_window = window_ms / chunk_length_ms;
+    (window * per_window, (window + 1) * per_window)
+}
+
+// WINDOW shared by the statements of `query`
+fn query_window(query: &PQLQuery) -> Result<u64, OnlineError> {
+    let mut windows: Vec<u64> = query
+        .select_stmts
+        .iter()
+        .flat_map(|stmt| match stmt {
+            AstNode::Select { qualifiers, .. } => qualifiers
+                .iter()
+                .filter_map(|q| match q {
+                    AstNode::DurationMs(ms, _) => Some(*ms),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        })
+        .collect();
+    windows.sort_unstable();
+    windows.dedup();
+    match windows[..] {
+        [window] => Ok(window),
+        [] => Err(OnlineError::Window(String::from(
+            "an online query needs a window, either WITH WINDOW or when it is registered",
+        ))),
+        _ => Err(OnlineError::Window(String::from(
+            "every statement of an online query must use the same WINDOW",
+        ))),
+    }
+}
+
+// Moves the tables read by `node` to the window, and drops its WINDOW, which is now implicit
+fn restrict_node(node: &mut AstNode, start: u64, end: u64) {
+    match node {
+        AstNode::Table(ss, _) => {
+            ss.start_time = start;
+            ss.end_time = end;
+        }
+        AstNode::Tables(tables, _)
+        | AstNode::Intersect { tables, .. }
+        | AstNode::Union { tables, .. }
+        | AstNode::Equijoin { tables, .. } => {
+            for t in tables.iter_mut() {
+                restrict_node(t, start, end);
+            }
+        }
+        AstNode::Select {
+            from, qualifiers, ..
+        } => {
+            restrict_node(from, start, end);
+            qualifiers.retain(|q| !matches!(q, AstNode::DurationMs(..)));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounting::Accounting;
+    use crate::execute::Value;
+    use crate::online::*;
+    use crate::parser::build;
+    use crate::policy::{PrivacyBudget, PrivacyPolicy};
+
+    // Ends an hour in, with 20min windows of 10min chunks
+    const QUERY: &str = "SPLIT cam1 BEGIN 0 END 3600 BY TIME 10min INTO chunks1;
+PROCESS chunks1 USING counter TIMEOUT 1sec PRODUCING 1 ROWS
+    WITH SCHEMA (people:NUMBER=0) INTO t1;
+SELECT sum(people,l=0,u=10) FROM t1 WITH WINDOW 20min;
+";
+
+    fn policies() -> PolicyMap {
+        vec![(
+            String::from("cam1"),
+            PrivacyPolicy::Static {
+                k_segments: 1,
+                rho_ms: 60_000,
+                epsilon: 1.0,
+            },
+        )]
+        .into_iter()
+        .collect()
+    }
+
+    fn ledger(budget: f64) -> BudgetLedger {
+        let mut ledger = BudgetLedger::new();
+        ledger.insert("cam1", PrivacyBudget::new(budget));
+        ledger
+    }
+
+    fn chunk(chunk: u64, people: f64) -> ChunkRows {
+        ChunkRows {
+            chunk,
+            rows: vec![vec![(String::from("people"), Value::Number(people))]
+                .into_iter()
+                .collect()],
+        }
+    }
+
+    fn sum(release: &WindowRelease) -> f64 {
+        release.results[0].rows[0]["sum(people,l=0,u=10)"]
+            .as_number()
+            .unwrap()
+    }
+
+    #[test]
+    fn release_windows() {
+        let query = build(QUERY).unwrap();
+        let mut ledger = ledger(10.0);
+        let mut online = OnlineQuery::new(query, policies(), None).unwrap();
+        online.set_seed(0);
+        assert_eq!(online.window_ms(), 1_200_000);
+        assert_eq!(online.num_windows(), 3);
+
+        online.push("t1", chunk(0, 4.0)).unwrap();
+        assert_eq!(online.next_release(1_199_999, &mut ledger).unwrap(), None);
+        // Chunk 1 never arrived, so it outputs the default rows
+        online.push("t1", chunk(2, 7.0)).unwrap();
+        let first = online
+            .next_release(1_200_000, &mut ledger)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (first.window, first.start_ms, first.end_ms),
+            (0, 0, 1_200_000)
+        );
+        assert_eq!(
+            first.charged["cam1"],
+            vec![PrivacyCost::Pure { epsilon: 1.0 }]
+        );
+        assert_eq!(ledger.remaining("cam1"), Some(9.0));
+        // Laplace noise with scale 10 (sensitivity of one 10min chunk) around 4
+        assert_ne!(sum(&first), 4.0);
+        assert!((sum(&first) - 4.0).abs() < 200.0);
+        assert_eq!(online.next_release(1_200_000, &mut ledger).unwrap(), None);
+
+        assert!(matches!(
+            online.push("t1", chunk(1, 1.0)),
+            Err(OnlineError::Chunk(_))
+        ));
+        assert!(matches!(
+            online.push("t2", chunk(3, 1.0)),
+            Err(OnlineError::Chunk(_))
+        ));
+
+        let second = online.next_release(u64::MAX, &mut ledger).unwrap().unwrap();
+        assert_eq!(second.window, 1);
+        assert_eq!(
+            online
+                .next_release(u64::MAX, &mut ledger)
+                .unwrap()
+                .unwrap()
+                .window,
+            2
+        );
+        assert_eq!(online.next_release(u64::MAX, &mut ledger).unwrap(), None);
+        assert_eq!(ledger.remaining("cam1"), Some(7.0));
+    }
+
+    #[test]
+    fn budget_exhausted() {
+        let query = build(QUERY).unwrap();
+        let mut ledger = ledger(1.5);
+        let mut online = OnlineQuery::new(query, policies(), None).unwrap();
+        assert!(online
+            .next_release(u64::MAX, &mut ledger)
+            .unwrap()
+            .is_some());
+        assert!(matches!(
+            online.next_release(u64::MAX, &mut ledger),
+            Err(OnlineError::Budget(BudgetError::Exhausted { .. }))
+        ));
+        // The window is not skipped
+        assert_eq!(online.next_window(), 1);
+    }
+
+    #[test]
+    fn gaussian_zcdp() {
+        // A day of 20min windows, each costing 0.1
+        let query = build(&QUERY.replace("END 3600", "END 86400")).unwrap();
+        let mut policies = policies();
+        policies.insert(
+            String::from("cam1"),
+            PrivacyPolicy::Static {
+                k_segments: 1,
+                rho_ms: 60_000,
+                epsilon: 0.1,
+            },
+        );
+        let mut ledger = ledger(5.0);
+        ledger.set_accounting(Accounting::Zcdp { delta: 1e-6 });
+        let mut online = OnlineQuery::new(query, policies, None).unwrap();
+        online.set_mechanism(Mechanism::Gaussian);
+
+        // Pure composition would run out after 50 windows
+        assert_eq!(online.num_windows(), 72);
+        while let Some(release) = online.next_release(u64::MAX, &mut ledger).unwrap() {
+            assert_eq!(release.charged["cam1"], vec![Mechanism::Gaussian.cost(0.1)]);
+        }
+        assert_eq!(online.next_window(), 72);
+        assert!(ledger.remaining("cam1").unwrap() > 0.0);
+    }
+
+    #[test]
+    fn shared_ledger() {
+        // Two queries over the same camera spend the same budget
+        let mut ledger = ledger(1.5);
+        let mut first = OnlineQuery::new(build(QUERY).unwrap(), policies(), None).unwrap();
+        let mut second = OnlineQuery::new(build(QUERY).unwrap(), policies(), None).unwrap();
+        assert!(first.next_release(u64::MAX, &mut ledger).unwrap().is_some());
+        assert!(matches!(
+            second.next_release(u64::MAX, &mut ledger),
+            Err(OnlineError::Budget(BudgetError::Exhausted { .. }))
+        ));
+        assert_eq!(ledger.remaining("cam1"), Some(0.5));
+    }
+
+    #[test]
+    fn checkpoint_resume() {
+        let query = build(QUERY).unwrap();
+        let mut ledger = ledger(10.0);
+        let mut online = OnlineQuery::new(query, policies(), None).unwrap();
+        online.push("t1", chunk(0, 4.0)).unwrap();
+        online
+            .next_release(1_200_000, &mut ledger)
+            .unwrap()
+            .unwrap();
+        online.push("t1", chunk(3, 5.0)).unwrap();
+
+        let mut resumed = OnlineQuery::resume(&online.checkpoint().unwrap()).unwrap();
+        assert_eq!(resumed.next_window(), 1);
+        assert_eq!(resumed.pending, online.pending);
+        assert_eq!(
+            resumed
+                .next_release(2_400_000, &mut ledger)
+                .unwrap()
+                .unwrap()
+                .window,
+            1
+        );
+
+        assert!(matches!(
+            OnlineQuery::resume("{}"),
+            Err(OnlineError::Checkpoint(_))
+        ));
+    }
+
+    #[test]
+    fn resume_tampered() {
+        let query = build(QUERY).unwrap();
+        let online = OnlineQuery::new(query, policies(), None).unwrap();
+        let checkpoint: serde_json::Value =
+            serde_json::from_str(&online.checkpoint().unwrap()).unwrap();
+        let resume = |edit: &dyn Fn(&mut serde_json::Value)| {
+            let mut c = checkpoint.clone();
+            edit(&mut c);
+            OnlineQuery::resume(&c.to_string())
+        };
+        assert!(resume(&|_| ()).is_ok());
+
+        // A larger epsilon in the policy, without updating the history or the hash
+        let err = resume(&|c| c["policies"]["cam1"]["static"]["epsilon"] = 100.0.into());
+        assert_eq!(
+            err.err().unwrap().to_string(),
+            "checkpoint query or policies don't match its hash"
+        );
+        // The hash isn't keyed, so it can be recomputed, but the policy still has to be the
+        // latest in the history
+        let mut edited = policies();
+        edited.insert(String::from("cam1"), edited["cam1"].with_epsilon(100.0));
+        let hash = content_hash(&build(QUERY).unwrap(), &edited);
+        let err = resume(&|c| {
+            c["hash"] = hash.clone().into();
+            c["policies"]["cam1"]["static"]["epsilon"] = 100.0.into();
+        });
+        assert_eq!(
+            err.err().unwrap().to_string(),
+            "checkpoint policy of camera 'cam1' isn't the latest in its history"
+        );
+
+        // Dropping the policies of the cameras makes the query invalid
+        let hash = content_hash(&build(QUERY).unwrap(), &PolicyMap::new());
+        let err = resume(&|c| {
+            c["hash"] = hash.clone().into();
+            c["policies"] = serde_json::json!({});
+        });
+        assert!(matches!(err, Err(OnlineError::Invalid(_))));
+    }
+
+    #[test]
+    fn policy_update() {
+        let query = build(QUERY).unwrap();
+        let mut ledger = ledger(10.0);
+        let mut online = OnlineQuery::new(query, policies(), None).unwrap();
+        let first = online.next_release(u64::MAX, &mut ledger).unwrap().unwrap();
+        assert_eq!(
+            first.policies,
+            vec![online.history().current("cam1").unwrap()]
+        );
+
+        // rho was re-estimated after the first window
+        let longer = PrivacyPolicy::Static {
+            k_segments: 1,
+            rho_ms: 600_000,
+            epsilon: 1.0,
+        };
+        let version = online.update_policy("cam1", longer).unwrap();
+        assert_eq!(version.version, 2);
+        let second = online.next_release(u64::MAX, &mut ledger).unwrap().unwrap();
+        assert_eq!(second.policies, vec![version]);
+        // The first release can still be traced back to the policy it used
+        assert_eq!(online.history().get("cam1", 1), Some(&policies()["cam1"]));
+        assert_eq!(first.policies[0].hash, policies()["cam1"].content_hash());
+
+        let resumed = OnlineQuery::resume(&online.checkpoint().unwrap()).unwrap();
+        assert_eq!(resumed.history(), online.history());
+        assert!(matches!(
+            online.update_policy("cam1", PrivacyPolicy::Mask {}),
+            Err(OnlineError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_window() {
+        let no_window = QUERY.replace(" WITH WINDOW 20min", "");
+        let err = OnlineQuery::new(build(&no_window).unwrap(), policies(), None);
+        assert!(matches!(err, Err(OnlineError::Window(_))));
+
+        // Windows must line up with the 10min chunks
+        let err = OnlineQuery::new(build(&no_window).unwrap(), policies(), Some(900_000));
+        assert!(matches!(err, Err(OnlineError::Window(_))));
+
+        let online =
+            OnlineQuery::new(build(&no_window).unwrap(), policies(), Some(1_800_000)).unwrap();
+        assert_eq!(online.num_windows(), 2);
+
+        let err = OnlineQuery::new(build(QUERY).unwrap(), PolicyMap::new(), None);
+        assert!(matches!(err, Err(OnlineError::Invalid(_))));
+    }
+
+    #[test]
+    fn online_query() {
+        let query = build(QUERY).unwrap();
+        assert!(is_online(&query, 3_599_999));
+        assert!(!is_online(&query, 3_600_000));
+    }
+}
@@ -1,4 +1,4 @@
-use pest::error::Error;
+use pest::error::{Error, ErrorVariant};
 use pest::Parser;
 
 use std::collections::HashMap;
@@ -11,14 +11,59 @@ use crate::split::{parse_split_stmt, SplitStatement};
 #[grammar = "pql.pest"]
 pub struct PQLParser;
 
-#[derive(Debug)]
+// Location of a statement or AstNode in the original query string, used to point at the
+// offending text when reporting errors
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    // Byte offsets into the query string
+    pub start: usize,
+    pub end: usize,
+    // 1-indexed line and column of `start`
+    pub line: usize,
+    pub col: usize,
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span) -> Self {
+        let (line, col) = span.start_pos().line_col();
+        Span {
+            start: span.start(),
+            end: span.end(),
+            line,
+            col,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PQLQuery {
     pub split_stmts: HashMap<String, SplitStatement>,
     pub process_stmts: HashMap<String, ProcessStatement>,
     pub select_stmts: Vec<AstNode>,
 }
 
-// TODO use this for parse_duration as well
+impl PQLQuery {
+    // Copy of the query with every span reset, see AstNode::without_spans
+    #[cfg(test)]
+    pub(crate) fn without_spans(&self) -> PQLQuery {
+        let mut query = self.clone();
+        for ss in query.split_stmts.values_mut() {
+            ss.span = Span::default();
+        }
+        for ps in query.process_stmts.values_mut() {
+            ps.span = Span::default();
+        }
+        query.select_stmts = query
+            .select_stmts
+            .iter()
+            .map(|s| s.without_spans())
+            .collect();
+        query
+    }
+}
+
 pub fn unit_to_ms(unit: &str) -> f64 {
     match unit {
         "usec" => 1.0 / 1_000.0,
@@ -29,6 +74,7 @@ pub fn unit_to_ms(unit: &str) -> f64 {
         "day" => 1_000.0 * 60.0 * 60.0 * 24.0,
         "week" => 1_000.0 * 60.0 * 60.0 * 24.0 * 7.0,
         "month" => 1_000.0 * 60.0 * 60.0 * 24.0 * 7.0 * 30.0,
+        "year" => 1_000.0 * 60.0 * 60.0 * 24.0 * 365.0,
         _ => unimplemented!("unknown duration unit"),
     }
 }
@@ -41,17 +87,7 @@ pub fn parse_duration_to_ms(duration: &mut pest::iterators::Pairs<Rule>) -> u64
         .to_owned()
         .parse::<f64>()
         .unwrap();
-    let ms = match duration.next().unwrap().as_str() {
-        "usec" => value / 1_000.0,
-        "ms" => value,
-        "sec" => value * 1_000.0,
-        "min" => value * 1_000.0 * 60.0,
-        "hr" => value * 1_000.0 * 60.0 * 60.0,
-        "day" => value * 1_000.0 * 60.0 * 60.0 * 24.0,
-        "week" => value * 1_000.0 * 60.0 * 60.0 * 24.0 * 7.0,
-        "month" => value * 1_000.0 * 60.0 * 60.0 * 24.0 * 7.0 * 30.0,
-        _ => unimplemented!("unknown duration unit"),
-    } as u64;
+    let ms = (value * unit_to_ms(duration.next().unwrap().as_str())) as u64;
     assert!(
         duration.next().is_none(),
         "parse error: duration expected 2 pairs (value and unit), but got more"
@@ -59,24 +95,64 @@ pub fn parse_duration_to_ms(duration: &mut pest::iterators::Pairs<Rule>) -> u64
     ms
 }
 
+// Parses a standalone duration in the same format used in queries (e.g. `60sec`, `1.5 hrs`)
+pub fn parse_duration_str(duration: &str) -> Result<u64, Error<Rule>> {
+    let duration = duration.trim();
+    let pair = PQLParser::parse(Rule::duration, duration)?.next().unwrap();
+    if pair.as_str().len() != duration.len() {
+        return Err(Error::new_from_pos(
+            ErrorVariant::CustomError {
+                message: String::from("unexpected text after duration"),
+            },
+            pest::Position::new(duration, pair.as_str().len()).unwrap(),
+        ));
+    }
+    Ok(parse_duration_to_ms(&mut pair.into_inner()))
+}
+
+// Error pointing at `span` in the query string
+fn custom_error(query_str: &str, span: Span, message: String) -> Error<Rule> {
+    Error::new_from_span(
+        ErrorVariant::CustomError { message },
+        pest::Span::new(query_str, span.start, span.end).unwrap(),
+    )
+}
+
 fn replace_tables(
+    query_str: &str,
     split_stmts: &HashMap<String, SplitStatement>,
     process_stmts: &HashMap<String, ProcessStatement>,
     node: AstNode,
-) -> AstNode {
-    match node {
-        AstNode::TableNames(ts) => AstNode::Tables(
+) -> Result<AstNode, Error<Rule>> {
+    let replace_all = |tables: Vec<AstNode>| -> Result<Vec<AstNode>, Error<Rule>> {
+        tables
+            .into_iter()
+            .map(|t| replace_tables(query_str, split_stmts, process_stmts, t))
+            .collect()
+    };
+    Ok(match node {
+        AstNode::TableNames(ts, span) => AstNode::Tables(
             ts.iter()
                 .map(|t| {
                     let mut ps = process_stmts
                         .get(t)
-                        .unwrap_or_else(|| panic!("unknown table used in aggregation: {}", t))
+                        .ok_or_else(|| {
+                            custom_error(
+                                query_str,
+                                span,
+                                format!("unknown table used in aggregation: {}", t),
+                            )
+                        })?
                         .clone();
                     let ss = split_stmts
                         .get(&ps.input_name)
-                        .unwrap_or_else(|| {
-                            panic!("unknown chunks used in PROCESS: {}", ps.input_name)
-                        })
+                        .ok_or_else(|| {
+                            custom_error(
+                                query_str,
+                                ps.span,
+                                format!("unknown chunks used in PROCESS: {}", ps.input_name),
+                            )
+                        })?
                         .clone();
                     // manually add fixed columns here
                     // TODO also add region here
@@ -85,46 +161,48 @@ fn replace_tables(
                         name: String::from("chunk"),
                         range: (ss.start_time, ss.end_time),
                     }));
-                    AstNode::Table(ss, ps)
+                    Ok(AstNode::Table(ss, ps))
                 })
-                .collect(),
+                .collect::<Result<_, _>>()?,
+            span,
         ),
         AstNode::Select {
             exprs,
             from,
             qualifiers,
+            span,
         } => AstNode::Select {
             exprs,
-            from: Box::new(replace_tables(split_stmts, process_stmts, *from)),
+            from: Box::new(replace_tables(
+                query_str,
+                split_stmts,
+                process_stmts,
+                *from,
+            )?),
             qualifiers,
+            span,
         },
-        AstNode::Intersect { tables, on } => AstNode::Intersect {
-            tables: tables
-                .iter()
-                .map(|t| replace_tables(split_stmts, process_stmts, t.to_owned()))
-                .collect(),
+        AstNode::Intersect { tables, on, span } => AstNode::Intersect {
+            tables: replace_all(tables)?,
             on,
+            span,
         },
-        AstNode::Union { tables, on } => AstNode::Union {
-            tables: tables
-                .iter()
-                .map(|t| replace_tables(split_stmts, process_stmts, t.to_owned()))
-                .collect(),
+        AstNode::Union { tables, on, span } => AstNode::Union {
+            tables: replace_all(tables)?,
             on,
+            span,
         },
-        AstNode::Equijoin { tables, on } => AstNode::Equijoin {
-            tables: tables
-                .iter()
-                .map(|t| replace_tables(split_stmts, process_stmts, t.to_owned()))
-                .collect(),
+        AstNode::Equijoin { tables, on, span } => AstNode::Equijoin {
+            tables: replace_all(tables)?,
             on,
+            span,
         },
         _ => node,
-    }
+    })
 }
 
 pub fn build(query_str: &str) -> Result<PQLQuery, Error<Rule>> {
-    let mut pairs = PQLParser::parse(Rule::query, query_str).unwrap_or_else(|e| panic!("{}", e));
+    let mut pairs = PQLParser::parse(Rule::query, query_str)?;
 
     // Splits
     let mut split_stmts: HashMap<String, SplitStatement> = HashMap::new();
@@ -140,11 +218,11 @@ pub fn build(query_str: &str) -> Result<PQLQuery, Error<Rule>> {
                 process_stmts.insert(stmt.output_table_name.clone(), stmt);
             }
             _ => {
-                unreachable!(format!(
+                unreachable!(
                     "query must begin with SPLITs or PROCESSs, got: {:?} = {:#?}",
                     pair.as_rule(),
                     pair
-                ));
+                );
             }
         }
     }
@@ -153,7 +231,12 @@ pub fn build(query_str: &str) -> Result<PQLQuery, Error<Rule>> {
     let mut select_stmts = vec![];
     for pair in pairs.next().unwrap().into_inner() {
         let stmt = build_agg_ast(pair);
-        select_stmts.push(replace_tables(&split_stmts, &process_stmts, stmt));
+        select_stmts.push(replace_tables(
+            query_str,
+            &split_stmts,
+            &process_stmts,
+            stmt,
+        )?);
     }
 
     Ok(PQLQuery {
@@ -213,22 +296,72 @@ mod tests {
         crate::parser::build(query_string).unwrap();
     }
 
-    //#[test]
-    //#[should_panic(expected = "unknown table used in aggregation: t2")]
-    //fn simple_query_missing_table() {
-    //    let query_string = "CREATE t1 FROM cam1
-    //        START AT 0
-    //        END AT 10
-    //        PARTITION BY TIME(10.5secs, 0secs)
-    //        PROCESS USING yolov3
-    //        FOR AT MOST 1sec
-    //        PRODUCE [(plate,STRING,null),(speed,NUMBER,0.743)]
-    //        LIMIT 1
-
-    //        THEN
-    //
-    //        SELECT count(plate) FROM t2";
-
-    //    crate::parser::parse(query_string).unwrap();
-    //}
+    #[test]
+    fn spans() {
+        let query_string = "SPLIT cam1 BEGIN 0 END 0 BY TIME 5sec INTO chunks1;
+            PROCESS chunks1 USING yolov3 TIMEOUT 1sec
+            PRODUCING 10 ROWS
+            WITH SCHEMA (plate:STRING=null)
+            INTO table1;
+        SELECT count(plate) FROM table1;";
+
+        let query = crate::parser::build(query_string).unwrap();
+        let split = query.split_stmts.get("chunks1").unwrap().span;
+        assert_eq!((split.line, split.col), (1, 1));
+        assert_eq!(
+            &query_string[split.start..split.end],
+            "SPLIT cam1 BEGIN 0 END 0 BY TIME 5sec INTO chunks1"
+        );
+        let process = query.process_stmts.get("table1").unwrap().span;
+        assert_eq!((process.line, process.col), (2, 13));
+        let select = query.select_stmts.first().unwrap().span();
+        assert_eq!((select.line, select.col), (6, 9));
+        match query.select_stmts.first().unwrap() {
+            crate::aggregation::AstNode::Select { exprs, .. } => {
+                let span = exprs.first().unwrap().span();
+                assert_eq!(&query_string[span.start..span.end], "count(plate)");
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let query_string = "SPLIT cam1 BEGIN 0 END 0 BY TIME 5sec INTO chunks1;
+            PROCESS chunks1 USING yolov3 TIMEOUT 1sec
+            PRODUCING 10 ROWS
+            WITH SCHEMA (plate:STRING=null, speed:NUMBER=0.75)
+            INTO table1;
+        SELECT plate,count(plate) FROM table1 WHERE speed > 10 GROUP BY plate,bin(chunk,hr);";
+
+        let query = crate::parser::build(query_string).unwrap();
+        let json = serde_json::to_string(&query).unwrap();
+        let parsed: crate::parser::PQLQuery = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, query);
+    }
+
+    #[test]
+    fn missing_table() {
+        let query_string = "SPLIT cam1 BEGIN 0 END 0 BY TIME 5sec INTO chunks1;
+            PROCESS chunks1 USING yolov3 TIMEOUT 1sec
+            PRODUCING 10 ROWS
+            WITH SCHEMA (plate:STRING=null)
+            INTO table1;
+        SELECT count(plate) FROM table2;";
+
+        let err = crate::parser::build(query_string).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("unknown table used in aggregation: table2"));
+        assert_eq!(
+            err.line_col,
+            pest::error::LineColLocation::Span((6, 34), (6, 40))
+        );
+    }
+
+    #[test]
+    fn syntax_error() {
+        assert!(crate::parser::build("SPLIT cam1 BEGIN;").is_err());
+    }
 }
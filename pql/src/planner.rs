@@ -0,0 +1,291 @@
+/*
+ * Inverse planning: instead of computing the noise of a fixed query, search for the chunk length
+ * (BY TIME), the rows per chunk (PRODUCING) and the epsilon that reach a target accuracy. The
+ * sensitivity of a table is maxrow * k * (1 + ceil(rho / chunk_length)), so longer chunks and
+ * fewer rows per chunk need less noise, but the model must still fit everything it detects in a
+ * chunk into its rows. The planner re-computes the sensitivity of the query for every
+ * combination, and keeps the Pareto set of (noise scale, budget cost).
+ */
+use crate::accounting::{Accountant, Mechanism, PureComposition};
+use crate::aggregation::AstNode;
+use crate::parser::PQLQuery;
+use crate::policy::PolicyMap;
+use crate::sensitivity::{query_costs, query_sensitivity};
+use crate::validate::validate;
+
+// Values to try for each parameter. An empty list keeps the value of the query (or policies).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlanSpace {
+    // BY TIME of every SPLIT
+    pub chunk_lengths_ms: Vec<u64>,
+    // PRODUCING of every PROCESS
+    pub max_rows: Vec<u64>,
+    // Epsilon of the policy of every camera
+    pub epsilons: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Plan {
+    // None when kept as in the query or policies
+    pub chunk_length_ms: Option<u64>,
+    pub max_rows: Option<u64>,
+    pub epsilon: Option<f64>,
+    // Largest noise scale of any column released by the query
+    pub noise_scale: f64,
+    // Largest epsilon spent on a camera by releasing the whole query
+    pub cost: f64,
+}
+
+/// Copy of `query` with every split using `chunk_length_ms` and every PROCESS producing
+/// `max_rows` rows per chunk
+pub fn retarget_query(
+    query: &PQLQuery,
+    chunk_length_ms: Option<u64>,
+    max_rows: Option<u64>,
+) -> PQLQuery {
+    let mut query = query.clone();
+    for ss in query.split_stmts.values_mut() {
+        ss.chunk_length_ms = chunk_length_ms.unwrap_or(ss.chunk_length_ms);
+    }
+    for ps in query.process_stmts.values_mut() {
+        ps.maxrow = max_rows.unwrap_or(ps.maxrow);
+    }
+    for stmt in query.select_stmts.iter_mut() {
+        retarget_node(stmt, chunk_length_ms, max_rows);
+    }
+    query
+}
+
+// The splits and PROCESS statements are copied into the tables of each SELECT
+fn retarget_node(node: &mut AstNode, chunk_length_ms: Option<u64>, max_rows: Option<u64>) {
+    match node {
+        AstNode::Table(ss, ps) => {
+            ss.chunk_length_ms = chunk_length_ms.unwrap_or(ss.chunk_length_ms);
+            ps.maxrow = max_rows.unwrap_or(ps.maxrow);
+        }
+        AstNode::Tables(tables, _)
+        | AstNode::Intersect { tables, .. }
+        | AstNode::Union { tables, .. }
+        | AstNode::Equijoin { tables, .. } => {
+            for t in tables.iter_mut() {
+                retarget_node(t, chunk_length_ms, max_rows);
+            }
+        }
+        AstNode::Select { from, .. } => retarget_node(from, chunk_length_ms, max_rows),
+        _ => {}
+    }
+}
+
+// Copy of `policies` with the epsilon of every camera set to `epsilon`
+fn with_epsilon(policies: &PolicyMap, epsilon: Option<f64>) -> PolicyMap {
+    policies
+        .iter()
+        .map(|(camera, policy)| {
+            let policy = epsilon.map_or_else(|| policy.clone(), |e| policy.with_epsilon(e));
+            (camera.clone(), policy)
+        })
+        .collect()
+}
+
+/// Noise scale and cost of releasing `query` with Laplace noise, or None if no column can be
+/// released
+pub fn evaluate(query: &PQLQuery, policies: &PolicyMap) -> Option<(f64, f64)> {
+    let statements = query_sensitivity(query, policies);
+    let noise_scale = statements
+        .iter()
+        .flat_map(|s| s.columns.iter().filter_map(|c| c.noise_scale))
+        .fold(None, |acc: Option<f64>, n| {
+            Some(acc.map_or(n, |acc| acc.max(n)))
+        })?;
+    let cost = query_costs(&statements, policies, Mechanism::Laplace)
+        .values()
+        .map(|costs| PureComposition.epsilon(costs))
+        .fold(0.0, f64::max);
+    Some((noise_scale, cost))
+}
+
+// The values of a parameter to try
+fn options<T: Copy>(values: &[T]) -> Vec<Option<T>> {
+    if values.is_empty() {
+        vec![None]
+    } else {
+        values.iter().map(|v| Some(*v)).collect()
+    }
+}
+
+/// Evaluates every combination of `space` and returns the Pareto set: the plans that no other plan
+/// beats on both noise scale and cost, sorted by increasing cost. Combinations that make the query
+/// invalid, e.g. a chunk length of 0 or a WITH EPSILON above that of the policies, are skipped.
+pub fn plan(query: &PQLQuery, policies: &PolicyMap, space: &PlanSpace) -> Vec<Plan> {
+    let mut plans = vec![];
+    for chunk_length_ms in options(&space.chunk_lengths_ms) {
+        for max_rows in options(&space.max_rows) {
+            let query = retarget_query(query, chunk_length_ms, max_rows);
+            for epsilon in options(&space.epsilons) {
+                let policies = with_epsilon(policies, epsilon);
+                if !validate(&query, &policies).is_empty() {
+                    continue;
+                }
+                if let Some((noise_scale, cost)) = evaluate(&query, &policies) {
+                    plans.push(Plan {
+                        chunk_length_ms,
+                        max_rows,
+                        epsilon,
+                        noise_scale,
+                        cost,
+                    });
+                }
+            }
+        }
+    }
+    pareto(plans)
+}
+
+fn pareto(mut plans: Vec<Plan>) -> Vec<Plan> {
+    // Plans whose noise or cost is NaN can't be compared with the others
+    plans.retain(|p| !p.noise_scale.is_nan() && !p.cost.is_nan());
+    plans.sort_by(|a, b| {
+        a.cost
+            .total_cmp(&b.cost)
+            .then(a.noise_scale.total_cmp(&b.noise_scale))
+    });
+    // Going by increasing cost, a plan is only worth it if it has less noise than every cheaper one
+    let mut front: Vec<Plan> = vec![];
+    for p in plans {
+        if front
+            .last()
+            .is_none_or(|last| p.noise_scale < last.noise_scale)
+        {
+            front.push(p);
+        }
+    }
+    front
+}
+
+/// Smallest epsilon, set for every camera, that releases every column of `query` with a noise
+/// scale of at most `max_noise_scale`. The noise scale is inversely proportional to epsilon, so
+/// this is exact. None if no column can be released, or if a statement sets its own epsilon (WITH
+/// EPSILON, or EPSILON on a column), which doesn't change with that of the policies.
+pub fn min_epsilon(query: &PQLQuery, policies: &PolicyMap, max_noise_scale: f64) -> Option<f64> {
+    if query
+        .select_stmts
+        .iter()
+        .any(|s| s.statement_epsilon().is_some() || !s.column_epsilons().is_empty())
+    {
+        return None;
+    }
+    let (noise_scale, _) = evaluate(query, &with_epsilon(policies, Some(1.0)))?;
+    Some(noise_scale / max_noise_scale)
+}
+
+/// Cheapest plan whose noise scale is at most `max_noise_scale`, if any
+pub fn cheapest(plans: &[Plan], max_noise_scale: f64) -> Option<&Plan> {
+    plans.iter().find(|p| p.noise_scale <= max_noise_scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::build;
+    use crate::planner::*;
+    use crate::policy::PrivacyPolicy;
+
+    const QUERY: &str = "SPLIT cam1 BEGIN 0 END 3600 BY TIME 5sec INTO chunks1;
+PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 10 ROWS
+    WITH SCHEMA (plate:STRING=null) INTO table1;
+SELECT count(plate) FROM table1;
+";
+
+    fn policies() -> PolicyMap {
+        vec![(
+            String::from("cam1"),
+            PrivacyPolicy::Static {
+                k_segments: 1,
+                rho_ms: 30_000,
+                epsilon: 1.0,
+            },
+        )]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn retarget() {
+        let query = retarget_query(&build(QUERY).unwrap(), Some(30_000), Some(2));
+        assert_eq!(query.split_stmts["chunks1"].chunk_length_ms, 30_000);
+        // 2 * 1 * (1 + 30 / 30)
+        assert_eq!(evaluate(&query, &policies()), Some((4.0, 1.0)));
+    }
+
+    #[test]
+    fn pareto_set() {
+        let query = build(QUERY).unwrap();
+        let space = PlanSpace {
+            chunk_lengths_ms: vec![5_000, 30_000],
+            max_rows: vec![],
+            epsilons: vec![0.5, 1.0],
+        };
+        let plans = plan(&query, &policies(), &space);
+        let summary: Vec<(Option<u64>, Option<f64>, f64, f64)> = plans
+            .iter()
+            .map(|p| (p.chunk_length_ms, p.epsilon, p.noise_scale, p.cost))
+            .collect();
+        // 5sec chunks (sensitivity 70) never beat 30sec ones (sensitivity 20) at the same cost
+        assert_eq!(
+            summary,
+            vec![
+                (Some(30_000), Some(0.5), 40.0, 0.5),
+                (Some(30_000), Some(1.0), 20.0, 1.0),
+            ]
+        );
+        assert_eq!(cheapest(&plans, 30.0).unwrap().epsilon, Some(1.0));
+        assert_eq!(cheapest(&plans, 10.0), None);
+    }
+
+    #[test]
+    fn invalid_plans() {
+        let query = build(QUERY).unwrap();
+        let space = PlanSpace {
+            chunk_lengths_ms: vec![0, 30_000],
+            max_rows: vec![u64::MAX, 2],
+            epsilons: vec![],
+        };
+        let plans: Vec<(Option<u64>, Option<u64>)> = plan(&query, &policies(), &space)
+            .iter()
+            .map(|p| (p.chunk_length_ms, p.max_rows))
+            .collect();
+        assert_eq!(plans, vec![(Some(30_000), Some(2))]);
+    }
+
+    #[test]
+    fn target_epsilon() {
+        let query = build(QUERY).unwrap();
+        // Sensitivity 70
+        assert_eq!(min_epsilon(&query, &policies(), 140.0), Some(0.5));
+
+        for select in &[
+            "SELECT count(plate) FROM table1 WITH EPSILON 0.5;",
+            "SELECT count(plate) EPSILON 0.5 FROM table1;",
+        ] {
+            let fixed = build(&QUERY.replace("SELECT count(plate) FROM table1;", select)).unwrap();
+            assert_eq!(min_epsilon(&fixed, &policies(), 140.0), None, "{}", select);
+        }
+    }
+
+    #[test]
+    fn pareto_nan() {
+        let plan = |noise_scale: f64, cost: f64| Plan {
+            chunk_length_ms: None,
+            max_rows: None,
+            epsilon: None,
+            noise_scale,
+            cost,
+        };
+        let front = pareto(vec![
+            plan(f64::NAN, 1.0),
+            plan(2.0, f64::NAN),
+            plan(4.0, 0.5),
+        ]);
+        assert_eq!(front, vec![plan(4.0, 0.5)]);
+    }
+}
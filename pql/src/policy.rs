@@ -1,5 +1,13 @@
+use crate::accounting::{Accountant, Accounting, PrivacyCost};
+
+use chrono::{DateTime, Datelike};
 use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write as _;
 
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum PrivacyPolicy {
     /*
      * Simple static privacy policy for a given camera/video
@@ -15,16 +23,633 @@ pub enum PrivacyPolicy {
         epsilon: f64,
         rho_ms: u64,
     },
+    /*
+     * Like Static, but persistence changes over the day (e.g. rush hour vs night), so `k` and
+     * `rho` depend on the time: each entry of `schedule` sets them for a time of day, on some days
+     * of the week. `k_segments` and `rho_ms` apply whenever no entry does. A split is bounded by
+     * the largest `k` and `rho` among the times it covers.
+     */
+    Scheduled {
+        k_segments: u64,
+        epsilon: f64,
+        rho_ms: u64,
+        schedule: Vec<ScheduleEntry>,
+    },
+    /*
+     * Camera that is part of a site: a group of cameras (e.g. along the same street) that an
+     * individual can appear in one after the other. `policy` bounds the event across every camera
+     * of the site together, i.e. <= `k` segments in total, each seen by a single camera. Every
+     * camera of the site has the same Site policy, and they share the budget of the site.
+     */
+    Site {
+        site: String,
+        policy: Box<PrivacyPolicy>,
+    },
     // TODO
     Mask {},
 }
 
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+const WEEK_MS: u64 = 7 * DAY_MS;
+
+// (k, rho) of a Scheduled policy between two times of day (in UTC)
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScheduleEntry {
+    // Days of the week the entry applies to, as days since Monday (0 to 6). Every day if empty.
+    pub days: Vec<u32>,
+    // Milliseconds since midnight, start_ms < end_ms <= 24 hours
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub k_segments: u64,
+    pub rho_ms: u64,
+}
+
+impl ScheduleEntry {
+    // Whether the entry applies at `time_ms` (unix milliseconds)
+    fn covers(&self, time_ms: u64) -> bool {
+        let time_of_day = time_ms % DAY_MS;
+        let day = DateTime::from_timestamp_millis(time_ms as i64)
+            .map_or(0, |t| t.weekday().num_days_from_monday());
+        (self.days.is_empty() || self.days.contains(&day))
+            && self.start_ms <= time_of_day
+            && time_of_day < self.end_ms
+    }
+}
+
+impl PrivacyPolicy {
+    /// Epsilon that releases from the camera must satisfy, None for mask policies
+    pub fn epsilon(&self) -> Option<f64> {
+        match self {
+            PrivacyPolicy::Static { epsilon, .. } | PrivacyPolicy::Scheduled { epsilon, .. } => {
+                Some(*epsilon)
+            }
+            PrivacyPolicy::Site { policy, .. } => policy.epsilon(),
+            PrivacyPolicy::Mask {} => None,
+        }
+    }
+
+    /// Name of the site the camera is part of, if any
+    pub fn site(&self) -> Option<&str> {
+        match self {
+            PrivacyPolicy::Site { site, .. } => Some(site),
+            _ => None,
+        }
+    }
+
+    /// Copy of the policy with a different epsilon
+    pub fn with_epsilon(&self, epsilon: f64) -> Self {
+        let mut policy = self.clone();
+        match &mut policy {
+            PrivacyPolicy::Static { epsilon: e, .. }
+            | PrivacyPolicy::Scheduled { epsilon: e, .. } => *e = epsilon,
+            PrivacyPolicy::Site { policy, .. } => **policy = policy.with_epsilon(epsilon),
+            PrivacyPolicy::Mask {} => {}
+        }
+        policy
+    }
+
+    /// Largest `(k_segments, rho_ms)` that applies at any time between `start_ms` and `end_ms`
+    /// (unix milliseconds), None for mask policies
+    pub fn segment_bounds(&self, start_ms: u64, end_ms: u64) -> Option<(u64, u64)> {
+        let (k_segments, rho_ms, schedule) = match self {
+            PrivacyPolicy::Static {
+                k_segments, rho_ms, ..
+            } => return Some((*k_segments, *rho_ms)),
+            PrivacyPolicy::Scheduled {
+                k_segments,
+                rho_ms,
+                schedule,
+                ..
+            } => (*k_segments, *rho_ms, schedule),
+            PrivacyPolicy::Site { policy, .. } => return policy.segment_bounds(start_ms, end_ms),
+            PrivacyPolicy::Mask {} => return None,
+        };
+        // The schedule repeats every week, so a week is enough to see every part of it
+        let end_ms = std::cmp::min(end_ms, start_ms + WEEK_MS);
+        // Which entries apply only changes at midnight and at the start or end of an entry
+        let mut times = vec![start_ms];
+        let mut midnight = start_ms - start_ms % DAY_MS;
+        while midnight < end_ms {
+            for e in schedule {
+                times.push(midnight + e.start_ms);
+                times.push(midnight + e.end_ms);
+            }
+            midnight += DAY_MS;
+            times.push(midnight);
+        }
+        times.retain(|t| start_ms <= *t && *t < end_ms);
+        times.sort_unstable();
+        times.dedup();
+
+        let mut bounds: Option<(u64, u64)> = None;
+        for t in times {
+            let mut entries = schedule.iter().filter(|e| e.covers(t)).peekable();
+            let (k, rho) = if entries.peek().is_none() {
+                (k_segments, rho_ms)
+            } else {
+                entries.fold((0, 0), |(k, rho), e| {
+                    (std::cmp::max(k, e.k_segments), std::cmp::max(rho, e.rho_ms))
+                })
+            };
+            bounds = Some(bounds.map_or((k, rho), |(bk, brho)| {
+                (std::cmp::max(bk, k), std::cmp::max(brho, rho))
+            }));
+        }
+        // An empty range still gets the bounds of its start
+        bounds.or_else(|| self.segment_bounds(start_ms, start_ms + 1))
+    }
+
+    /// Hash of everything in the policy, as 16 hex digits. Equal policies have the same hash, and
+    /// it doesn't depend on the platform or the Rust version, so it can be stored to identify
+    /// which version of a policy a release used.
+    pub fn content_hash(&self) -> String {
+        let mut canonical = String::new();
+        self.write_canonical(&mut canonical);
+        format!("{:016x}", fnv1a(canonical.as_bytes()))
+    }
+
+    // Every field in a fixed order, with floats as their bits so that the hash is exact
+    fn write_canonical(&self, out: &mut String) {
+        match self {
+            PrivacyPolicy::Static {
+                k_segments,
+                epsilon,
+                rho_ms,
+            } => {
+                let _ = write!(
+                    out,
+                    "static({},{:x},{})",
+                    k_segments,
+                    epsilon.to_bits(),
+                    rho_ms
+                );
+            }
+            PrivacyPolicy::Scheduled {
+                k_segments,
+                epsilon,
+                rho_ms,
+                schedule,
+            } => {
+                let _ = write!(
+                    out,
+                    "scheduled({},{:x},{},[",
+                    k_segments,
+                    epsilon.to_bits(),
+                    rho_ms
+                );
+                for e in schedule {
+                    let days: Vec<String> = e.days.iter().map(|d| d.to_string()).collect();
+                    let _ = write!(
+                        out,
+                        "({},{},{},{},{})",
+                        days.join(" "),
+                        e.start_ms,
+                        e.end_ms,
+                        e.k_segments,
+                        e.rho_ms
+                    );
+                }
+                out.push_str("])");
+            }
+            PrivacyPolicy::Site { site, policy } => {
+                let _ = write!(out, "site({:?},", site);
+                policy.write_canonical(out);
+                out.push(')');
+            }
+            PrivacyPolicy::Mask {} => out.push_str("mask()"),
+        }
+    }
+}
+
+// 64-bit FNV-1a, unlike std's DefaultHasher it is the same everywhere
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
 // PrivacyPolicy for each camera, identified by a unique string
 pub type PolicyMap = HashMap<String, PrivacyPolicy>;
 
-// TODO
-//struct PrivacyBudget {}
-//
-//struct Video {
-//    budget: PrivacyBudget,
-//}
+/// Where releases reading `camera` are charged: its site if it is part of one, or the camera
+/// itself. Cameras of a site share the epsilon and budget of the site.
+pub fn budget_key(camera: &str, policies: &PolicyMap) -> String {
+    policies
+        .get(camera)
+        .and_then(|p| p.site())
+        .map_or_else(|| camera.to_owned(), |site| site.to_owned())
+}
+
+// A version of the policy of a camera. Versions start at 1 and go up whenever the policy changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PolicyVersion {
+    pub camera: String,
+    pub version: u32,
+    // PrivacyPolicy::content_hash of the policy
+    pub hash: String,
+}
+
+// Every version of the policy of each camera, so that past releases can be traced back to the
+// policy they used, e.g. after `rho_ms` has been re-estimated
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PolicyHistory {
+    versions: HashMap<String, Vec<PrivacyPolicy>>,
+}
+
+impl PolicyHistory {
+    pub fn new() -> Self {
+        PolicyHistory::default()
+    }
+
+    /// History starting with version 1 of the policy of every camera in `policies`
+    pub fn from_policies(policies: &PolicyMap) -> Self {
+        let mut history = PolicyHistory::new();
+        for (camera, policy) in policies {
+            history.update(camera, policy.clone());
+        }
+        history
+    }
+
+    /// Makes `policy` the current policy of `camera`. It only becomes a new version if it differs
+    /// from the current one.
+    pub fn update(&mut self, camera: &str, policy: PrivacyPolicy) -> PolicyVersion {
+        let versions = self.versions.entry(camera.to_owned()).or_default();
+        if versions.last() != Some(&policy) {
+            versions.push(policy);
+        }
+        self.current(camera).unwrap()
+    }
+
+    /// Latest version of the policy of `camera`
+    pub fn current(&self, camera: &str) -> Option<PolicyVersion> {
+        let versions = self.versions.get(camera)?;
+        Some(PolicyVersion {
+            camera: camera.to_owned(),
+            version: versions.len() as u32,
+            hash: versions.last()?.content_hash(),
+        })
+    }
+
+    /// Latest version of the policy of every camera
+    pub fn policies(&self) -> PolicyMap {
+        self.versions
+            .iter()
+            .filter_map(|(camera, versions)| Some((camera.clone(), versions.last()?.clone())))
+            .collect()
+    }
+
+    /// Policy of `camera` at `version`
+    pub fn get(&self, camera: &str, version: u32) -> Option<&PrivacyPolicy> {
+        let index = (version as usize).checked_sub(1)?;
+        self.versions.get(camera)?.get(index)
+    }
+
+    /// Latest version of the policy of `camera` whose content hash is `hash`
+    pub fn find(&self, camera: &str, hash: &str) -> Option<PolicyVersion> {
+        let versions = self.versions.get(camera)?;
+        let index = versions.iter().rposition(|p| p.content_hash() == hash)?;
+        Some(PolicyVersion {
+            camera: camera.to_owned(),
+            version: index as u32 + 1,
+            hash: hash.to_owned(),
+        })
+    }
+}
+
+// Total privacy budget (epsilon) available to a camera over its lifetime, and how much of it has
+// already been used by released queries
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PrivacyBudget {
+    pub total: f64,
+    // Composition of `costs` by the accountant of the ledger
+    pub spent: f64,
+    // Cost of every release charged so far
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub costs: Vec<PrivacyCost>,
+}
+
+impl PrivacyBudget {
+    pub fn new(total: f64) -> Self {
+        PrivacyBudget {
+            total,
+            spent: 0.0,
+            costs: vec![],
+        }
+    }
+
+    pub fn remaining(&self) -> f64 {
+        self.total - self.spent
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BudgetError {
+    // The camera has no budget in the ledger
+    UnknownCamera(String),
+    Exhausted {
+        camera: String,
+        requested: f64,
+        remaining: f64,
+    },
+}
+
+impl fmt::Display for BudgetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BudgetError::UnknownCamera(camera) => {
+                write!(f, "no privacy budget for camera '{}'", camera)
+            }
+            BudgetError::Exhausted {
+                camera,
+                requested,
+                remaining,
+            } => write!(
+                f,
+                "camera '{}' has {} of its privacy budget remaining, but {} was requested",
+                camera, remaining, requested
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BudgetError {}
+
+// Privacy budget of each camera. The calling code is responsible for charging the ledger before
+// releasing the result of a query.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BudgetLedger {
+    budgets: HashMap<String, PrivacyBudget>,
+    // How the costs of successive releases add up, the same for every camera
+    #[cfg_attr(feature = "serde", serde(default))]
+    accounting: Accounting,
+}
+
+impl BudgetLedger {
+    pub fn new() -> Self {
+        BudgetLedger::default()
+    }
+
+    /// Changes the accountant, recomputing how much of each budget has been spent
+    pub fn set_accounting(&mut self, accounting: Accounting) {
+        self.accounting = accounting;
+        for budget in self.budgets.values_mut() {
+            budget.spent = accounting.epsilon(&budget.costs);
+        }
+    }
+
+    pub fn accounting(&self) -> Accounting {
+        self.accounting
+    }
+
+    pub fn insert(&mut self, camera: &str, budget: PrivacyBudget) {
+        self.budgets.insert(camera.to_owned(), budget);
+    }
+
+    pub fn get(&self, camera: &str) -> Option<&PrivacyBudget> {
+        self.budgets.get(camera)
+    }
+
+    pub fn remaining(&self, camera: &str) -> Option<f64> {
+        self.budgets.get(camera).map(|b| b.remaining())
+    }
+
+    /// Debits `epsilon` from the budget of each camera in `costs`, as pure epsilon-DP releases.
+    /// Either every camera is charged, or (if any of them doesn't have enough budget left) none
+    /// are.
+    pub fn charge(&mut self, costs: &HashMap<String, f64>) -> Result<(), BudgetError> {
+        let costs = costs
+            .iter()
+            .map(|(camera, epsilon)| {
+                (
+                    camera.clone(),
+                    vec![PrivacyCost::Pure { epsilon: *epsilon }],
+                )
+            })
+            .collect();
+        self.charge_costs(&costs)
+    }
+
+    /// Charges releases with the given costs to each camera, composing them with the previous
+    /// releases of the camera. All or nothing, like `charge`.
+    pub fn charge_costs(
+        &mut self,
+        costs: &HashMap<String, Vec<PrivacyCost>>,
+    ) -> Result<(), BudgetError> {
+        let mut spent = HashMap::new();
+        for (camera, new) in costs {
+            let budget = self
+                .budgets
+                .get(camera)
+                .ok_or_else(|| BudgetError::UnknownCamera(camera.clone()))?;
+            let mut all = budget.costs.clone();
+            all.extend(new);
+            let total = self.accounting.epsilon(&all);
+            if total > budget.total {
+                return Err(BudgetError::Exhausted {
+                    camera: camera.clone(),
+                    requested: total - budget.spent,
+                    remaining: budget.remaining(),
+                });
+            }
+            spent.insert(camera, total);
+        }
+        for (camera, new) in costs {
+            let budget = self.budgets.get_mut(camera).unwrap();
+            budget.costs.extend(new);
+            budget.spent = spent[camera];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounting::{Accounting, Mechanism};
+    use crate::policy::{
+        budget_key, BudgetError, BudgetLedger, PolicyHistory, PolicyMap, PrivacyBudget,
+        PrivacyPolicy, ScheduleEntry,
+    };
+
+    #[test]
+    fn charge_budget() {
+        let mut ledger = BudgetLedger::new();
+        ledger.insert("cam1", PrivacyBudget::new(2.0));
+        ledger.insert("cam2", PrivacyBudget::new(1.0));
+
+        let costs = vec![(String::from("cam1"), 1.0), (String::from("cam2"), 1.0)]
+            .into_iter()
+            .collect();
+        assert_eq!(ledger.charge(&costs), Ok(()));
+        assert_eq!(ledger.remaining("cam1"), Some(1.0));
+
+        // cam2 is exhausted, so cam1 must not be charged either
+        assert_eq!(
+            ledger.charge(&costs),
+            Err(BudgetError::Exhausted {
+                camera: String::from("cam2"),
+                requested: 1.0,
+                remaining: 0.0,
+            })
+        );
+        assert_eq!(ledger.remaining("cam1"), Some(1.0));
+
+        let costs = vec![(String::from("cam3"), 1.0)].into_iter().collect();
+        assert_eq!(
+            ledger.charge(&costs),
+            Err(BudgetError::UnknownCamera(String::from("cam3")))
+        );
+    }
+
+    #[test]
+    fn zcdp_budget() {
+        let mut ledger = BudgetLedger::new();
+        ledger.insert("cam1", PrivacyBudget::new(6.0));
+        let costs = |mechanism: Mechanism| {
+            vec![(String::from("cam1"), vec![mechanism.cost(0.1)])]
+                .into_iter()
+                .collect()
+        };
+
+        // Pure composition can only afford 60 releases of 0.1
+        for _ in 0..60 {
+            ledger.charge_costs(&costs(Mechanism::Laplace)).unwrap();
+        }
+        assert!(ledger.charge_costs(&costs(Mechanism::Laplace)).is_err());
+        assert!(ledger.charge_costs(&costs(Mechanism::Gaussian)).is_err());
+
+        // Under zCDP the same releases only cost ~4.4, leaving room for more
+        ledger.set_accounting(Accounting::Zcdp { delta: 1e-6 });
+        assert!(ledger.remaining("cam1").unwrap() > 1.5);
+        for _ in 0..40 {
+            ledger.charge_costs(&costs(Mechanism::Gaussian)).unwrap();
+        }
+        assert_eq!(ledger.get("cam1").unwrap().costs.len(), 100);
+        assert!(ledger.remaining("cam1").unwrap() < 0.3);
+    }
+
+    #[test]
+    fn scheduled_bounds() {
+        const HOUR: u64 = 3_600_000;
+        // Rush hour on weekdays
+        let policy = PrivacyPolicy::Scheduled {
+            k_segments: 1,
+            epsilon: 1.0,
+            rho_ms: 30_000,
+            schedule: vec![ScheduleEntry {
+                days: vec![0, 1, 2, 3, 4],
+                start_ms: 7 * HOUR,
+                end_ms: 10 * HOUR,
+                k_segments: 2,
+                rho_ms: 300_000,
+            }],
+        };
+        // 1970-01-01 was a Thursday, so day 4 is Monday and day 2 Saturday
+        let day = |d: u64| d * 24 * HOUR;
+        assert_eq!(
+            policy.segment_bounds(day(4), day(4) + 6 * HOUR),
+            Some((1, 30_000))
+        );
+        assert_eq!(
+            policy.segment_bounds(day(4) + 6 * HOUR, day(4) + 8 * HOUR),
+            Some((2, 300_000))
+        );
+        assert_eq!(
+            policy.segment_bounds(day(2) + 7 * HOUR, day(2) + 9 * HOUR),
+            Some((1, 30_000))
+        );
+        // Ends right when rush hour starts
+        assert_eq!(
+            policy.segment_bounds(day(4), day(4) + 7 * HOUR),
+            Some((1, 30_000))
+        );
+        assert_eq!(policy.segment_bounds(0, day(400)), Some((2, 300_000)));
+        assert_eq!(PrivacyPolicy::Mask {}.segment_bounds(0, day(1)), None);
+        assert_eq!(policy.with_epsilon(0.5).epsilon(), Some(0.5));
+    }
+
+    #[test]
+    fn site_policy() {
+        let inner = PrivacyPolicy::Static {
+            k_segments: 2,
+            epsilon: 1.0,
+            rho_ms: 30_000,
+        };
+        let policy = PrivacyPolicy::Site {
+            site: String::from("street"),
+            policy: Box::new(inner.clone()),
+        };
+        assert_eq!(policy.segment_bounds(0, 1000), Some((2, 30_000)));
+        assert_eq!(policy.with_epsilon(0.5).epsilon(), Some(0.5));
+        assert_eq!(policy.with_epsilon(0.5).site(), Some("street"));
+
+        let policies: PolicyMap = vec![
+            (String::from("cam1"), policy),
+            (String::from("cam2"), inner),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(budget_key("cam1", &policies), "street");
+        assert_eq!(budget_key("cam2", &policies), "cam2");
+    }
+
+    #[test]
+    fn policy_versions() {
+        let policy = |rho_ms: u64| PrivacyPolicy::Static {
+            k_segments: 1,
+            epsilon: 1.0,
+            rho_ms,
+        };
+        // Pinned, the hash must never change for the same policy
+        assert_eq!(policy(30_000).content_hash(), "ea63f36910918d95");
+        assert_eq!(policy(30_000).content_hash(), policy(30_000).content_hash());
+        assert_ne!(policy(30_000).content_hash(), policy(60_000).content_hash());
+        let site = PrivacyPolicy::Site {
+            site: String::from("street"),
+            policy: Box::new(policy(30_000)),
+        };
+        assert_ne!(site.content_hash(), policy(30_000).content_hash());
+
+        let mut history = PolicyHistory::from_policies(
+            &vec![(String::from("cam1"), policy(30_000))]
+                .into_iter()
+                .collect(),
+        );
+        let first = history.current("cam1").unwrap();
+        assert_eq!(first.version, 1);
+        // The same policy again is not a new version
+        assert_eq!(history.update("cam1", policy(30_000)), first);
+        let second = history.update("cam1", policy(60_000));
+        assert_eq!(second.version, 2);
+        assert_eq!(history.policies()["cam1"], policy(60_000));
+        assert_eq!(history.get("cam1", 1), Some(&policy(30_000)));
+        assert_eq!(history.get("cam1", 0), None);
+        assert_eq!(history.find("cam1", &first.hash), Some(first));
+        assert_eq!(history.find("cam2", &second.hash), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_policy() {
+        let policy = PrivacyPolicy::Static {
+            k_segments: 2,
+            epsilon: 1.0,
+            rho_ms: 60_000,
+        };
+        let json = serde_json::to_value(&policy).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "static": { "k_segments": 2, "epsilon": 1.0, "rho_ms": 60000 }
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<PrivacyPolicy>(json).unwrap(),
+            policy
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use crate::parser::*;
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProcessStatement {
     // Name of chunks object to be processed
     pub input_name: String,
@@ -18,27 +19,34 @@ pub struct ProcessStatement {
 
     // Name of table produced by processing chunks
     pub output_table_name: String,
+
+    pub span: Span,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StringColumn {
     pub name: String,
     pub default: String,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NumberColumn {
     pub name: String,
     pub default: f64,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FixedColumn {
     pub name: String,
     pub range: (u64, u64),
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Column {
     String(StringColumn),
     Number(NumberColumn),
@@ -77,6 +85,7 @@ fn parse_columns(def_list: pest::iterators::Pairs<Rule>) -> Vec<Column> {
 pub fn parse_process_stmt(pair: pest::iterators::Pair<Rule>) -> ProcessStatement {
     match pair.as_rule() {
         Rule::process_stmt => {
+            let span = Span::from(pair.as_span());
             let mut pair = pair.into_inner();
             let input_name = pair.next().unwrap().as_str().to_owned();
             let model_name = pair.next().unwrap().as_str().to_owned();
@@ -98,6 +107,7 @@ pub fn parse_process_stmt(pair: pest::iterators::Pair<Rule>) -> ProcessStatement
                 maxrow,
                 schema,
                 output_table_name,
+                span,
             }
         }
         _ => unreachable!(
@@ -119,7 +129,9 @@ mod tests {
             .next()
             .unwrap();
         // pest obj -> ProcessStatement object
-        let parsed_stmt = parse_process_stmt(pair);
+        let mut parsed_stmt = parse_process_stmt(pair);
+        // Spans are checked by the parser tests
+        parsed_stmt.span = Span::default();
         assert_eq!(parsed_stmt, expected);
     }
 
@@ -149,6 +161,7 @@ mod tests {
                     }),
                 ],
                 output_table_name: String::from("table1"),
+                span: Span::default(),
             },
         );
     }
@@ -0,0 +1,168 @@
+/*
+ * Python bindings, built with `maturin develop` (see pyproject.toml):
+ *
+ *   import pql
+ *   policies = {"cam1": {"k_segments": 1, "rho": "30sec", "epsilon": 0.5, "budget": 5.0}}
+ *   stmts = pql.sensitivity(query, policies)
+ *   errors = pql.accuracy(query, policies, epsilon=0.1, mechanism="gaussian", delta=1e-6)
+ *   ledger = pql.BudgetLedger.from_policies(policies)
+ *   ledger.charge({cam: stmts[0]["epsilon"] for cam in stmts[0]["cameras"]})
+ *
+ * Policies are dicts in the same format as a camera entry of a JSON policy file, and results are
+ * plain dicts/lists in the same format as the serde serialization of the Rust types.
+ */
+use crate::accounting::Mechanism;
+use crate::accuracy::estimate_accuracy;
+use crate::config::PolicyConfig;
+use crate::parser::{self, PQLQuery};
+use crate::policy::{self, PolicyMap, PrivacyBudget};
+use crate::sensitivity::query_sensitivity;
+use crate::validate::{validate_query, Diagnostic};
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::collections::HashMap;
+
+// Raised for invalid queries and policies, and when a budget can't be charged
+create_exception!(pql, PQLError, PyException);
+
+// Converts through JSON so that Python sees exactly the documented serialization format
+fn to_py<T: serde::Serialize>(py: Python, value: &T) -> PyResult<PyObject> {
+    let json = serde_json::to_string(value).map_err(|e| PQLError::new_err(e.to_string()))?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?.unbind())
+}
+
+fn parse(query: &str) -> PyResult<PQLQuery> {
+    parser::build(query).map_err(|e| PQLError::new_err(Diagnostic::from(&e).render(query)))
+}
+
+fn load_config(py: Python, policies: &Bound<PyDict>) -> PyResult<PolicyConfig> {
+    let cameras: String = py
+        .import("json")?
+        .call_method1("dumps", (policies,))?
+        .extract()?;
+    PolicyConfig::from_json(&format!("{{\"cameras\": {}}}", cameras))
+        .map_err(|e| PQLError::new_err(e.to_string()))
+}
+
+fn load_policies(py: Python, policies: &Bound<PyDict>) -> PyResult<PolicyMap> {
+    load_config(py, policies).map(|config| config.policies)
+}
+
+/// Parses `query`, returning its AST as a dict
+#[pyfunction]
+fn build(py: Python, query: &str) -> PyResult<PyObject> {
+    to_py(py, &parse(query)?)
+}
+
+/// Returns the list of problems with `query`, checked against `policies` if given
+#[pyfunction]
+#[pyo3(signature = (query, policies=None))]
+fn validate(py: Python, query: &str, policies: Option<&Bound<PyDict>>) -> PyResult<PyObject> {
+    let query = parse(query)?;
+    let diags = match policies {
+        Some(policies) => crate::validate::validate(&query, &load_policies(py, policies)?),
+        None => validate_query(&query),
+    };
+    to_py(py, &diags)
+}
+
+/// Computes the sensitivity and noise scale of each column released by each statement in `query`.
+/// Raises PQLError with every problem found if the query is invalid.
+#[pyfunction]
+fn sensitivity(py: Python, query: &str, policies: &Bound<PyDict>) -> PyResult<PyObject> {
+    let source = query;
+    let query = parse(source)?;
+    let policies = load_policies(py, policies)?;
+    let diags = crate::validate::validate(&query, &policies);
+    if !diags.is_empty() {
+        let rendered: Vec<String> = diags.iter().map(|d| d.render(source)).collect();
+        return Err(PQLError::new_err(rendered.join("\n")));
+    }
+    to_py(py, &query_sensitivity(&query, &policies))
+}
+
+/// Estimates the error of each column released by each statement in `query` before running it:
+/// its noise scale and the confidence intervals of the noise. With `epsilon`, every statement is
+/// estimated for that epsilon. Raises PQLError if the query is invalid.
+#[pyfunction]
+#[pyo3(signature = (query, policies, epsilon=None, mechanism="laplace", delta=None))]
+fn accuracy(
+    py: Python,
+    query: &str,
+    policies: &Bound<PyDict>,
+    epsilon: Option<f64>,
+    mechanism: &str,
+    delta: Option<f64>,
+) -> PyResult<PyObject> {
+    let mechanism = Mechanism::from_name(mechanism, delta).map_err(PQLError::new_err)?;
+    let source = query;
+    let query = parse(source)?;
+    let policies = load_policies(py, policies)?;
+    let diags = crate::validate::validate(&query, &policies);
+    if !diags.is_empty() {
+        let rendered: Vec<String> = diags.iter().map(|d| d.render(source)).collect();
+        return Err(PQLError::new_err(rendered.join("\n")));
+    }
+    to_py(
+        py,
+        &estimate_accuracy(&query, &policies, epsilon, mechanism),
+    )
+}
+
+// Python wrapper around policy::BudgetLedger
+#[pyclass(name = "BudgetLedger")]
+struct BudgetLedger {
+    ledger: policy::BudgetLedger,
+}
+
+#[pymethods]
+impl BudgetLedger {
+    /// Creates a ledger from a dict of camera name to total budget
+    #[new]
+    #[pyo3(signature = (budgets=HashMap::new()))]
+    fn new(budgets: HashMap<String, f64>) -> Self {
+        let mut ledger = policy::BudgetLedger::new();
+        for (camera, total) in budgets {
+            ledger.insert(&camera, PrivacyBudget::new(total));
+        }
+        BudgetLedger { ledger }
+    }
+
+    /// Creates a ledger from the `budget` of each camera in a policies dict
+    #[staticmethod]
+    fn from_policies(py: Python, policies: &Bound<PyDict>) -> PyResult<Self> {
+        Ok(BudgetLedger {
+            ledger: load_config(py, policies)?.budgets,
+        })
+    }
+
+    fn remaining(&self, camera: &str) -> Option<f64> {
+        self.ledger.remaining(camera)
+    }
+
+    fn spent(&self, camera: &str) -> Option<f64> {
+        self.ledger.get(camera).map(|b| b.spent)
+    }
+
+    /// Charges each camera in `costs`. Either every camera is charged or, if any of them doesn't
+    /// have enough budget left, none are and PQLError is raised.
+    fn charge(&mut self, costs: HashMap<String, f64>) -> PyResult<()> {
+        self.ledger
+            .charge(&costs)
+            .map_err(|e| PQLError::new_err(e.to_string()))
+    }
+}
+
+#[pymodule]
+fn pql(m: &Bound<PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(build, m)?)?;
+    m.add_function(wrap_pyfunction!(validate, m)?)?;
+    m.add_function(wrap_pyfunction!(sensitivity, m)?)?;
+    m.add_function(wrap_pyfunction!(accuracy, m)?)?;
+    m.add_class::<BudgetLedger>()?;
+    m.add("PQLError", m.py().get_type::<PQLError>())?;
+    Ok(())
+}
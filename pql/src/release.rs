@@ -0,0 +1,562 @@
+/*
+ * Differentially private release of query results. Every bounded column gets noise added to each
+ * of its values: Laplace noise with the noise scale computed by statement_sensitivity, or
+ * Gaussian noise scaled to its L2 sensitivity. Columns without a sensitivity are released as
+ * they are, validation only lets through the public keys of bin() and GROUP BY .. WITH KEYS.
+ *
+ * release_with treats each column as a separate release, while release_statement releases the
+ * whole output of a statement at once, with noise scaled to the sensitivity of all its columns
+ * together. With many columns, the Gaussian mechanism then needs less noise than the Laplace one,
+ * since the L2 sensitivity of m columns grows with sqrt(m) rather than m. GROUP BY rows don't
+ * help: the sensitivity of an aggregate is a bound on its change across all groups, which could
+ * all land on one group, so its L2 sensitivity is the same as its L1 one.
+ *
+ * Floating point noise leaks the value it was added to through its low bits, so the noise is
+ * sampled exactly from the discrete Laplace and Gaussian distributions instead, in units of a grid
+ * that noisy values are rounded to (see Noise).
+ */
+use crate::accounting::{gaussian_sigma, Mechanism, PrivacyCost};
+use crate::execute::{ResultTable, Value};
+use crate::sensitivity::StatementSensitivity;
+
+use rand::Rng;
+use std::convert::TryFrom;
+use std::fmt;
+
+// Bits of precision of the grid that noisy values are rounded to, below the sensitivity. Rounding
+// to the grid adds at most 2^-GRID_BITS to the sensitivity, which the noise is inflated to cover.
+const GRID_BITS: i32 = 12;
+// Largest noise scale in units of the grid, which keeps the arithmetic of the samplers in 128 bits
+const MAX_LAPLACE_SCALE: f64 = (1u64 << 52) as f64;
+const MAX_GAUSSIAN_SIGMA: f64 = (1u64 << 20) as f64;
+
+// Bernoulli(num / den)
+fn bernoulli<R: Rng + ?Sized>(rng: &mut R, num: u128, den: u128) -> bool {
+    rng.gen_range(0..den) < num
+}
+
+/// Samples Bernoulli(exp(-num / den)) exactly, as in Canonne, Kamath and Steinke, "The Discrete
+/// Gaussian for Differential Privacy"
+pub fn bernoulli_exp<R: Rng + ?Sized>(rng: &mut R, num: u128, den: u128) -> bool {
+    // exp(-x) is exp(-1) for each whole unit of x, times exp(-frac(x))
+    let mut whole = num / den;
+    while whole > 0 {
+        if !bernoulli_exp_fraction(rng, 1, 1) {
+            return false;
+        }
+        whole -= 1;
+    }
+    bernoulli_exp_fraction(rng, num % den, den)
+}
+
+// Bernoulli(exp(-x)) for x = num / den <= 1: the first failure of Bernoulli(x / k) for k = 1, 2, ..
+// is at an odd k with probability exp(-x)
+fn bernoulli_exp_fraction<R: Rng + ?Sized>(rng: &mut R, num: u128, den: u128) -> bool {
+    let mut k = 1;
+    while bernoulli(rng, num, den) && bernoulli(rng, 1, k) {
+        k += 1;
+    }
+    k % 2 == 1
+}
+
+/// Samples the discrete Laplace distribution over the integers, centered at 0 with scale `scale`
+/// (P(x) proportional to exp(-|x| / scale)), without floating point
+pub fn discrete_laplace<R: Rng + ?Sized>(rng: &mut R, scale: u64) -> i64 {
+    let t = u128::from(scale.max(1));
+    loop {
+        // |x| = u + t v, with u uniform in [0, t) weighted by exp(-u / t) and v geometric
+        let u = rng.gen_range(0..t);
+        if !bernoulli_exp(rng, u, t) {
+            continue;
+        }
+        let mut v = 0;
+        while bernoulli_exp(rng, 1, 1) {
+            v += 1;
+        }
+        let magnitude = i64::try_from(u + t * v).unwrap_or(i64::MAX);
+        // Reject -0, so 0 isn't sampled twice as often
+        if !rng.gen::<bool>() {
+            return magnitude;
+        } else if magnitude != 0 {
+            return -magnitude;
+        }
+    }
+}
+
+/// Samples the discrete Gaussian distribution over the integers, centered at 0 with parameter
+/// `variance` (P(x) proportional to exp(-x^2 / (2 variance))), without floating point
+pub fn discrete_gaussian<R: Rng + ?Sized>(rng: &mut R, variance: u128) -> i64 {
+    let variance = variance.max(1);
+    // t = floor(sigma) + 1
+    let mut t = (variance as f64).sqrt() as u128;
+    while t * t > variance {
+        t -= 1;
+    }
+    while (t + 1) * (t + 1) <= variance {
+        t += 1;
+    }
+    t += 1;
+    loop {
+        // Discrete Laplace samples of scale t, accepted with probability
+        // exp(-(|y| - variance / t)^2 / (2 variance))
+        let y = discrete_laplace(rng, t as u64);
+        let diff = (u128::from(y.unsigned_abs()) * t).abs_diff(variance);
+        // Only overflows for |y| over 2^24 sigma (with sigma at most MAX_GAUSSIAN_SIGMA), whose
+        // acceptance probability is below exp(-2^40)
+        if let Some(num) = diff.checked_mul(diff) {
+            if bernoulli_exp(rng, num, 2 * variance * t * t) {
+                return y;
+            }
+        }
+    }
+}
+
+// Largest power of two at most `x`, and smallest at least `x`
+fn floor_pow2(x: f64) -> f64 {
+    let p = 2f64.powi(x.log2().floor() as i32);
+    if p > x {
+        p / 2.0
+    } else {
+        p
+    }
+}
+
+fn ceil_pow2(x: f64) -> f64 {
+    let p = 2f64.powi(x.log2().ceil() as i32);
+    if p < x {
+        p * 2.0
+    } else {
+        p
+    }
+}
+
+// Granularity of the grid for noise of the given scale, and the scale in units of the grid. Each
+// of the released values is rounded to the grid, which moves neighbouring values up to one
+// granularity further apart, so the sensitivity of the rounded values grows by `spread` times the
+// granularity (n values for L1, sqrt(n) for L2). The scale is inflated by as much.
+fn grid(noise: f64, sensitivity: f64, spread: f64, max: f64) -> Option<(f64, f64)> {
+    // Values that don't depend on anyone are released as they are
+    if sensitivity.is_nan() || sensitivity <= 0.0 || spread == 0.0 {
+        return None;
+    }
+    let granularity =
+        floor_pow2(sensitivity / spread / 2f64.powi(GRID_BITS)).max(ceil_pow2(2.0 * noise / max));
+    let scale = noise * (sensitivity + granularity * spread) / sensitivity / granularity;
+    Some((granularity, scale))
+}
+
+/// Noise added to released values. To avoid the attacks on floating point noise of Mironov, "On
+/// Significance of the Least Significant Bits for Differential Privacy", values are rounded to a
+/// grid of a power of two, and integer noise from an exact discrete sampler is added in units of
+/// the grid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Noise {
+    // Discrete Laplace noise of the given scale in units of the grid
+    Laplace { granularity: f64, scale: u64 },
+    // Discrete Gaussian noise of the given variance in units of the grid
+    Gaussian { granularity: f64, variance: u128 },
+    // For values with sensitivity 0
+    Exact,
+}
+
+impl Noise {
+    /// Laplace noise of scale `scale` for `n` values of L1 sensitivity `sensitivity`
+    pub fn laplace(scale: f64, sensitivity: f64, n: usize) -> Noise {
+        match grid(scale, sensitivity, n as f64, MAX_LAPLACE_SCALE) {
+            // Rounded up with a unit to spare for floating point error, as more noise is safe
+            Some((granularity, scale)) => Noise::Laplace {
+                granularity,
+                scale: scale.ceil() as u64 + 1,
+            },
+            None => Noise::Exact,
+        }
+    }
+
+    /// Gaussian noise of standard deviation `sigma` for `n` values of L2 sensitivity
+    /// `sensitivity`
+    pub fn gaussian(sigma: f64, sensitivity: f64, n: usize) -> Noise {
+        match grid(sigma, sensitivity, (n as f64).sqrt(), MAX_GAUSSIAN_SIGMA) {
+            Some((granularity, sigma)) => Noise::Gaussian {
+                granularity,
+                variance: (sigma * sigma).ceil() as u128 + 1,
+            },
+            None => Noise::Exact,
+        }
+    }
+
+    /// Rounds `x` to the grid and adds noise to it
+    pub fn add<R: Rng + ?Sized>(&self, rng: &mut R, x: f64) -> f64 {
+        let (granularity, noise) = match *self {
+            Noise::Laplace { granularity, scale } => (granularity, discrete_laplace(rng, scale)),
+            Noise::Gaussian {
+                granularity,
+                variance,
+            } => (granularity, discrete_gaussian(rng, variance)),
+            Noise::Exact => return x,
+        };
+        // Dividing and multiplying by a power of two is exact, and rounding the sum to a float is
+        // post-processing of the exact noisy integer
+        granularity * ((x / granularity).round() + noise as f64)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReleaseError {
+    // A bounded column without the epsilon or sensitivity its noise is scaled to
+    Column(String),
+    // A statement with bounded columns, but without the epsilon or sensitivity its noise is
+    // scaled to
+    Statement(String),
+}
+
+impl fmt::Display for ReleaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReleaseError::Column(name) => write!(
+                f,
+                "column `{}` has no epsilon or sensitivity to scale its noise to",
+                name
+            ),
+            ReleaseError::Statement(query) => write!(
+                f,
+                "statement `{}` has no epsilon or sensitivity to scale its noise to",
+                query
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReleaseError {}
+
+/// Adds Laplace noise to the result of the statement whose sensitivity is `sens`
+pub fn release_table<R: Rng + ?Sized>(
+    table: ResultTable,
+    sens: &StatementSensitivity,
+    rng: &mut R,
+) -> Result<ResultTable, ReleaseError> {
+    release_with(table, sens, Mechanism::Laplace, rng)
+}
+
+/// Adds noise from `mechanism` to the result of the statement whose sensitivity is `sens`. The
+/// epsilon of the statement is split between its columns, so the release costs
+/// `mechanism.cost(sens.epsilon)`. Fails rather than release a bounded column without noise.
+pub fn release_with<R: Rng + ?Sized>(
+    mut table: ResultTable,
+    sens: &StatementSensitivity,
+    mechanism: Mechanism,
+    rng: &mut R,
+) -> Result<ResultTable, ReleaseError> {
+    // Only keys have no sensitivity, and are released as they are
+    for column in sens.columns.iter().filter(|c| c.sensitivity.is_some()) {
+        let missing = || ReleaseError::Column(column.name.clone());
+        // Each column is released with its share of the statement's epsilon
+        let n = table.rows.len();
+        let noise = match mechanism.cost(column.epsilon.ok_or_else(missing)?) {
+            PrivacyCost::Pure { .. } => Noise::laplace(
+                column.noise_scale.ok_or_else(missing)?,
+                column.sensitivity.ok_or_else(missing)?,
+                n,
+            ),
+            PrivacyCost::Concentrated { rho } => {
+                let l2 = column.l2_sensitivity.ok_or_else(missing)?;
+                Noise::gaussian(gaussian_sigma(l2, rho), l2, n)
+            }
+        };
+        for row in table.rows.iter_mut() {
+            if let Some(Value::Number(x)) = row.get_mut(&column.name) {
+                *x = noise.add(rng, *x);
+            }
+        }
+    }
+    Ok(table)
+}
+
+/// Adds noise from `mechanism` to every bounded column of the result of a statement, so that
+/// releasing the whole result costs `mechanism.cost(epsilon)`. Fails rather than release bounded
+/// columns without noise.
+pub fn release_statement<R: Rng + ?Sized>(
+    mut table: ResultTable,
+    sens: &StatementSensitivity,
+    mechanism: Mechanism,
+    epsilon: f64,
+    rng: &mut R,
+) -> Result<ResultTable, ReleaseError> {
+    let bounded: Vec<_> = sens
+        .columns
+        .iter()
+        .filter(|c| c.sensitivity.is_some())
+        .collect();
+    // Only keys, which are released as they are
+    if bounded.is_empty() {
+        return Ok(table);
+    }
+    let missing = || ReleaseError::Statement(sens.query.clone());
+    let n = bounded.len() * table.rows.len();
+    let noise = match mechanism.cost(epsilon) {
+        PrivacyCost::Pure { epsilon } => {
+            let l1 = sens.l1_sensitivity.ok_or_else(missing)?;
+            Noise::laplace(l1 / epsilon, l1, n)
+        }
+        PrivacyCost::Concentrated { rho } => {
+            let l2 = sens.l2_sensitivity.ok_or_else(missing)?;
+            Noise::gaussian(gaussian_sigma(l2, rho), l2, n)
+        }
+    };
+    for column in bounded {
+        for row in table.rows.iter_mut() {
+            if let Some(Value::Number(x)) = row.get_mut(&column.name) {
+                *x = noise.add(rng, *x);
+            }
+        }
+    }
+    Ok(table)
+}
+
+/// Releases the result of every statement of a query (in the order of `sens`, from
+/// query_sensitivity) with its allocated epsilon. The ledger should be charged with
+/// `query_costs(sens, policies, mechanism)` once this succeeds. Anything computed from the
+/// released tables afterwards, such as the ratio of two statements, is post-processing and costs
+/// nothing more.
+pub fn release_query<R: Rng + ?Sized>(
+    results: Vec<ResultTable>,
+    sens: &[StatementSensitivity],
+    mechanism: Mechanism,
+    rng: &mut R,
+) -> Result<Vec<ResultTable>, ReleaseError> {
+    results
+        .into_iter()
+        .zip(sens)
+        .map(|(table, s)| match s.epsilon {
+            Some(epsilon) => release_statement(table, s, mechanism, epsilon, rng),
+            // Only for cameras without a policy, which validation rejects
+            None if s.columns.iter().all(|c| c.sensitivity.is_none()) => Ok(table),
+            None => Err(ReleaseError::Statement(s.query.clone())),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::execute::{ChunkRows, Executor, TableData};
+    use crate::model::split_chunks;
+    use crate::parser::build;
+    use crate::policy::{PolicyMap, PrivacyPolicy};
+    use crate::release::*;
+    use crate::runner::default_rows;
+    use crate::sensitivity::query_sensitivity;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // Mean and variance of `n` samples
+    fn moments(mut sample: impl FnMut() -> f64) -> (f64, f64) {
+        let n = 100_000;
+        let samples: Vec<f64> = (0..n).map(|_| sample()).collect();
+        let mean = samples.iter().sum::<f64>() / n as f64;
+        let var = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n as f64;
+        (mean, var)
+    }
+
+    #[test]
+    fn bernoulli_exp_mean() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for (num, den) in [(0, 1), (1, 2), (3, 2), (7, 3)] {
+            let (mean, _) = moments(|| bernoulli_exp(&mut rng, num, den) as u8 as f64);
+            let p = (-(num as f64) / den as f64).exp();
+            assert!(
+                (mean - p).abs() < 0.01,
+                "{}/{}: {} != {}",
+                num,
+                den,
+                mean,
+                p
+            );
+        }
+    }
+
+    #[test]
+    fn laplace_moments() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let (mean, var) = moments(|| discrete_laplace(&mut rng, 2) as f64);
+        // Discrete Laplace(t) has mean 0 and variance 2 e^(-1/t) / (1 - e^(-1/t))^2
+        let q = (-0.5f64).exp();
+        let expected = 2.0 * q / (1.0 - q).powi(2);
+        assert!(mean.abs() < 0.05, "mean {}", mean);
+        assert!((var - expected).abs() < 0.3, "variance {}", var);
+    }
+
+    #[test]
+    fn gaussian_moments() {
+        let mut rng = StdRng::seed_from_u64(0);
+        // Close to the continuous one for a variance over 1
+        let (mean, var) = moments(|| discrete_gaussian(&mut rng, 4) as f64);
+        assert!(mean.abs() < 0.05, "mean {}", mean);
+        assert!((var - 4.0).abs() < 0.15, "variance {}", var);
+    }
+
+    #[test]
+    fn noise_grid() {
+        let mut rng = StdRng::seed_from_u64(0);
+        // 10 values of L1 sensitivity 3, released with Laplace noise of scale 3
+        let noise = Noise::laplace(3.0, 3.0, 10);
+        let (granularity, scale) = match noise {
+            Noise::Laplace { granularity, scale } => (granularity, scale),
+            _ => panic!("{:?}", noise),
+        };
+        // 3 / (10 * 2^12) is between 2^-14 and 2^-13
+        assert_eq!(granularity, 2f64.powi(-14));
+        // Inflated by (3 + 10 granularity) / 3
+        let inflated = 3.0 * (3.0 + 10.0 * granularity) / 3.0 / granularity;
+        assert!(scale as f64 >= inflated && scale as f64 <= inflated + 2.0);
+        for _ in 0..100 {
+            let x = noise.add(&mut rng, 0.1);
+            assert_eq!(x / granularity, (x / granularity).round());
+        }
+
+        // Gaussian noise on a grid of L2 sensitivity over sqrt(n)
+        match Noise::gaussian(1.0, 3.0, 100) {
+            Noise::Gaussian { granularity, .. } => assert_eq!(granularity, 2f64.powi(-14)),
+            noise => panic!("{:?}", noise),
+        }
+        // The grid is coarser when the noise would be too large in units of it
+        match Noise::gaussian(1e4, 3.0, 100) {
+            Noise::Gaussian {
+                granularity,
+                variance,
+            } => {
+                // At least 2 sigma / MAX_GAUSSIAN_SIGMA
+                assert_eq!(granularity, 2f64.powi(-5));
+                assert!(variance <= (MAX_GAUSSIAN_SIGMA * MAX_GAUSSIAN_SIGMA) as u128);
+            }
+            noise => panic!("{:?}", noise),
+        }
+        // Values that don't depend on anyone are released as they are
+        assert_eq!(Noise::laplace(0.0, 0.0, 10), Noise::Exact);
+        assert_eq!(Noise::Exact.add(&mut rng, 0.1), 0.1);
+    }
+
+    // Statement with `n` columns of the same sensitivity, and its result on default rows
+    fn statement(n: usize) -> (Vec<String>, StatementSensitivity, ResultTable) {
+        let exprs: Vec<String> = (0..n).map(|i| format!("sum(s{},l=0,u=1)", i)).collect();
+        let schema: Vec<String> = (0..n).map(|i| format!("s{}:NUMBER=0", i)).collect();
+        let query = build(&format!(
+            "SPLIT cam1 BEGIN 0 END 60 BY TIME 30sec INTO chunks1;
+            PROCESS chunks1 USING m TIMEOUT 1sec PRODUCING 1 ROWS
+                WITH SCHEMA ({}) INTO t1;
+            SELECT {} FROM t1;",
+            schema.join(", "),
+            exprs.join(", ")
+        ))
+        .unwrap();
+        let policies: PolicyMap = vec![(
+            String::from("cam1"),
+            PrivacyPolicy::Static {
+                k_segments: 1,
+                rho_ms: 0,
+                epsilon: 1.0,
+            },
+        )]
+        .into_iter()
+        .collect();
+        let sens = query_sensitivity(&query, &policies).remove(0);
+
+        let ps = &query.process_stmts["t1"];
+        let mut data = TableData::new();
+        let chunks = split_chunks(&query.split_stmts["chunks1"], ps)
+            .iter()
+            .map(|c| ChunkRows {
+                chunk: c.chunk,
+                rows: default_rows(ps),
+            })
+            .collect();
+        data.insert(String::from("t1"), chunks);
+        let result = Executor::new(&data)
+            .execute(&query.select_stmts[0])
+            .unwrap();
+        (exprs, sens, result)
+    }
+
+    #[test]
+    fn statement_noise() {
+        // Fifty columns with the same sensitivity
+        let (exprs, sens, result) = statement(50);
+        let sens = &sens;
+        let s = sens.columns[0].sensitivity.unwrap();
+        assert_eq!(sens.l1_sensitivity, Some(50.0 * s));
+        assert!((sens.l2_sensitivity.unwrap() - 50f64.sqrt() * s).abs() < 1e-6);
+
+        // Average squared error of each mechanism, releasing everything for epsilon = 1
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut error = |mechanism: Mechanism| {
+            let n = 1000;
+            let mut total = 0.0;
+            for _ in 0..n {
+                let noisy =
+                    release_statement(result.clone(), sens, mechanism, 1.0, &mut rng).unwrap();
+                for e in &exprs {
+                    total += noisy.rows[0][e].as_number().unwrap().powi(2);
+                }
+            }
+            total / (n * exprs.len()) as f64
+        };
+        let laplace = error(Mechanism::Laplace);
+        let gaussian = error(Mechanism::ApproxGaussian { delta: 1e-6 });
+        // Laplace: 2 (50 s)^2 = 5000 s^2, Gaussian: (sqrt(50) s)^2 / (2 rho) ~ 1431 s^2
+        assert!(
+            (laplace / (s * s) - 5000.0).abs() < 250.0,
+            "{}",
+            laplace / (s * s)
+        );
+        assert!(
+            (gaussian / (s * s) - 1431.0).abs() < 75.0,
+            "{}",
+            gaussian / (s * s)
+        );
+    }
+
+    #[test]
+    fn column_noise() {
+        let (exprs, sens, result) = statement(2);
+        let mut rng = StdRng::seed_from_u64(0);
+        for mechanism in [
+            Mechanism::Laplace,
+            Mechanism::Gaussian,
+            Mechanism::ApproxGaussian { delta: 1e-6 },
+        ] {
+            let noisy = release_with(result.clone(), &sens, mechanism, &mut rng).unwrap();
+            for e in &exprs {
+                assert_ne!(noisy.rows[0][e], result.rows[0][e], "{:?}", mechanism);
+            }
+        }
+    }
+
+    #[test]
+    fn missing_noise() {
+        let (_, sens, result) = statement(1);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut unscaled = sens.clone();
+        unscaled.columns[0].l2_sensitivity = None;
+        assert_eq!(
+            release_with(result.clone(), &unscaled, Mechanism::Gaussian, &mut rng),
+            Err(ReleaseError::Column(String::from("sum(s0,l=0,u=1)")))
+        );
+        unscaled.l2_sensitivity = None;
+        assert_eq!(
+            release_statement(
+                result.clone(),
+                &unscaled,
+                Mechanism::Gaussian,
+                1.0,
+                &mut rng
+            ),
+            Err(ReleaseError::Statement(sens.query.clone()))
+        );
+        let mut unallocated = sens.clone();
+        unallocated.epsilon = None;
+        unallocated.columns[0].epsilon = None;
+        assert_eq!(
+            release_query(vec![result], &[unallocated], Mechanism::Laplace, &mut rng),
+            Err(ReleaseError::Statement(sens.query.clone()))
+        );
+    }
+}
@@ -0,0 +1,195 @@
+/*
+ * Runs the processing function of each chunk under the TIMEOUT of its PROCESS statement. If the
+ * function overruns, fails or panics, the chunk outputs exactly the default rows instead, so
+ * whether an individual is in the chunk can't change its output through the runtime or failures
+ * of the model. Otherwise its output goes through the ChunkOutputSanitizer. Either way it is only
+ * returned once the TIMEOUT has passed, so how long the model took doesn't leak either.
+ */
+use crate::execute::{ChunkRows, Row, Value};
+use crate::process::{Column, ProcessStatement};
+use crate::sanitize::{ChunkOutputSanitizer, Violation};
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkOutcome {
+    Completed,
+    TimedOut,
+    // The function returned an error or panicked
+    Failed(String),
+}
+
+// Output of a single chunk, and how it was produced
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkRun {
+    pub output: ChunkRows,
+    pub outcome: ChunkOutcome,
+    // Problems fixed by the sanitizer, only for completed chunks
+    pub violations: Vec<Violation>,
+}
+
+/// Rows output by a chunk whose processing didn't complete: a single row with the default value
+/// of every column in the schema
+pub fn default_rows(ps: &ProcessStatement) -> Vec<Row> {
+    let row = ps
+        .schema
+        .iter()
+        .filter_map(|c| match c {
+            Column::String(sc) => Some((sc.name.clone(), Value::String(sc.default.clone()))),
+            Column::Number(nc) => Some((nc.name.clone(), Value::Number(nc.default))),
+            // Added by the executor
+            Column::Fixed(_) => None,
+        })
+        .collect();
+    vec![row]
+}
+
+/// Sleeps until `deadline`, if it isn't already past
+pub fn wait_until(deadline: Instant) {
+    let now = Instant::now();
+    if now < deadline {
+        thread::sleep(deadline - now);
+    }
+}
+
+pub struct ChunkRunner<'a> {
+    sanitizer: ChunkOutputSanitizer<'a>,
+}
+
+impl<'a> ChunkRunner<'a> {
+    pub fn new(sanitizer: ChunkOutputSanitizer<'a>) -> Self {
+        ChunkRunner { sanitizer }
+    }
+
+    /// Runs `f` to produce the output of `chunk`, giving up after the TIMEOUT of the PROCESS
+    /// statement. Threads can't be killed, so a function that overruns keeps running in the
+    /// background until it returns, but its output is discarded. Returns at the TIMEOUT even if
+    /// `f` finishes earlier.
+    pub fn run<F>(&self, chunk: u64, f: F) -> ChunkRun
+    where
+        F: FnOnce() -> Result<Vec<Row>, String> + Send + 'static,
+    {
+        let ps = self.sanitizer.process();
+        let deadline = Instant::now() + Duration::from_millis(ps.chunk_timeout_ms);
+        let (tx, rx) = mpsc::channel();
+        let spawned = thread::Builder::new()
+            .name(format!("{}-chunk-{}", ps.output_table_name, chunk))
+            .spawn(move || {
+                // The receiver is gone if we already timed out
+                let _ = tx.send(f());
+            });
+
+        let outcome = match spawned {
+            Err(e) => Err(ChunkOutcome::Failed(format!(
+                "could not start thread: {}",
+                e
+            ))),
+            Ok(_) => match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Ok(rows)) => Ok(rows),
+                Ok(Err(e)) => Err(ChunkOutcome::Failed(e)),
+                Err(RecvTimeoutError::Timeout) => Err(ChunkOutcome::TimedOut),
+                // The sender was dropped without sending anything
+                Err(RecvTimeoutError::Disconnected) => {
+                    Err(ChunkOutcome::Failed(String::from("processing panicked")))
+                }
+            },
+        };
+        wait_until(deadline);
+
+        match outcome {
+            Ok(rows) => {
+                let (output, violations) = self.sanitizer.sanitize_chunk(chunk, rows);
+                ChunkRun {
+                    output,
+                    outcome: ChunkOutcome::Completed,
+                    violations,
+                }
+            }
+            Err(outcome) => ChunkRun {
+                output: ChunkRows {
+                    chunk,
+                    rows: default_rows(ps),
+                },
+                outcome,
+                violations: vec![],
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::build;
+    use crate::runner::*;
+
+    fn process() -> ProcessStatement {
+        let query = build(
+            "SPLIT cam1 BEGIN 0 END 60 BY TIME 30sec INTO chunks1;
+            PROCESS chunks1 USING yolov3 TIMEOUT 100ms PRODUCING 2 ROWS
+                WITH SCHEMA (plate:STRING=null, speed:NUMBER=0) INTO t1;
+            SELECT count(plate) FROM t1;",
+        )
+        .unwrap();
+        query.process_stmts["t1"].clone()
+    }
+
+    fn row(plate: &str, speed: f64) -> Row {
+        vec![
+            (String::from("plate"), Value::String(plate.to_owned())),
+            (String::from("speed"), Value::Number(speed)),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn completed() {
+        let ps = process();
+        let runner = ChunkRunner::new(ChunkOutputSanitizer::new(&ps));
+        let start = Instant::now();
+        let run = runner.run(3, || Ok(vec![row("abc", 30.0)]));
+        // Held until the TIMEOUT, however fast the function was
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(run.outcome, ChunkOutcome::Completed);
+        assert_eq!(
+            run.output,
+            ChunkRows {
+                chunk: 3,
+                rows: vec![row("abc", 30.0)]
+            }
+        );
+    }
+
+    #[test]
+    fn default_on_failure() {
+        let ps = process();
+        let runner = ChunkRunner::new(ChunkOutputSanitizer::new(&ps));
+        let defaults = vec![row("null", 0.0)];
+        assert_eq!(default_rows(&ps), defaults);
+
+        let run = runner.run(0, || {
+            thread::sleep(Duration::from_millis(500));
+            Ok(vec![row("abc", 30.0)])
+        });
+        assert_eq!(run.outcome, ChunkOutcome::TimedOut);
+        assert_eq!(run.output.rows, defaults);
+
+        let start = Instant::now();
+        let run = runner.run(0, || Err(String::from("model crashed")));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            run.outcome,
+            ChunkOutcome::Failed(String::from("model crashed"))
+        );
+        assert_eq!(run.output.rows, defaults);
+
+        let run = runner.run(0, || panic!("model panicked"));
+        assert_eq!(
+            run.outcome,
+            ChunkOutcome::Failed(String::from("processing panicked"))
+        );
+        assert_eq!(run.output.rows, defaults);
+    }
+}
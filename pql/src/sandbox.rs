@@ -0,0 +1,981 @@
+/*
+ * ChunkModel that runs an external program (e.g. the Python script named in `USING auburn.py`)
+ * as a child process for each chunk. The program is untrusted analyst code, so it runs:
+ *
+ *   - with an empty environment (except for a fixed PATH) and stdin/stdout as its only channels,
+ *     stderr is discarded
+ *   - with resource limits: address space, CPU time, open files, processes and no file writes
+ *   - as a dedicated user: the process refuses to run it as root, unless it is given a uid and gid
+ *     to switch to first
+ *   - in its own network and IPC namespaces (on Linux), so it can't send data over the network or
+ *     through SysV IPC and POSIX message queues
+ *   - with a default-deny seccomp filter (on Linux), which only allows syscalls that read files,
+ *     manage its own memory, processes and pipes, and use network sockets. It can't create,
+ *     change or remove files, open unix sockets, leave its process group or create namespaces.
+ *   - in its own process group, which is killed when the chunk's TIMEOUT expires
+ *
+ * Its result is only returned at a fixed time before the TIMEOUT, even if it exits earlier.
+ *
+ * Protocol, one JSON value per line: the program reads `{"info": <ChunkInfo>}` followed by one
+ * `{"row": {...}}` or `{"frame": [<bytes>]}` line per input item, until EOF. It writes one JSON
+ * object per output row (`{"plate": "abc", "speed": 31.5}`) to stdout and exits with status 0.
+ * Anything else fails the chunk, which then outputs the default rows.
+ */
+use crate::execute::Row;
+use crate::model::{ChunkInfo, ChunkInput, ChunkModel, ModelRegistry};
+use crate::parser::PQLQuery;
+use crate::runner::wait_until;
+
+use serde_json::json;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Only PATH is passed to the program, so that `#!/usr/bin/env python3` works
+const PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+// The program is stopped this long before the TIMEOUT, so that its result reaches the
+// ChunkRunner in time. The ChunkRunner then holds it until the TIMEOUT itself.
+const RESULT_MARGIN_MS: u64 = 50;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    // Maximum size of the address space, in bytes
+    pub memory_bytes: u64,
+    pub open_files: u64,
+    // Maximum size of the program's stdout, in bytes
+    pub output_bytes: u64,
+    // Maximum number of processes of the user running the program, which RLIMIT_NPROC counts
+    // across all their processes, so programs should run as a dedicated user
+    pub processes: u64,
+    // uid and gid the program runs as, required when running as root
+    pub user: Option<(libc::uid_t, libc::gid_t)>,
+    // Run the program in a new network namespace with no interfaces (Linux only)
+    pub isolate_network: bool,
+    // Run the program in a new IPC namespace, without the SysV IPC objects and POSIX message
+    // queues of other processes (Linux only)
+    pub isolate_ipc: bool,
+    // Only allow the syscalls needed to read files, compute and write the output (Linux on x86_64
+    // and aarch64 only)
+    pub restrict_syscalls: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            memory_bytes: 1 << 30,
+            open_files: 64,
+            output_bytes: 1 << 20,
+            processes: 64,
+            user: None,
+            isolate_network: true,
+            isolate_ipc: true,
+            restrict_syscalls: true,
+        }
+    }
+}
+
+pub struct ProcessModel {
+    program: PathBuf,
+    args: Vec<String>,
+    dir: PathBuf,
+    limits: Limits,
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type Resource = libc::c_int;
+
+fn set_rlimit(resource: Resource, limit: u64) -> io::Result<()> {
+    let rlim = libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: limit as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource, &rlim) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Runs in the child between fork and exec, so it may only make async-signal-safe calls. `filter`
+// is built beforehand for the same reason.
+fn sandbox_child(limits: &Limits, cpu_secs: u64, filter: &SyscallFilter) -> io::Result<()> {
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    set_rlimit(libc::RLIMIT_AS, limits.memory_bytes)?;
+    set_rlimit(libc::RLIMIT_CPU, cpu_secs)?;
+    set_rlimit(libc::RLIMIT_NOFILE, limits.open_files)?;
+    set_rlimit(libc::RLIMIT_NPROC, limits.processes)?;
+    set_rlimit(libc::RLIMIT_FSIZE, 0)?;
+    set_rlimit(libc::RLIMIT_CORE, 0)?;
+    if limits.isolate_network || limits.isolate_ipc {
+        isolate(limits)?;
+    }
+    // After creating the namespaces, which needs root unless in a new user namespace
+    if let Some((uid, gid)) = limits.user {
+        drop_privileges(uid, gid)?;
+    }
+    // Last, since it denies setsid, setuid and unshare
+    if limits.restrict_syscalls {
+        restrict_syscalls(filter)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn isolate(limits: &Limits) -> io::Result<()> {
+    let mut flags = 0;
+    if limits.isolate_network {
+        flags |= libc::CLONE_NEWNET;
+    }
+    if limits.isolate_ipc {
+        flags |= libc::CLONE_NEWIPC;
+    }
+    // Unprivileged users need a user namespace to create the other namespaces
+    if unsafe { libc::geteuid() } != 0 {
+        flags |= libc::CLONE_NEWUSER;
+    }
+    if unsafe { libc::unshare(flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn isolate(_: &Limits) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "network and IPC isolation are only supported on Linux",
+    ))
+}
+
+// Switches to `uid` and `gid` for good, dropping the supplementary groups of the parent
+fn drop_privileges(uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    unsafe {
+        if libc::setgroups(0, std::ptr::null()) != 0
+            || libc::setgid(gid) != 0
+            || libc::setuid(uid) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Syscalls the program may make, any other fails with EPERM. They only read files, use memory,
+// processes, threads, signals, pipes and sockets, and query the process. Opening files, creating
+// sockets and cloning are allowed separately, only with some arguments.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_preadv2,
+    libc::SYS_pwritev2,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_lseek,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigsuspend,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_sigaltstack,
+    libc::SYS_ioctl,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_pipe2,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_fcntl,
+    libc::SYS_getdents64,
+    libc::SYS_getcwd,
+    libc::SYS_chdir,
+    libc::SYS_fchdir,
+    libc::SYS_readlinkat,
+    libc::SYS_uname,
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getgroups,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getpgid,
+    libc::SYS_getsid,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    libc::SYS_sysinfo,
+    libc::SYS_times,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_get_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_membarrier,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_getrandom,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_kill,
+    libc::SYS_tgkill,
+    libc::SYS_tkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_umask,
+    libc::SYS_setitimer,
+    libc::SYS_getitimer,
+    libc::SYS_restart_syscall,
+    libc::SYS_fadvise64,
+    libc::SYS_sendfile,
+    libc::SYS_copy_file_range,
+    libc::SYS_splice,
+    libc::SYS_access,
+    libc::SYS_pipe,
+    libc::SYS_dup2,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_getdents,
+    libc::SYS_readlink,
+    libc::SYS_getrlimit,
+    libc::SYS_getpgrp,
+    libc::SYS_time,
+    libc::SYS_arch_prctl,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_wait,
+    libc::SYS_epoll_create,
+    libc::SYS_fork,
+    libc::SYS_vfork,
+    libc::SYS_alarm,
+    libc::SYS_pause,
+];
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_preadv2,
+    libc::SYS_pwritev2,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_lseek,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigsuspend,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_sigaltstack,
+    libc::SYS_ioctl,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_pipe2,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_fcntl,
+    libc::SYS_getdents64,
+    libc::SYS_getcwd,
+    libc::SYS_chdir,
+    libc::SYS_fchdir,
+    libc::SYS_readlinkat,
+    libc::SYS_uname,
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getgroups,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getpgid,
+    libc::SYS_getsid,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    libc::SYS_sysinfo,
+    libc::SYS_times,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_get_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_membarrier,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_getrandom,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_kill,
+    libc::SYS_tgkill,
+    libc::SYS_tkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_umask,
+    libc::SYS_setitimer,
+    libc::SYS_getitimer,
+    libc::SYS_restart_syscall,
+    libc::SYS_fadvise64,
+    libc::SYS_sendfile,
+    libc::SYS_copy_file_range,
+    libc::SYS_splice,
+];
+
+// Syscalls opening files, with the index of their flags argument. Files may only be opened for
+// reading.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const OPEN_SYSCALLS: &[(libc::c_long, u32)] = &[(libc::SYS_open, 1), (libc::SYS_openat, 2)];
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const OPEN_SYSCALLS: &[(libc::c_long, u32)] = &[(libc::SYS_openat, 2)];
+
+// Only network sockets (in the empty network namespace), unix sockets could reach other processes
+// through the filesystem
+#[cfg(target_os = "linux")]
+const SOCKET_DOMAINS: &[libc::c_int] = &[libc::AF_INET, libc::AF_INET6];
+
+// clone flags creating namespaces, which could undo the isolation of the program
+#[cfg(target_os = "linux")]
+const NAMESPACE_FLAGS: libc::c_int = libc::CLONE_NEWNS
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | libc::CLONE_NEWCGROUP;
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+type SyscallFilter = Vec<libc::sock_filter>;
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+type SyscallFilter = ();
+
+// Seccomp filter failing the syscalls the program may not make with EPERM
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn syscall_filter() -> SyscallFilter {
+    let op = |code: u32, k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    // Offsets in seccomp_data, arguments are little-endian so the low 32 bits come first
+    let load = |offset: u32| op(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset, 0, 0);
+    let arg = |index: u32| load(16 + 8 * index);
+    let is = |k: u32, jf: u8| op(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, k, 0, jf);
+    let ret = |k: u32| op(libc::BPF_RET | libc::BPF_K, k, 0, 0);
+    let deny = ret(libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+    let allow = ret(libc::SECCOMP_RET_ALLOW);
+
+    // Syscall numbers differ between architectures
+    let mut filter = vec![
+        load(4),
+        op(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            AUDIT_ARCH,
+            1,
+            0,
+        ),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+        load(0),
+    ];
+    // x32 syscalls have the same numbers with this bit set
+    if cfg!(target_arch = "x86_64") {
+        filter.push(op(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            0x4000_0000,
+            0,
+            1,
+        ));
+        filter.push(deny);
+    }
+    // clone3 passes its flags in memory, which the filter can't read. ENOSYS makes libc fall back
+    // to clone.
+    filter.push(is(libc::SYS_clone3 as u32, 1));
+    filter.push(ret(libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+    filter.push(is(libc::SYS_clone as u32, 4));
+    filter.push(arg(0));
+    filter.push(op(
+        libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K,
+        NAMESPACE_FLAGS as u32,
+        0,
+        1,
+    ));
+    filter.push(deny);
+    filter.push(allow);
+    let writing = (libc::O_WRONLY | libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC) as u32;
+    for (nr, flags) in OPEN_SYSCALLS {
+        filter.push(is(*nr as u32, 4));
+        filter.push(arg(*flags));
+        filter.push(op(
+            libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K,
+            writing,
+            0,
+            1,
+        ));
+        filter.push(deny);
+        filter.push(allow);
+    }
+    filter.push(is(
+        libc::SYS_socket as u32,
+        2 * SOCKET_DOMAINS.len() as u8 + 2,
+    ));
+    filter.push(arg(0));
+    for domain in SOCKET_DOMAINS {
+        filter.push(is(*domain as u32, 1));
+        filter.push(allow);
+    }
+    filter.push(deny);
+    for nr in ALLOWED_SYSCALLS {
+        filter.push(is(*nr as u32, 1));
+        filter.push(allow);
+    }
+    filter.push(deny);
+    filter
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn syscall_filter() -> SyscallFilter {}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn restrict_syscalls(filter: &SyscallFilter) -> io::Result<()> {
+    // Required to install a filter without CAP_SYS_ADMIN
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let program = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    let installed = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &program as *const libc::sock_fprog,
+        )
+    };
+    if installed != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn restrict_syscalls(_: &SyscallFilter) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "restricting syscalls is only supported on Linux on x86_64 and aarch64",
+    ))
+}
+
+fn kill_group(child: &mut Child) {
+    // The child called setsid, so its pid is also its process group id
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn write_input(mut stdin: impl Write, info: &ChunkInfo, input: ChunkInput) -> io::Result<()> {
+    writeln!(stdin, "{}", json!({ "info": info }))?;
+    match input {
+        ChunkInput::Rows(rows) => {
+            for row in rows {
+                writeln!(stdin, "{}", json!({ "row": row }))?;
+            }
+        }
+        ChunkInput::Frames(frames) => {
+            for frame in frames {
+                writeln!(stdin, "{}", json!({ "frame": frame }))?;
+            }
+        }
+    }
+    stdin.flush()
+}
+
+fn parse_output(output: &[u8]) -> Result<Vec<Row>, String> {
+    let mut rows = vec![];
+    for (i, line) in BufReader::new(output).lines().enumerate() {
+        let line = line.map_err(|e| format!("output is not valid UTF-8: {}", e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        rows.push(
+            serde_json::from_str(&line)
+                .map_err(|e| format!("line {} of output is not a row: {}", i + 1, e))?,
+        );
+    }
+    Ok(rows)
+}
+
+impl ProcessModel {
+    /// Runs `program` directly, it must be executable
+    pub fn new<P: AsRef<Path>>(program: P) -> Self {
+        let program = program.as_ref().to_path_buf();
+        let dir = program
+            .parent()
+            .map_or_else(|| PathBuf::from("/"), Path::to_path_buf);
+        ProcessModel {
+            program,
+            args: vec![],
+            dir,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Runs `script` with `interpreter`, e.g. `/usr/bin/python3 auburn.py`
+    pub fn script<P: AsRef<Path>, S: AsRef<Path>>(interpreter: P, script: S) -> Self {
+        let script = script.as_ref();
+        let mut model = ProcessModel::new(script);
+        model.program = interpreter.as_ref().to_path_buf();
+        model.args = vec![script.to_string_lossy().into_owned()];
+        model
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    fn run(
+        &self,
+        info: &ChunkInfo,
+        input: ChunkInput,
+        deadline: Instant,
+    ) -> Result<Vec<Row>, String> {
+        if self.limits.user.is_none() && unsafe { libc::geteuid() } == 0 {
+            return Err(String::from(
+                "refusing to run the program as root, set the uid and gid it runs as in Limits",
+            ));
+        }
+        // CPU time can't exceed wall-clock time, round up to whole seconds
+        let cpu_secs = info.timeout_ms.div_ceil(1000).max(1);
+        let limits = self.limits.clone();
+        let filter = syscall_filter();
+
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .current_dir(&self.dir)
+            .env_clear()
+            .env("PATH", PATH)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        unsafe {
+            command.pre_exec(move || sandbox_child(&limits, cpu_secs, &filter));
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|e| format!("could not start {}: {}", self.program.display(), e))?;
+
+        // Write and read on separate threads so that a program that doesn't read its input (or
+        // doesn't write its output) can't block us past the deadline
+        let stdin = child.stdin.take().unwrap();
+        let info_in = info.clone();
+        let writer = thread::spawn(move || write_input(stdin, &info_in, input));
+        let stdout = child.stdout.take().unwrap();
+        let max_output = self.limits.output_bytes;
+        let reader = thread::spawn(move || {
+            let mut output = vec![];
+            stdout.take(max_output + 1).read_to_end(&mut output)?;
+            Ok::<_, io::Error>(output)
+        });
+
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() >= deadline => {
+                    kill_group(&mut child);
+                    return Err(format!("timed out after {}ms", info.timeout_ms));
+                }
+                Ok(None) => thread::sleep(Duration::from_millis(5)),
+                Err(e) => {
+                    kill_group(&mut child);
+                    return Err(format!("could not wait for program: {}", e));
+                }
+            }
+        };
+        // Make sure nothing the program started outlives it
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+        }
+        // The program may exit without reading all its input
+        let _ = writer.join();
+        let output = reader
+            .join()
+            .map_err(|_| String::from("output reader panicked"))?
+            .map_err(|e| format!("could not read output: {}", e))?;
+
+        if !status.success() {
+            return Err(format!("program exited with {}", status));
+        }
+        if output.len() as u64 > max_output {
+            return Err(format!("output is larger than {} bytes", max_output));
+        }
+        parse_output(&output)
+    }
+}
+
+impl ChunkModel for ProcessModel {
+    /// Returns at the timeout even if the program exits earlier, or fails to start, so that its
+    /// runtime doesn't depend on the chunk
+    fn process(&self, info: &ChunkInfo, input: ChunkInput) -> Result<Vec<Row>, String> {
+        let deadline = Instant::now()
+            + Duration::from_millis(info.timeout_ms.saturating_sub(RESULT_MARGIN_MS));
+        let result = self.run(info, input, deadline);
+        wait_until(deadline);
+        result
+    }
+}
+
+/// Registers a ProcessModel running `dir/<model>` with `interpreter` and `limits` for every model
+/// used by `query` that isn't registered yet. Model names can't contain path separators, but are
+/// also rejected if they start with a `.`, so `USING ..` can't escape `dir`.
+pub fn register_scripts<P: AsRef<Path>, D: AsRef<Path>>(
+    registry: &mut ModelRegistry,
+    query: &PQLQuery,
+    interpreter: P,
+    dir: D,
+    limits: &Limits,
+) -> Result<(), String> {
+    for ps in query.process_stmts.values() {
+        let name = &ps.model_name;
+        if registry.get(name).is_some() {
+            continue;
+        }
+        if name.starts_with('.') {
+            return Err(format!("invalid model name `{}`", name));
+        }
+        let mut model = ProcessModel::script(interpreter.as_ref(), dir.as_ref().join(name));
+        model.set_limits(limits.clone());
+        registry.register(name, Arc::new(model));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::execute::Value;
+    use crate::model::{process_tables, ChunkSource};
+    use crate::parser::build;
+    use crate::runner::ChunkOutcome;
+    use crate::sandbox::*;
+
+    // Writes a shell script into a fresh temporary directory, returning its path
+    fn script(name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pql-sandbox-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn info(timeout_ms: u64) -> ChunkInfo {
+        ChunkInfo {
+            camera: String::from("cam1"),
+            chunk: 0,
+            start_ms: 0,
+            end_ms: 30_000,
+            timeout_ms,
+            schema: vec![],
+        }
+    }
+
+    // Default limits, running as nobody when the tests run as root
+    fn limits() -> Limits {
+        let root = unsafe { libc::geteuid() } == 0;
+        Limits {
+            user: Some((65534, 65534)).filter(|_| root),
+            ..Limits::default()
+        }
+    }
+
+    fn model(interpreter: &str, path: PathBuf) -> ProcessModel {
+        let mut model = ProcessModel::script(interpreter, path);
+        model.set_limits(limits());
+        model
+    }
+
+    fn run(contents: &str, timeout_ms: u64) -> Result<Vec<Row>, String> {
+        let path = script(&format!("{}.sh", timeout_ms), contents);
+        model("/bin/sh", path).process(&info(timeout_ms), ChunkInput::Rows(vec![]))
+    }
+
+    #[test]
+    fn protocol() {
+        // Echoes the number of input lines and the (empty) environment back as a row
+        let rows = run(
+            r#"n=$(wc -l); echo "{\"lines\": $n, \"home\": \"$HOME\"}""#,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["lines"], Value::Number(1.0));
+        assert_eq!(rows[0]["home"], Value::String(String::new()));
+
+        let err = run("echo not json", 1000).unwrap_err();
+        assert!(err.starts_with("line 1 of output is not a row"), "{}", err);
+        let start = Instant::now();
+        let err = run("exit 3", 300).unwrap_err();
+        assert_eq!(err, "program exited with exit status: 3");
+        assert!(start.elapsed() >= Duration::from_millis(300 - RESULT_MARGIN_MS));
+    }
+
+    #[test]
+    fn sandboxed() {
+        let start = Instant::now();
+        let err = run("sleep 5; echo '{}'", 200).unwrap_err();
+        assert_eq!(err, "timed out after 200ms");
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // Only the loopback interface exists
+        if cfg!(target_os = "linux") {
+            let rows = run(
+                r#"n=$(grep -c : /proc/net/dev); echo "{\"interfaces\": $n}""#,
+                1000,
+            )
+            .unwrap();
+            assert_eq!(rows[0]["interfaces"], Value::Number(1.0));
+        }
+
+        // Writing files is not allowed
+        let dir = std::env::temp_dir().join(format!("pql-sandbox-{}-fsize", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let leaked = dir.join("leaked");
+        let err = run(&format!("echo secret > {}", leaked.display()), 1000);
+        assert!(err.is_err());
+        assert!(!leaked.exists());
+
+        // Nor changing or removing them
+        if cfg!(target_os = "linux") {
+            let kept = dir.join("kept");
+            std::fs::write(&kept, "data").unwrap();
+            for command in ["rm -f", "chmod 777", "touch"] {
+                run(&format!("{} {}", command, kept.display()), 1000).unwrap_err();
+            }
+            run(&format!("mv {} {}", kept.display(), leaked.display()), 1000).unwrap_err();
+            assert_eq!(std::fs::read(&kept).unwrap(), b"data");
+            assert!(!leaked.exists());
+
+            // Nor leaving the process group that is killed on timeout
+            let rows = run(r#"setsid true; echo "{\"status\": $?}""#, 1000).unwrap();
+            assert_ne!(rows[0]["status"], Value::Number(0.0));
+
+            // Nor opening unix sockets
+            if Path::new("/usr/bin/python3").exists() {
+                let path = script(
+                    "unix.py",
+                    "import socket\n\
+                     try:\n    socket.socket(socket.AF_UNIX)\n    print('{\"unix\": 1}')\n\
+                     except OSError:\n    print('{\"unix\": 0}')\n",
+                );
+                let rows = model("/usr/bin/python3", path)
+                    .process(&info(1000), ChunkInput::Rows(vec![]))
+                    .unwrap();
+                assert_eq!(rows[0]["unix"], Value::Number(0.0));
+
+                // Nor anything outside the allowed syscalls, e.g. changing open files, SysV
+                // shared memory or POSIX message queues
+                let path = script(
+                    "denied.py",
+                    "import ctypes, os, sys\n\
+                     libc = ctypes.CDLL(None, use_errno=True)\n\
+                     calls = [lambda: os.fchmod(0, 0o777), lambda: os.fchown(0, 0, 0)]\n\
+                     denied = 0\n\
+                     for call in calls:\n    try:\n        call()\n    except OSError:\n        denied += 1\n\
+                     denied += libc.shmget(0, 4096, 0o1600) < 0\n\
+                     denied += libc.mq_open(b'/pql', 0o100 | 2, 0o600, None) < 0\n\
+                     print('{\"denied\": %d}' % denied)\n",
+                );
+                let rows = model("/usr/bin/python3", path)
+                    .process(&info(2000), ChunkInput::Rows(vec![]))
+                    .unwrap();
+                assert_eq!(rows[0]["denied"], Value::Number(4.0));
+            }
+
+            // Nor creating namespaces
+            let rows = run(r#"unshare -n true; echo "{\"status\": $?}""#, 1000).unwrap();
+            assert_ne!(rows[0]["status"], Value::Number(0.0));
+        }
+    }
+
+    #[test]
+    fn dedicated_user() {
+        let path = script("uid.sh", r#"echo "{\"uid\": $(id -u)}""#);
+        let mut root = ProcessModel::script("/bin/sh", &path);
+        root.set_limits(Limits {
+            user: None,
+            ..Limits::default()
+        });
+        let result = root.process(&info(1000), ChunkInput::Rows(vec![]));
+        if unsafe { libc::geteuid() } == 0 {
+            // Refuses to run as root
+            assert_eq!(
+                result,
+                Err(String::from(
+                    "refusing to run the program as root, set the uid and gid it runs as in Limits"
+                ))
+            );
+            let rows = model("/bin/sh", path)
+                .process(&info(1000), ChunkInput::Rows(vec![]))
+                .unwrap();
+            assert_eq!(rows[0]["uid"], Value::Number(65534.0));
+        } else {
+            assert!(result.is_ok());
+        }
+    }
+
+    #[test]
+    fn run_query() {
+        struct NoInput;
+        impl ChunkSource for NoInput {
+            fn input(&self, _: &ChunkInfo) -> Result<ChunkInput, String> {
+                Ok(ChunkInput::Rows(vec![]))
+            }
+        }
+        let path = script("count.sh", r#"echo '{"people": 2}'"#);
+        let query = build(
+            "SPLIT cam1 BEGIN 0 END 60 BY TIME 30sec INTO chunks1;
+            PROCESS chunks1 USING count.sh TIMEOUT 1sec PRODUCING 1 ROWS
+                WITH SCHEMA (people:NUMBER=0) INTO t1;
+            SELECT sum(people,l=0,u=10) FROM t1;",
+        )
+        .unwrap();
+        let mut registry = ModelRegistry::new();
+        register_scripts(
+            &mut registry,
+            &query,
+            "/bin/sh",
+            path.parent().unwrap(),
+            &limits(),
+        )
+        .unwrap();
+        let tables = process_tables(&query, &registry, &NoInput).unwrap();
+        assert_eq!(tables["t1"].len(), 2);
+        assert!(tables["t1"]
+            .iter()
+            .all(|r| r.outcome == ChunkOutcome::Completed
+                && r.output.rows[0]["people"] == Value::Number(2.0)));
+    }
+}
@@ -0,0 +1,310 @@
+/*
+ * The sensitivity calculation assumes that each chunk outputs at most `maxrow` rows matching the
+ * schema of its PROCESS statement. Models are untrusted, so their output is forced into that shape
+ * before it reaches the executor: extra rows are dropped, missing, mistyped or non-finite values
+ * are replaced by the schema defaults, unknown columns are removed and numbers are clamped to
+ * their declared range. Every change is reported as a Violation.
+ */
+use crate::execute::{ChunkRows, Row, Value};
+use crate::process::{Column, ProcessStatement};
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    // The chunk output more than `maxrow` rows, the extra rows were dropped
+    TooManyRows {
+        emitted: usize,
+        maxrow: u64,
+    },
+    // `row` is the index of the row in the chunk output
+    MissingColumn {
+        row: usize,
+        column: String,
+    },
+    WrongType {
+        row: usize,
+        column: String,
+    },
+    NotFinite {
+        row: usize,
+        column: String,
+    },
+    OutOfRange {
+        row: usize,
+        column: String,
+        value: f64,
+    },
+    // Columns that are not in the schema, or that are added by the executor (e.g. `chunk`)
+    UnknownColumn {
+        row: usize,
+        column: String,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::TooManyRows { emitted, maxrow } => write!(
+                f,
+                "chunk output {} rows but at most {} are allowed, extra rows were dropped",
+                emitted, maxrow
+            ),
+            Violation::MissingColumn { row, column } => {
+                write!(
+                    f,
+                    "row {}: missing column `{}`, using its default",
+                    row, column
+                )
+            }
+            Violation::WrongType { row, column } => write!(
+                f,
+                "row {}: column `{}` has the wrong type, using its default",
+                row, column
+            ),
+            Violation::NotFinite { row, column } => write!(
+                f,
+                "row {}: column `{}` is not a finite number, using its default",
+                row, column
+            ),
+            Violation::OutOfRange { row, column, value } => write!(
+                f,
+                "row {}: column `{}` is out of range ({}), clamping it",
+                row, column, value
+            ),
+            Violation::UnknownColumn { row, column } => {
+                write!(f, "row {}: unknown column `{}` was removed", row, column)
+            }
+        }
+    }
+}
+
+// Output of a chunk after sanitizing, and every problem found in it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sanitized {
+    pub rows: Vec<Row>,
+    pub violations: Vec<Violation>,
+}
+
+pub struct ChunkOutputSanitizer<'a> {
+    ps: &'a ProcessStatement,
+    // Range of NUMBER columns, by unqualified name
+    ranges: HashMap<String, (f64, f64)>,
+}
+
+impl<'a> ChunkOutputSanitizer<'a> {
+    pub fn new(ps: &'a ProcessStatement) -> Self {
+        ChunkOutputSanitizer {
+            ps,
+            ranges: HashMap::new(),
+        }
+    }
+
+    pub fn process(&self) -> &'a ProcessStatement {
+        self.ps
+    }
+
+    /// Declares the range of a NUMBER column, values outside of it are clamped
+    pub fn set_range(&mut self, column: &str, range: (f64, f64)) {
+        self.ranges.insert(column.to_owned(), range);
+    }
+
+    /// Sanitizes the rows output by a single chunk
+    pub fn sanitize(&self, rows: Vec<Row>) -> Sanitized {
+        let mut violations = vec![];
+        let emitted = rows.len();
+        if emitted as u64 > self.ps.maxrow {
+            violations.push(Violation::TooManyRows {
+                emitted,
+                maxrow: self.ps.maxrow,
+            });
+        }
+
+        let rows = rows
+            .into_iter()
+            .take(self.ps.maxrow as usize)
+            .enumerate()
+            .map(|(i, row)| self.sanitize_row(i, row, &mut violations))
+            .collect();
+        Sanitized { rows, violations }
+    }
+
+    /// Sanitizes the output of a chunk, returning it ready to be passed to the executor
+    pub fn sanitize_chunk(&self, chunk: u64, rows: Vec<Row>) -> (ChunkRows, Vec<Violation>) {
+        let Sanitized { rows, violations } = self.sanitize(rows);
+        (ChunkRows { chunk, rows }, violations)
+    }
+
+    fn sanitize_row(&self, i: usize, mut row: Row, violations: &mut Vec<Violation>) -> Row {
+        let mut out = Row::new();
+        for c in &self.ps.schema {
+            let (name, value) = match c {
+                Column::String(sc) => {
+                    let default = || Value::String(sc.default.clone());
+                    let value = match row.remove(&sc.name) {
+                        Some(v @ Value::String(_)) => v,
+                        Some(_) => {
+                            violations.push(Violation::WrongType {
+                                row: i,
+                                column: sc.name.clone(),
+                            });
+                            default()
+                        }
+                        None => {
+                            violations.push(Violation::MissingColumn {
+                                row: i,
+                                column: sc.name.clone(),
+                            });
+                            default()
+                        }
+                    };
+                    (&sc.name, value)
+                }
+                Column::Number(nc) => {
+                    let value = match row.remove(&nc.name) {
+                        Some(Value::Number(x)) if !x.is_finite() => {
+                            violations.push(Violation::NotFinite {
+                                row: i,
+                                column: nc.name.clone(),
+                            });
+                            nc.default
+                        }
+                        Some(Value::Number(x)) => x,
+                        Some(_) => {
+                            violations.push(Violation::WrongType {
+                                row: i,
+                                column: nc.name.clone(),
+                            });
+                            nc.default
+                        }
+                        None => {
+                            violations.push(Violation::MissingColumn {
+                                row: i,
+                                column: nc.name.clone(),
+                            });
+                            nc.default
+                        }
+                    };
+                    let value = match self.ranges.get(&nc.name) {
+                        Some((l, u)) if value < *l || value > *u => {
+                            violations.push(Violation::OutOfRange {
+                                row: i,
+                                column: nc.name.clone(),
+                                value,
+                            });
+                            value.max(*l).min(*u)
+                        }
+                        _ => value,
+                    };
+                    (&nc.name, Value::Number(value))
+                }
+                // Added by the executor, a model can't choose their value
+                Column::Fixed(_) => continue,
+            };
+            out.insert(name.clone(), value);
+        }
+
+        // Whatever is left is not part of the schema
+        let mut unknown: Vec<String> = row.into_keys().collect();
+        unknown.sort();
+        violations.extend(
+            unknown
+                .into_iter()
+                .map(|column| Violation::UnknownColumn { row: i, column }),
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::build;
+    use crate::sanitize::*;
+
+    fn process() -> ProcessStatement {
+        let query = build(
+            "SPLIT cam1 BEGIN 0 END 60 BY TIME 30sec INTO chunks1;
+            PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 2 ROWS
+                WITH SCHEMA (plate:STRING=null, speed:NUMBER=0) INTO t1;
+            SELECT count(plate) FROM t1;",
+        )
+        .unwrap();
+        query.process_stmts["t1"].clone()
+    }
+
+    fn row(values: Vec<(&str, Value)>) -> Row {
+        values.into_iter().map(|(k, v)| (k.to_owned(), v)).collect()
+    }
+
+    #[test]
+    fn valid_output() {
+        let ps = process();
+        let rows = vec![row(vec![
+            ("plate", Value::String(String::from("abc"))),
+            ("speed", Value::Number(30.0)),
+        ])];
+        let out = ChunkOutputSanitizer::new(&ps).sanitize(rows.clone());
+        assert_eq!(out.rows, rows);
+        assert!(out.violations.is_empty());
+    }
+
+    #[test]
+    fn malicious_output() {
+        let ps = process();
+        let mut sanitizer = ChunkOutputSanitizer::new(&ps);
+        sanitizer.set_range("speed", (0.0, 100.0));
+        let out = sanitizer.sanitize(vec![
+            row(vec![
+                ("plate", Value::Number(1.0)),
+                ("speed", Value::Number(1e9)),
+                ("chunk", Value::Number(0.0)),
+            ]),
+            row(vec![("speed", Value::Number(f64::NAN))]),
+            row(vec![("plate", Value::String(String::from("abc")))]),
+        ]);
+        assert_eq!(
+            out.rows,
+            vec![
+                row(vec![
+                    ("plate", Value::String(String::from("null"))),
+                    ("speed", Value::Number(100.0)),
+                ]),
+                row(vec![
+                    ("plate", Value::String(String::from("null"))),
+                    ("speed", Value::Number(0.0)),
+                ]),
+            ]
+        );
+        assert_eq!(
+            out.violations,
+            vec![
+                Violation::TooManyRows {
+                    emitted: 3,
+                    maxrow: 2
+                },
+                Violation::WrongType {
+                    row: 0,
+                    column: String::from("plate")
+                },
+                Violation::OutOfRange {
+                    row: 0,
+                    column: String::from("speed"),
+                    value: 1e9
+                },
+                Violation::UnknownColumn {
+                    row: 0,
+                    column: String::from("chunk")
+                },
+                Violation::MissingColumn {
+                    row: 1,
+                    column: String::from("plate")
+                },
+                Violation::NotFinite {
+                    row: 1,
+                    column: String::from("speed")
+                },
+            ]
+        );
+    }
+}
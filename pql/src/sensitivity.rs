@@ -23,32 +23,65 @@
 //}
 //
 //
+use crate::accounting::{Mechanism, PrivacyCost};
 use crate::aggregation::{AstNode, GroupByAttr};
-use crate::policy::{PolicyMap, PrivacyPolicy};
+use crate::parser::PQLQuery;
+use crate::policy::{budget_key, PolicyMap};
 use crate::process::{Column, ProcessStatement};
 use crate::split::SplitStatement;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
 
-type RowSensitivity = u64;
+pub type RowSensitivity = u64;
 // TODO eventually need to change this to range constarint and add a size constraint
 #[derive(Debug, Clone, PartialEq)]
-enum ColumnInfo {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ColumnInfo {
+    // Sensitivity of a value that is ready to be released
     Release(f64),
+    // Bounds on each value in the column
     Range((f64, f64)),
+    // Not a number (e.g. STRING columns and GROUP BY keys)
     Nan,
+    // A number with no known bounds
     Unbound,
 }
-type ColumnMap = HashMap<String, ColumnInfo>;
+pub type ColumnMap = HashMap<String, ColumnInfo>;
 
 // TODO add string explanation of how the sensitivity was computed for easy debugging
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TableSensitivity {
-    rows: RowSensitivity,
-    columns: ColumnMap,
-    size_constraint: Option<u64>,
+    // Maximum number of rows of the table an individual's event can impact
+    pub rows: RowSensitivity,
+    pub columns: ColumnMap,
+    // Number of rows the table is known to have, if any
+    pub size_constraint: Option<u64>,
 }
 impl TableSensitivity {
+    /// Sensitivity of releasing `column`, or None if the column doesn't exist or its values are
+    /// not bounded (in which case it can't be released)
+    pub fn release_sensitivity(&self, column: &str) -> Option<f64> {
+        match self.columns.get(column)? {
+            ColumnInfo::Release(x) => Some(*x),
+            ColumnInfo::Range((l, u)) => Some((u - l) * self.rows as f64),
+            ColumnInfo::Nan | ColumnInfo::Unbound => None,
+        }
+    }
+
+    /// L2 sensitivity of releasing `column`. For a range, each of the `rows` rows an individual
+    /// impacts changes by at most u - l, so the L2 norm of the change is (u - l) * sqrt(rows).
+    /// An aggregate's whole change can land on a single value, so it is the same as the L1 one.
+    pub fn release_l2_sensitivity(&self, column: &str) -> Option<f64> {
+        match self.columns.get(column)? {
+            ColumnInfo::Release(x) => Some(*x),
+            ColumnInfo::Range((l, u)) => Some((u - l) * (self.rows as f64).sqrt()),
+            ColumnInfo::Nan | ColumnInfo::Unbound => None,
+        }
+    }
+
     fn empty() -> Self {
         TableSensitivity {
             rows: 0,
@@ -56,45 +89,526 @@ impl TableSensitivity {
             size_constraint: Some(0),
         }
     }
+
+    fn with_rows(mut self, rows: RowSensitivity) -> Self {
+        self.rows = rows;
+        self
+    }
 }
 
-#[allow(dead_code, unused_variables)]
+// Rows of a union or intersection of `tables` an individual's event can impact. The impact on
+// each table adds up, except for tables reading different cameras of the same site: the event
+// is at most k segments across the whole site, each seen by a single camera, so its impact is at
+// most that on the table it impacts the most. A segment seen by two cameras of a site at once
+// counts as one segment for each camera.
+fn combined_rows(
+    tables: &[AstNode],
+    sens: &[TableSensitivity],
+    policies: &PolicyMap,
+) -> RowSensitivity {
+    let mut rows = 0;
+    // Cameras and rows of the tables reading a single site, by site
+    let mut sites: HashMap<String, Vec<(Vec<String>, RowSensitivity)>> = HashMap::new();
+    for (t, s) in tables.iter().zip(sens) {
+        let cameras = t.cameras();
+        let mut keys: Vec<String> = cameras.iter().map(|c| budget_key(c, policies)).collect();
+        keys.sort();
+        keys.dedup();
+        let site = match keys.as_slice() {
+            [key] if policies.get(&cameras[0]).and_then(|p| p.site()).is_some() => key.clone(),
+            _ => {
+                rows += s.rows;
+                continue;
+            }
+        };
+        sites.entry(site).or_default().push((cameras, s.rows));
+    }
+    for tables in sites.values() {
+        // Two tables reading the same camera both see its segments
+        let disjoint = tables.iter().enumerate().all(|(i, (a, _))| {
+            tables[i + 1..]
+                .iter()
+                .all(|(b, _)| a.iter().all(|c| !b.contains(c)))
+        });
+        rows += if disjoint {
+            tables.iter().map(|(_, r)| *r).max().unwrap_or(0)
+        } else {
+            tables.iter().map(|(_, r)| *r).sum()
+        };
+    }
+    rows
+}
+
+/// Number of rows of the table output by `process_stmt` that a single individual can appear in.
+/// Fails if the split has no chunk length or the number doesn't fit in a u64, which validation
+/// reports before sensitivity_composition is called.
 pub fn table_sensitivity(
     split_stmt: &SplitStatement,
     process_stmt: &ProcessStatement,
     policies: &PolicyMap,
-) -> u64 {
-    let policy = policies.get(&split_stmt.camera_name).expect(
-        format!(
+) -> Result<u64, String> {
+    let policy = policies.get(&split_stmt.camera_name).unwrap_or_else(|| {
+        panic!(
             "policymap does not contain policy for camera '{}'",
             split_stmt.camera_name
         )
-        .as_str(),
-    );
-    match policy {
-        PrivacyPolicy::Static {
-            k_segments,
-            epsilon,
-            rho_ms,
-        } => {
-            let chunks =
-                1 + (rho_ms.clone() as f64 / split_stmt.chunk_length_ms as f64).ceil() as u64;
-            let rows = process_stmt.maxrow * k_segments * chunks;
-            rows
-        }
-        PrivacyPolicy::Mask {} => {
+    });
+    if split_stmt.chunk_length_ms == 0 {
+        return Err(format!(
+            "split `{}` has a chunk length of 0",
+            split_stmt.output_name
+        ));
+    }
+    let overflow = || {
+        format!(
+            "the rows of table `{}` an individual can appear in don't fit in 64 bits",
+            process_stmt.output_table_name
+        )
+    };
+    // Scheduled policies are bounded by the times the split covers
+    let start_ms = split_stmt
+        .start_time
+        .checked_mul(1000)
+        .ok_or_else(overflow)?;
+    let end_ms = split_stmt.end_time.checked_mul(1000).ok_or_else(overflow)?;
+    match policy.segment_bounds(start_ms, end_ms) {
+        Some((k_segments, rho_ms)) => rho_ms
+            .div_ceil(split_stmt.chunk_length_ms)
+            .checked_add(1)
+            .and_then(|chunks| process_stmt.maxrow.checked_mul(chunks))
+            .and_then(|rows| rows.checked_mul(k_segments))
+            .ok_or_else(overflow),
+        None => {
             unimplemented!("mask privacy policies not implemented yet");
         }
     }
 }
 
+/// Epsilon that releasing the result of `node` must satisfy, i.e. the smallest epsilon among the
+/// policies of the cameras it reads
+pub fn release_epsilon(node: &AstNode, policies: &PolicyMap) -> Option<f64> {
+    node.cameras()
+        .iter()
+        .filter_map(|c| policies.get(c).and_then(|p| p.epsilon()))
+        .fold(None, |acc: Option<f64>, e| {
+            Some(acc.map_or(e, |acc| acc.min(e)))
+        })
+}
+
+// Sensitivity of a single column released by a statement
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ColumnRelease {
+    pub name: String,
+    // None if the column is not bounded, so it can only be released as the key of other columns
+    pub sensitivity: Option<f64>,
+    // For the Gaussian mechanism
+    pub l2_sensitivity: Option<f64>,
+    // Share of the statement's epsilon spent on releasing the column
+    pub epsilon: Option<f64>,
+    // Scale of the Laplace noise that must be added to release the column, sensitivity / epsilon
+    pub noise_scale: Option<f64>,
+}
+
+// Everything needed to release the result of one SELECT statement
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StatementSensitivity {
+    // The statement, formatted as canonical PQL
+    pub query: String,
+    pub cameras: Vec<String>,
+    // Total epsilon spent by releasing the statement, split between its columns
+    pub epsilon: Option<f64>,
+    pub rows: RowSensitivity,
+    pub columns: Vec<ColumnRelease>,
+    // Sensitivity of every bounded column together, as a single vector. Releasing the whole
+    // output at once with noise scaled to these costs epsilon once, however many columns it has.
+    pub l1_sensitivity: Option<f64>,
+    pub l2_sensitivity: Option<f64>,
+    // Content hash of the policy of each camera read (see PrivacyPolicy::content_hash), to audit
+    // which version of the policies the result depends on
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub policy_hashes: BTreeMap<String, String>,
+}
+
+/// Computes the sensitivity and noise scale of each column output by `stmt`, when it is the only
+/// statement released. Its epsilon is set by WITH EPSILON, or is the policy epsilon otherwise. The
+/// statement should have been validated first.
+pub fn statement_sensitivity(stmt: &AstNode, policies: &PolicyMap) -> StatementSensitivity {
+    let epsilon = release_epsilon(stmt, policies).map(|e| stmt.statement_epsilon().unwrap_or(e));
+    release_sensitivity(stmt, policies, epsilon)
+}
+
+pub(crate) fn release_sensitivity(
+    stmt: &AstNode,
+    policies: &PolicyMap,
+    epsilon: Option<f64>,
+) -> StatementSensitivity {
+    let res = sensitivity_composition(stmt, policies);
+    let released: Vec<String> = stmt
+        .output_columns()
+        .into_iter()
+        .filter(|name| res.release_sensitivity(name).is_some())
+        .collect();
+    let shares = epsilon.map(|e| allocate_columns(stmt, &released, e));
+    let columns: Vec<ColumnRelease> = stmt
+        .output_columns()
+        .into_iter()
+        .map(|name| {
+            let sensitivity = res.release_sensitivity(&name);
+            let epsilon = shares.as_ref().and_then(|s| s.get(&name).copied());
+            let noise_scale = match (sensitivity, epsilon) {
+                (Some(s), Some(e)) => Some(s / e),
+                _ => None,
+            };
+            ColumnRelease {
+                l2_sensitivity: res.release_l2_sensitivity(&name),
+                name,
+                sensitivity,
+                epsilon,
+                noise_scale,
+            }
+        })
+        .collect();
+    let (l1_sensitivity, l2_sensitivity) = vector_sensitivity(&columns);
+    StatementSensitivity {
+        query: stmt.to_pql(),
+        cameras: stmt.cameras(),
+        epsilon,
+        rows: res.rows,
+        columns,
+        l1_sensitivity,
+        l2_sensitivity,
+        policy_hashes: stmt
+            .cameras()
+            .into_iter()
+            .filter_map(|c| {
+                let hash = policies.get(&c)?.content_hash();
+                Some((c, hash))
+            })
+            .collect(),
+    }
+}
+
+// Splits the epsilon of a statement between its `released` columns. Columns with EPSILON get
+// exactly that, and the others equally share the rest, so the shares add up to `epsilon`.
+fn allocate_columns(stmt: &AstNode, released: &[String], epsilon: f64) -> HashMap<String, f64> {
+    let explicit = stmt.column_epsilons();
+    let set: f64 = released.iter().filter_map(|c| explicit.get(c)).sum();
+    let unset = released
+        .iter()
+        .filter(|c| !explicit.contains_key(*c))
+        .count();
+    released
+        .iter()
+        .map(|c| {
+            let share = match explicit.get(c) {
+                Some(e) => *e,
+                None => (epsilon - set) / unset as f64,
+            };
+            (c.clone(), share)
+        })
+        .collect()
+}
+
+/// Budget keys (see policy::budget_key) of the cameras read by `stmt`, with the policy epsilon of
+/// each. Cameras without a policy are left out.
+pub fn statement_budgets(stmt: &AstNode, policies: &PolicyMap) -> Vec<(String, f64)> {
+    let mut budgets: Vec<(String, f64)> = stmt
+        .cameras()
+        .iter()
+        .filter_map(|c| Some((budget_key(c, policies), policies.get(c)?.epsilon()?)))
+        .collect();
+    budgets.sort_by(|a, b| a.0.cmp(&b.0));
+    budgets.dedup_by(|a, b| a.0 == b.0);
+    budgets
+}
+
+/// Epsilon of each SELECT in `query`, in order. Statements with WITH EPSILON get exactly that,
+/// while the others equally share what is left of the policy epsilon of every camera (or site)
+/// they read, so the statements reading a camera never spend more than its policy epsilon in
+/// total. None for statements reading a camera without a policy.
+pub fn allocate_epsilon(query: &PQLQuery, policies: &PolicyMap) -> Vec<Option<f64>> {
+    // Epsilon left for the statements without WITH EPSILON, and how many of them read each camera
+    let mut left: HashMap<String, (f64, usize)> = HashMap::new();
+    for stmt in &query.select_stmts {
+        for (key, epsilon) in statement_budgets(stmt, policies) {
+            let entry = left.entry(key).or_insert((epsilon, 0));
+            match stmt.statement_epsilon() {
+                Some(e) => entry.0 -= e,
+                None => entry.1 += 1,
+            }
+        }
+    }
+    query
+        .select_stmts
+        .iter()
+        .map(|stmt| {
+            release_epsilon(stmt, policies)?;
+            stmt.statement_epsilon().or_else(|| {
+                statement_budgets(stmt, policies)
+                    .iter()
+                    .filter_map(|(key, _)| left.get(key))
+                    .map(|(e, n)| e / *n as f64)
+                    .fold(None, |acc: Option<f64>, e| {
+                        Some(acc.map_or(e, |acc| acc.min(e)))
+                    })
+            })
+        })
+        .collect()
+}
+
+// An individual can change every column at once, so the L1 sensitivities add up, while the L2
+// sensitivity is the norm of the per-column ones. None if no column can be released.
+fn vector_sensitivity(columns: &[ColumnRelease]) -> (Option<f64>, Option<f64>) {
+    let bounded: Vec<&ColumnRelease> = columns.iter().filter(|c| c.sensitivity.is_some()).collect();
+    if bounded.is_empty() {
+        return (None, None);
+    }
+    let l1 = bounded.iter().filter_map(|c| c.sensitivity).sum();
+    let l2 = bounded
+        .iter()
+        .filter_map(|c| c.l2_sensitivity)
+        .map(|s| s * s)
+        .sum::<f64>()
+        .sqrt();
+    (Some(l1), Some(l2))
+}
+
+/// Sensitivity of every SELECT in `query`, in order, with the policy epsilon of each camera split
+/// between the statements reading it (see allocate_epsilon)
+pub fn query_sensitivity(query: &PQLQuery, policies: &PolicyMap) -> Vec<StatementSensitivity> {
+    query
+        .select_stmts
+        .iter()
+        .zip(allocate_epsilon(query, policies))
+        .map(|(stmt, epsilon)| release_sensitivity(stmt, policies, epsilon))
+        .collect()
+}
+
+/// Cost charged to each camera by releasing every statement in `statements` with `mechanism`: one
+/// cost per statement reading the camera, to be composed by the accountant of the ledger. Cameras
+/// of a site are charged to the site instead, once per statement however many of them it reads.
+pub fn query_costs(
+    statements: &[StatementSensitivity],
+    policies: &PolicyMap,
+    mechanism: Mechanism,
+) -> HashMap<String, Vec<PrivacyCost>> {
+    let mut costs: HashMap<String, Vec<PrivacyCost>> = HashMap::new();
+    for s in statements {
+        // Statements reading a camera without a policy can't be released anyway
+        if let Some(epsilon) = s.epsilon {
+            let mut keys: Vec<String> = s.cameras.iter().map(|c| budget_key(c, policies)).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                costs.entry(key).or_default().push(mechanism.cost(epsilon));
+            }
+        }
+    }
+    costs
+}
+
+/// Most rows `node` can output. Each chunk outputs at most `maxrow` rows, and a SELECT outputs
+/// at most one row per group (see `select_rows`). None if unknown, e.g. for joins.
+pub fn size_constraint(node: &AstNode) -> Option<u64> {
+    match node {
+        AstNode::Table(ss, ps) => ps.maxrow.checked_mul(ss.num_chunks()),
+        AstNode::Tables(tables, _) => tables.first().and_then(size_constraint),
+        // Both keep rows from every table, INTERSECT only drops some of them
+        AstNode::Union { tables, .. } | AstNode::Intersect { tables, .. } => tables
+            .iter()
+            .try_fold(0u64, |acc, t| acc.checked_add(size_constraint(t)?)),
+        AstNode::Select {
+            exprs,
+            from,
+            qualifiers,
+            ..
+        } => select_rows(exprs, from, qualifiers),
+        _ => None,
+    }
+}
+
+// Rows of `from` left after the LIMITs in `qualifiers`
+fn limited_rows(from: &AstNode, qualifiers: &[AstNode]) -> Option<u64> {
+    qualifiers
+        .iter()
+        .fold(size_constraint(from), |rows, q| match q {
+            AstNode::Limit { rows: n, .. } => Some(rows.map_or(*n, |r| r.min(*n))),
+            _ => rows,
+        })
+}
+
+// Rows output by a SELECT: one per listed key of GROUP BY .. WITH KEYS, one per bin of a GROUP BY
+// over bins only, a single one when aggregating without GROUP BY, and otherwise at most the rows
+// of its table
+fn select_rows(exprs: &[AstNode], from: &AstNode, qualifiers: &[AstNode]) -> Option<u64> {
+    let mut grouped = false;
+    for q in qualifiers {
+        match q {
+            AstNode::GroupByWithKeys { keys, .. } => return Some(keys.len() as u64),
+            AstNode::GroupBy { attrs, .. } => {
+                grouped = true;
+                let bins = attrs.iter().try_fold(1u64, |acc, attr| match attr {
+                    GroupByAttr::Bin { column, size, .. } => {
+                        let bins = bin_range(from, column, *size)?;
+                        acc.checked_mul(bins.end - bins.start)
+                    }
+                    GroupByAttr::Column(_) => None,
+                });
+                if bins.is_some() {
+                    return bins;
+                }
+            }
+            _ => {}
+        }
+    }
+    let aggregating = exprs
+        .iter()
+        .any(|e| matches!(e, AstNode::Aggregation { .. }));
+    if aggregating && !grouped {
+        return Some(1);
+    }
+    limited_rows(from, qualifiers)
+}
+
+/// Number of rows each aggregation of a SELECT is computed over, in each group. mean() divides by
+/// this number rather than by the number of rows it actually sees, so that its sensitivity is
+/// known: the executor truncates or pads the aggregated values to exactly this many.
+pub fn aggregated_rows(from: &AstNode, qualifiers: &[AstNode]) -> Option<u64> {
+    let mut rows = size_constraint(from);
+    for q in qualifiers {
+        match q {
+            AstNode::GroupBy { attrs, .. } => {
+                for attr in attrs {
+                    if let GroupByAttr::Bin { column, size, .. } = attr {
+                        rows = match (rows, bin_rows(from, column, *size)) {
+                            (Some(r), Some(b)) => Some(r.min(b)),
+                            (r, b) => r.or(b),
+                        };
+                    }
+                }
+            }
+            AstNode::Limit { rows: n, .. } => rows = Some(*n),
+            _ => {}
+        }
+    }
+    rows
+}
+
+/// Every key of `bin(column, size)` over the output of `node`, i.e. `floor(start / size)` for the
+/// start of each chunk of the splits the time column comes from (even those without any row), so
+/// that the bins don't depend on the data. None if `column` isn't a time column.
+pub fn bin_range(node: &AstNode, column: &str, size: u64) -> Option<Range<u64>> {
+    if size == 0 {
+        return None;
+    }
+    match node {
+        AstNode::Table(ss, ps) => {
+            ps.schema
+                .iter()
+                .find(|c| matches!(c, Column::Fixed(fc) if fc.name == column))?;
+            let n = ss.num_chunks();
+            if n == 0 {
+                return Some(0..0);
+            }
+            let (_, first, _) = ss.chunk(0)?;
+            let (_, last, _) = ss.chunk(n - 1)?;
+            Some(first / size..last / size + 1)
+        }
+        AstNode::Tables(tables, _) => tables.first().and_then(|t| bin_range(t, column, size)),
+        AstNode::Union { tables, .. } | AstNode::Intersect { tables, .. } => tables
+            .iter()
+            .filter_map(|t| bin_range(t, column, size))
+            .filter(|r| !r.is_empty())
+            .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end)),
+        // Only a time column selected as it is keeps its values
+        AstNode::Select { exprs, from, .. } => exprs
+            .iter()
+            .any(|e| matches!(e, AstNode::Column(c, _) if c == column))
+            .then(|| bin_range(from, column, size))
+            .flatten(),
+        _ => None,
+    }
+}
+
+// Most rows of `node` in a single bin of `bin(column, size)`: the chunks starting within `size`
+// of each other, or None if unknown
+fn bin_rows(node: &AstNode, column: &str, size: u64) -> Option<u64> {
+    match node {
+        AstNode::Table(ss, ps) => {
+            bin_range(node, column, size)?;
+            let chunks = match ss.chunk_length_ms {
+                0 => 0,
+                len => size.div_ceil(len).min(ss.num_chunks()),
+            };
+            ps.maxrow.checked_mul(chunks)
+        }
+        AstNode::Tables(tables, _) => tables.first().and_then(|t| bin_rows(t, column, size)),
+        AstNode::Union { tables, .. } | AstNode::Intersect { tables, .. } => tables
+            .iter()
+            .try_fold(0u64, |acc, t| acc.checked_add(bin_rows(t, column, size)?)),
+        _ => None,
+    }
+}
+
+/// Range of the values of `column` in the output of `node`, if it doesn't depend on the data:
+/// the fixed `chunk` column, bin() keys and USER_ outputs with explicit bounds
+pub fn column_range(node: &AstNode, column: &str) -> Option<(f64, f64)> {
+    match node {
+        AstNode::Table(_, ps) => ps.schema.iter().find_map(|c| match c {
+            Column::Fixed(fc) if fc.name == column => {
+                let (s, e) = fc.range;
+                Some((s as f64 * 1000.0, e as f64 * 1000.0))
+            }
+            _ => None,
+        }),
+        AstNode::Tables(tables, _) => tables.first().and_then(|t| column_range(t, column)),
+        AstNode::Union { tables, .. }
+        | AstNode::Intersect { tables, .. }
+        | AstNode::Equijoin { tables, .. } => tables.iter().find_map(|t| column_range(t, column)),
+        AstNode::Select {
+            exprs,
+            from,
+            qualifiers,
+            ..
+        } => exprs.iter().find_map(|e| match e {
+            AstNode::Column(c, _) if c == column => {
+                let bin = qualifiers.iter().find_map(|q| match q {
+                    AstNode::GroupBy { attrs, .. } => attrs.iter().find_map(|a| match a {
+                        GroupByAttr::Bin {
+                            column: binned,
+                            size,
+                            alias,
+                        } if alias == column => Some((binned, *size)),
+                        _ => None,
+                    }),
+                    _ => None,
+                });
+                match bin {
+                    Some((binned, size)) => bin_range(from, binned, size).map(bin_bounds),
+                    None => column_range(from, c),
+                }
+            }
+            AstNode::UserF { alias, range, .. } if alias == column => *range,
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+// Smallest and largest key of `bins`
+fn bin_bounds(bins: Range<u64>) -> (f64, f64) {
+    (bins.start as f64, bins.end.max(bins.start + 1) as f64 - 1.0)
+}
+
 // TODO also output the number of rows the table will have to provide an accuracy bound
 // when composing tables need to propogate this value
 #[allow(dead_code, unused_variables)]
 pub fn sensitivity_composition(node: &AstNode, policies: &PolicyMap) -> TableSensitivity {
     match node {
         AstNode::Table(ss, ps) => TableSensitivity {
-            rows: table_sensitivity(ss, ps, policies),
+            rows: table_sensitivity(ss, ps, policies).unwrap_or_else(|e| panic!("{}", e)),
             columns: ps
                 .schema
                 .iter()
@@ -118,58 +632,68 @@ pub fn sensitivity_composition(node: &AstNode, policies: &PolicyMap) -> TableSen
                     }
                 })
                 .collect::<ColumnMap>(),
-            size_constraint: {
-                let video_length_ms = ((ss.end_time - ss.start_time) * 1000) as f64;
-                let num_chunks = video_length_ms / ss.chunk_length_ms as f64;
-                Some(num_chunks.ceil() as u64)
-            },
+            size_constraint: size_constraint(node),
         },
-        AstNode::Tables(tables) => {
+        AstNode::Tables(tables, _) => {
             // TODO temp hack
-            sensitivity_composition(tables.get(0).unwrap(), policies)
+            sensitivity_composition(tables.first().unwrap(), policies)
         }
-        AstNode::Intersect { tables, on } => tables
-            .iter()
-            .map(|t| sensitivity_composition(t, policies))
-            .fold(TableSensitivity::empty(), |mut acc, s| {
-                acc.rows += s.rows;
-                acc.columns.extend(s.columns);
-                acc.size_constraint = std::cmp::max(acc.size_constraint, s.size_constraint);
-                acc
-            }),
-        AstNode::Union { tables, on } => {
-            tables
+        AstNode::Intersect { tables, on, .. } => {
+            let sens: Vec<TableSensitivity> = tables
                 .iter()
                 .map(|t| sensitivity_composition(t, policies))
+                .collect();
+            let rows = combined_rows(tables, &sens, policies);
+            let mut ret = sens
+                .into_iter()
+                .fold(TableSensitivity::empty(), |mut acc, s| {
+                    acc.columns.extend(s.columns);
+                    acc
+                })
+                .with_rows(rows);
+            ret.size_constraint = size_constraint(node);
+            ret
+        }
+        AstNode::Union { tables, on, .. } => {
+            let sens: Vec<TableSensitivity> = tables
+                .iter()
+                .map(|t| sensitivity_composition(t, policies))
+                .collect();
+            let rows = combined_rows(tables, &sens, policies);
+            let mut ret = sens
+                .into_iter()
                 .fold(TableSensitivity::empty(), |mut acc, s| {
-                    acc.rows += s.rows;
                     acc.columns.extend(s.columns);
-                    // TODO assuming tables cover the same time range for now, in the future need
-                    // to compute the actual size of the table based on the set of time ranges
-                    // covered across all tables
-                    acc.size_constraint = std::cmp::max(acc.size_constraint, s.size_constraint);
-                    // acc.size_constraint = match (acc.size_constraint, s.size_constraint) {
-                    //     (None, _) => None,
-                    //     (_, None) => None,
-                    //     (Some(a), Some(sc)) => Some(a + sc),
-                    // };
                     // TODO chunk column
                     acc
                 })
+                .with_rows(rows);
+            // The rows of every table, see size_constraint
+            ret.size_constraint = size_constraint(node);
+            ret
         }
-        AstNode::Equijoin { tables, on } => tables
+        // The executor joins each key once, so each row of an individual changes at most one
+        // joined row
+        AstNode::Equijoin { tables, on, .. } => tables
             .iter()
             .map(|t| sensitivity_composition(t, policies))
-            .fold(TableSensitivity::empty(), |mut acc, s| {
-                acc.rows += s.rows;
-                acc.columns.extend(s.columns);
-                // TODO chunk column
-                acc
-            }),
+            .fold(
+                TableSensitivity {
+                    size_constraint: size_constraint(node),
+                    ..TableSensitivity::empty()
+                },
+                |mut acc, s| {
+                    acc.rows += s.rows;
+                    acc.columns.extend(s.columns);
+                    // TODO chunk column
+                    acc
+                },
+            ),
         AstNode::Select {
             exprs,
             from,
             qualifiers,
+            ..
         } => {
             let mut base = sensitivity_composition(from, policies);
 
@@ -181,7 +705,7 @@ pub fn sensitivity_composition(node: &AstNode, policies: &PolicyMap) -> TableSen
 
             for q in qualifiers {
                 match q {
-                    AstNode::GroupBy { attrs } => {
+                    AstNode::GroupBy { attrs, .. } => {
                         let mut table_to_col: HashMap<String, Vec<String>> = HashMap::new();
                         for (name, info) in &base.columns {
                             if name.contains(".") {
@@ -210,41 +734,56 @@ pub fn sensitivity_composition(node: &AstNode, policies: &PolicyMap) -> TableSen
                                     size,
                                     alias,
                                 } => {
-                                    let prev = match base.columns.get(column) {
-                                        Some(ColumnInfo::Range((l, u))) => (u - l),
-                                        _ => unreachable!("chunk should be range..."),
-                                    };
+                                    let bins =
+                                        bin_range(from, column, *size).unwrap_or_else(|| {
+                                            unreachable!("chunk should be range...")
+                                        });
                                     base.columns.insert(
                                         alias.to_owned(),
-                                        ColumnInfo::Range((0.0, prev / *size as f64)),
+                                        ColumnInfo::Range(bin_bounds(bins)),
                                     );
-                                    // TODO TEMP HACK
-                                    base.size_constraint = Some((prev / *size as f64) as u64);
-                                    ret.size_constraint = Some((prev / *size as f64) as u64);
                                 }
                             }
                         }
                     }
-                    AstNode::Limit { rows } => {
-                        // TODO TEMP HACK
-                        base.size_constraint = Some(*rows);
+                    // The listed keys are released as they are
+                    AstNode::GroupByWithKeys { attr, .. } => {
+                        base.columns.insert(attr.to_owned(), ColumnInfo::Nan);
                     }
+                    // Only drops rows, so an individual doesn't change more of them, and the
+                    // size constraint is still an upper bound
+                    AstNode::Where { .. } => {}
+                    // Only changes the number of rows, see aggregated_rows
+                    AstNode::Limit { .. } => {}
+                    // Only changes how much noise is added
+                    AstNode::Epsilon(..) => {}
                     _ => {
-                        unimplemented!("this qualifier not implemented yet");
+                        let span = q.span();
+                        unimplemented!(
+                            "this qualifier not implemented yet (at {}:{})",
+                            span.line,
+                            span.col
+                        );
                     }
                 }
             }
 
+            base.size_constraint = aggregated_rows(from, qualifiers);
+            ret.size_constraint = base.size_constraint;
+
             for expr in exprs {
                 match expr {
                     // Pull from the base table
-                    AstNode::Column(c) => {
+                    AstNode::Column(c, span) => {
                         ret.columns.insert(
                             c.clone(),
                             base.columns
                                 .get(c)
                                 .unwrap_or_else(|| {
-                                    panic!("{} does not exist in {:#?}, base: {:#?}", c, from, base)
+                                    panic!(
+                                        "{} (at {}:{}) does not exist in {:#?}, base: {:#?}",
+                                        c, span.line, span.col, from, base
+                                    )
                                 })
                                 .to_owned(),
                         );
@@ -255,12 +794,13 @@ pub fn sensitivity_composition(node: &AstNode, policies: &PolicyMap) -> TableSen
                         column,
                         range,
                         alias,
+                        ..
                     } => {
                         ret.columns.insert(
                             alias.clone(),
                             range
                                 .to_owned()
-                                .map_or(ColumnInfo::Unbound, |r| ColumnInfo::Range(r)),
+                                .map_or(ColumnInfo::Unbound, ColumnInfo::Range),
                         );
                     }
                     AstNode::Aggregation {
@@ -268,6 +808,7 @@ pub fn sensitivity_composition(node: &AstNode, policies: &PolicyMap) -> TableSen
                         inner,
                         range: explicit_range,
                         alias,
+                        ..
                     } => match function.to_lowercase().as_str() {
                         "sum" => {
                             let res = explicit_range.map_or(ColumnInfo::Unbound, |(l, u)| {
@@ -283,26 +824,16 @@ pub fn sensitivity_composition(node: &AstNode, policies: &PolicyMap) -> TableSen
                                         .unwrap_or(&ColumnInfo::Unbound)
                                         .to_owned()
                                 },
-                                |r| ColumnInfo::Range(r),
+                                ColumnInfo::Range,
                             );
-                            let res = match range {
-                                ColumnInfo::Range((l, u)) => {
-                                    base.size_constraint.map_or(range, |sc| {
-                                        println!(
-                                            "alias: {}, range: ({},{}), rows: {}, sc: {}",
-                                            alias, l, u, base.rows, sc,
-                                        );
-                                        ColumnInfo::Release((u - l) * base.rows as f64 / sc as f64)
-                                    })
+                            // The executor divides by the size constraint, so one changed row
+                            // moves the mean by at most (u - l) / size constraint
+                            let res = match (range, base.size_constraint) {
+                                (ColumnInfo::Range((l, u)), Some(sc)) if sc > 0 => {
+                                    ColumnInfo::Release((u - l) * base.rows as f64 / sc as f64)
                                 }
-                                ColumnInfo::Release(r) => {
-                                    base.size_constraint.map_or(range, |sc| {
-                                        println!(
-                                            "alias: {}, release: {}, rows: {}, sc: {}",
-                                            alias, r, base.rows, sc,
-                                        );
-                                        ColumnInfo::Release(r / sc as f64)
-                                    })
+                                (ColumnInfo::Release(r), Some(sc)) if sc > 0 => {
+                                    ColumnInfo::Release(r / sc as f64)
                                 }
                                 _ => ColumnInfo::Unbound,
                             };
@@ -353,7 +884,6 @@ pub fn sensitivity_composition(node: &AstNode, policies: &PolicyMap) -> TableSen
             //         }
             //     };
             // }
-            println!("ret: {:#?}", ret);
             ret
         }
         //AstNode::AliasExpr {
@@ -647,6 +1177,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn where_clause() {
+        // Filtering rows doesn't change how many an individual can appear in
+        check_result(
+            table_one(),
+            policies(),
+            "SELECT sum(speed,l=0,u=10) FROM table1 WHERE (speed > 5) && (chunk < 30000);",
+            700.0,
+        );
+    }
+
     #[test]
     fn simple_union() {
         check_result(
@@ -657,6 +1198,183 @@ mod tests {
         );
     }
 
+    #[test]
+    fn union_release() {
+        let query_string = format!(
+            "{} {} {}",
+            table_one(),
+            table_two(),
+            "SELECT count(plate) FROM (table1 UNION table2 ON plate);"
+        );
+        let query = crate::parser::build(query_string.as_str()).unwrap();
+        let stmt = query.select_stmts.first().unwrap();
+        let res = sensitivity_composition(stmt, &policies());
+        assert_eq!(res.release_sensitivity("count(plate)"), Some(132.0));
+        assert_eq!(res.release_sensitivity("plate"), None);
+
+        let mut policies = policies();
+        policies.insert(
+            String::from("cam2"),
+            PrivacyPolicy::Static {
+                k_segments: 1,
+                rho_ms: 30_000,
+                epsilon: 0.5,
+            },
+        );
+        assert_eq!(release_epsilon(stmt, &policies), Some(0.5));
+    }
+
+    #[test]
+    fn epsilon_allocation() {
+        let query_string = format!(
+            "{} {} {}",
+            table_one(),
+            table_two(),
+            "SELECT count(plate) EPSILON 0.1, sum(speed,l=0,u=10), count(speed) FROM table1 WITH EPSILON 0.5;
+            SELECT count(plate) FROM (table1 UNION table2 ON plate);
+            SELECT count(plate) FROM table2;"
+        );
+        let query = crate::parser::build(query_string.as_str()).unwrap();
+        // cam1 has 0.5 left for the second statement, which also limits its epsilon for cam2
+        assert_eq!(
+            allocate_epsilon(&query, &policies()),
+            vec![Some(0.5), Some(0.5), Some(0.5)]
+        );
+
+        let sens = query_sensitivity(&query, &policies());
+        let epsilons: Vec<Option<f64>> = sens[0].columns.iter().map(|c| c.epsilon).collect();
+        assert_eq!(epsilons, vec![Some(0.1), Some(0.2), Some(0.2)]);
+        assert_eq!(sens[0].columns[1].noise_scale, Some(3500.0));
+        assert_eq!(sens[1].columns[0].noise_scale, Some(264.0));
+
+        // Alone, a statement without WITH EPSILON gets the whole policy epsilon
+        let alone = statement_sensitivity(&query.select_stmts[2], &policies());
+        assert_eq!(alone.epsilon, Some(1.0));
+    }
+
+    #[test]
+    fn costs_per_camera() {
+        let query_string = format!(
+            "{} {} {}",
+            table_one(),
+            table_two(),
+            "SELECT count(plate) FROM table1 WITH EPSILON 0.2;
+            SELECT count(plate) FROM (table1 UNION table2 ON plate);
+            SELECT sum(speed,l=0,u=10) FROM table2;"
+        );
+        let query = crate::parser::build(query_string.as_str()).unwrap();
+        let sens = query_sensitivity(&query, &policies());
+        let mut costs: Vec<(String, Vec<f64>)> =
+            query_costs(&sens, &policies(), Mechanism::Laplace)
+                .into_iter()
+                .map(|(camera, costs)| {
+                    let epsilons = costs
+                        .iter()
+                        .map(|c| match c {
+                            PrivacyCost::Pure { epsilon } => *epsilon,
+                            PrivacyCost::Concentrated { .. } => unreachable!(),
+                        })
+                        .collect();
+                    (camera, epsilons)
+                })
+                .collect();
+        costs.sort_by(|a, b| a.0.cmp(&b.0));
+        // Every statement is charged to each camera it reads, adding up to at most its epsilon
+        assert_eq!(
+            costs,
+            vec![
+                (String::from("cam1"), vec![0.2, 0.5]),
+                (String::from("cam2"), vec![0.5, 0.5]),
+            ]
+        );
+    }
+
+    #[test]
+    fn site_union() {
+        let mut policies = policies();
+        for camera in ["cam1", "cam2"] {
+            policies.insert(
+                String::from(camera),
+                PrivacyPolicy::Site {
+                    site: String::from("street"),
+                    policy: Box::new(policies[camera].clone()),
+                },
+            );
+        }
+        let query_string = format!(
+            "{} {} {}",
+            table_one(),
+            table_two(),
+            "SELECT count(plate) FROM (table1 UNION table2 ON plate);
+            SELECT count(plate) FROM (table1 UNION table1 ON plate);
+            SELECT count(plate) FROM table2;"
+        );
+        let query = crate::parser::build(query_string.as_str()).unwrap();
+        // The event is seen by one camera of the site at a time, max(70, 62) rather than 70 + 62,
+        // but a camera read twice still counts twice
+        let counts: Vec<Option<f64>> = query
+            .select_stmts
+            .iter()
+            .map(|stmt| {
+                sensitivity_composition(stmt, &policies).release_sensitivity("count(plate)")
+            })
+            .collect();
+        assert_eq!(counts, vec![Some(70.0), Some(140.0), Some(62.0)]);
+
+        // The statements share the epsilon of the site, and are charged to it
+        assert_eq!(
+            allocate_epsilon(&query, &policies),
+            vec![Some(1.0 / 3.0); 3]
+        );
+        let sens = query_sensitivity(&query, &policies);
+        let costs = query_costs(&sens, &policies, Mechanism::Laplace);
+        assert_eq!(costs.keys().collect::<Vec<_>>(), vec!["street"]);
+        assert_eq!(costs["street"].len(), 3);
+    }
+
+    #[test]
+    fn scheduled_policy() {
+        let mut policies = policies();
+        // Longer persistence at rush hour, 7am to 10am
+        policies.insert(
+            String::from("cam1"),
+            PrivacyPolicy::Scheduled {
+                k_segments: 1,
+                epsilon: 1.0,
+                rho_ms: 60_000,
+                schedule: vec![crate::policy::ScheduleEntry {
+                    days: vec![],
+                    start_ms: 7 * 3_600_000,
+                    end_ms: 10 * 3_600_000,
+                    k_segments: 2,
+                    rho_ms: 300_000,
+                }],
+            },
+        );
+        let sensitivity = |begin_hr: u64, end_hr: u64| {
+            let query = crate::parser::build(&format!(
+                "SPLIT cam1 BEGIN {} END {} BY TIME 60sec INTO chunks1;
+                PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 10 ROWS
+                WITH SCHEMA (plate:STRING=null) INTO table1;
+                SELECT count(plate) FROM table1;",
+                begin_hr * 3600,
+                end_hr * 3600
+            ))
+            .unwrap();
+            table_sensitivity(
+                &query.split_stmts["chunks1"],
+                &query.process_stmts["table1"],
+                &policies,
+            )
+            .unwrap()
+        };
+        // 10 * 1 * (1 + 60sec / 60sec) at night, 10 * 2 * (1 + 300sec / 60sec) once rush hour
+        // is covered
+        assert_eq!(sensitivity(0, 6), 20);
+        assert_eq!(sensitivity(0, 8), 120);
+        assert_eq!(sensitivity(10, 20), 20);
+    }
+
     // 64
     fn table_fig5() -> &'static str {
         "SPLIT auburn
@@ -768,6 +1486,29 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_sensitivity() {
+        let query_string = format!(
+            "{} {}",
+            table_one(),
+            "SELECT sum(speed,l=0,u=10) FROM table1;"
+        );
+        let query = crate::parser::build(query_string.as_str()).unwrap();
+        let res = sensitivity_composition(query.select_stmts.first().unwrap(), &policies());
+        let json = serde_json::to_value(&res).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "rows": 70,
+                "columns": { "sum(speed,l=0,u=10)": { "release": 700.0 } },
+                "size_constraint": 0,
+            })
+        );
+        let parsed: TableSensitivity = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, res);
+    }
+
     // TODO implement python interface
     // input: string query, privacy policy hashmap
     // output map of result to sensitivity
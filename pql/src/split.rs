@@ -1,6 +1,7 @@
 use crate::parser::*;
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SplitStatement {
     // Unique reference to the camera, used to fetch the proper video source
     pub camera_name: String,
@@ -20,11 +21,43 @@ pub struct SplitStatement {
 
     // Name of chunks that will be created from this video
     pub output_name: String,
+
+    pub span: Span,
+}
+
+impl SplitStatement {
+    /// Number of chunks created by this split, including a last partial chunk if the video length
+    /// isn't a multiple of the chunk length
+    pub fn num_chunks(&self) -> u64 {
+        let video_length_ms = self.end_time.saturating_sub(self.start_time) * 1000;
+        if self.chunk_length_ms == 0 {
+            return 0;
+        }
+        // TODO with strides, chunks will start every chunk_stride_ms instead
+        video_length_ms.div_ceil(self.chunk_length_ms)
+    }
+
+    /// `(index, start_ms, end_ms)` of the chunk at `index`, in unix milliseconds, or None if the
+    /// split has no such chunk. The last chunk is cut short by END.
+    pub fn chunk(&self, index: u64) -> Option<(u64, u64, u64)> {
+        if index >= self.num_chunks() {
+            return None;
+        }
+        let start_ms = self.start_time * 1000 + index * self.chunk_length_ms;
+        let end_ms = std::cmp::min(start_ms + self.chunk_length_ms, self.end_time * 1000);
+        Some((index, start_ms, end_ms))
+    }
+
+    /// Every chunk created by this split, in order, as `(index, start_ms, end_ms)`
+    pub fn chunks(&self) -> impl Iterator<Item = (u64, u64, u64)> + '_ {
+        (0..self.num_chunks()).filter_map(move |i| self.chunk(i))
+    }
 }
 
 pub fn parse_split_stmt(pair: pest::iterators::Pair<Rule>) -> SplitStatement {
     match pair.as_rule() {
         Rule::split_stmt => {
+            let span = Span::from(pair.as_span());
             let mut pair = pair.into_inner();
             let camera_name = pair.next().unwrap().as_str().to_owned();
             let start_time = pair
@@ -57,6 +90,7 @@ pub fn parse_split_stmt(pair: pest::iterators::Pair<Rule>) -> SplitStatement {
                 end_time,
                 chunk_length_ms,
                 output_name,
+                span,
             }
         }
         _ => unreachable!("parse error: expected split, got {:#?}", pair.as_rule()),
@@ -75,7 +109,9 @@ mod tests {
             .next()
             .unwrap();
         // pest obj -> SplitStatement object
-        let parsed_stmt = parse_split_stmt(pair);
+        let mut parsed_stmt = parse_split_stmt(pair);
+        // Spans are checked by the parser tests
+        parsed_stmt.span = Span::default();
         assert_eq!(parsed_stmt, expected);
     }
 
@@ -95,7 +131,36 @@ mod tests {
                 chunk_length_ms: 10500,
                 // chunk_stride_ms: 1000,
                 output_name: String::from("chunks1"),
+                span: Span::default(),
             },
         );
     }
+
+    #[test]
+    fn chunks() {
+        let pair = PQLParser::parse(
+            Rule::split_stmt,
+            "SPLIT cam1 BEGIN 10 END 35 BY TIME 10sec INTO c",
+        )
+        .unwrap()
+        .next()
+        .unwrap();
+        let ss = parse_split_stmt(pair);
+        assert_eq!(ss.num_chunks(), 3);
+        assert_eq!(
+            ss.chunks().collect::<Vec<_>>(),
+            vec![
+                (0, 10_000, 20_000),
+                (1, 20_000, 30_000),
+                (2, 30_000, 35_000)
+            ]
+        );
+        assert_eq!(ss.chunk(3), None);
+
+        let empty = SplitStatement {
+            chunk_length_ms: 0,
+            ..ss.clone()
+        };
+        assert_eq!(empty.chunks().count(), 0);
+    }
 }
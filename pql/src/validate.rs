@@ -0,0 +1,1192 @@
+use crate::aggregation::{AstNode, BooleanOp, GroupByAttr};
+use crate::parser::{PQLQuery, Rule, Span};
+use crate::policy::{PolicyMap, PrivacyPolicy};
+use crate::process::Column;
+use crate::sensitivity::{aggregated_rows, allocate_epsilon, statement_budgets, table_sensitivity};
+
+use pest::error::{Error, InputLocation, LineColLocation};
+use std::collections::HashMap;
+use std::fmt;
+
+// A single problem found while checking a query. Validation never stops at the first problem, so
+// a query produces a list of these.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Diagnostic {
+    pub message: String,
+    // Location of the offending text in the query string
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn error(message: String, span: Span) -> Self {
+        Diagnostic { message, span }
+    }
+
+    /// Formats the diagnostic like a compiler error, quoting the line of `source` (the original
+    /// query string) that it refers to and underlining the offending text.
+    pub fn render(&self, source: &str) -> String {
+        let line = source.lines().nth(self.span.line.saturating_sub(1));
+        let line = match line {
+            Some(line) if self.span.line > 0 => line,
+            _ => return format!("{}\n", self),
+        };
+        let gutter = " ".repeat(self.span.line.to_string().len());
+        // Spans covering several lines are only underlined up to the end of their first line
+        let width = std::cmp::min(
+            self.span.end - self.span.start,
+            line.len() + 1 - self.span.col,
+        );
+        format!(
+            "{}\n{}--> {}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+            self,
+            gutter,
+            self.span.line,
+            self.span.col,
+            gutter,
+            self.span.line,
+            line,
+            gutter,
+            " ".repeat(self.span.col - 1),
+            "^".repeat(std::cmp::max(width, 1)),
+        )
+    }
+}
+
+impl From<&Error<Rule>> for Diagnostic {
+    // Syntax errors (and unknown tables) found while building a query
+    fn from(e: &Error<Rule>) -> Self {
+        let (start, end) = match e.location {
+            InputLocation::Pos(p) => (p, p),
+            InputLocation::Span(s) => s,
+        };
+        let (line, col) = match e.line_col {
+            LineColLocation::Pos(lc) | LineColLocation::Span(lc, _) => lc,
+        };
+        Diagnostic {
+            message: e.variant.message().into_owned(),
+            span: Span {
+                start,
+                end,
+                line,
+                col,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error: {}", self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnType {
+    String,
+    Number,
+    // Time column added to every table (e.g. `chunk`), always has a known range
+    Fixed,
+}
+
+// What validation knows about each column visible at a given point in the query. Mirrors the
+// ColumnMap built by sensitivity_composition: base table columns are qualified by their table
+// name (`table1.plate`), while fixed columns, aliases and GROUP BY keys are not.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ColumnInfo {
+    ty: ColumnType,
+    // Whether sensitivity_composition will know a range for this column
+    bounded: bool,
+    // Whether its values don't depend on the data, so that it can be released as it is: the
+    // keys of bin() and GROUP BY .. WITH KEYS
+    key: bool,
+}
+type Scope = HashMap<String, ColumnInfo>;
+
+// Returns the entry for `name`, falling back to any qualified column (`table.name`) if the bare
+// name is not in scope
+fn resolve<'a>(scope: &'a Scope, name: &str) -> Option<&'a ColumnInfo> {
+    scope.get(name).or_else(|| {
+        scope
+            .iter()
+            .find(|(k, _)| k.split('.').nth(1) == Some(name))
+            .map(|(_, v)| v)
+    })
+}
+
+// Groups the qualified columns in scope by the table they came from
+fn table_columns(scope: &Scope) -> HashMap<String, Vec<String>> {
+    let mut table_to_col: HashMap<String, Vec<String>> = HashMap::new();
+    for name in scope.keys() {
+        let mut sp = name.split('.');
+        if let (Some(table), Some(col)) = (sp.next(), sp.next()) {
+            table_to_col
+                .entry(table.to_string())
+                .or_default()
+                .push(col.to_string());
+        }
+    }
+    table_to_col
+}
+
+fn validate_combine(
+    kind: &str,
+    tables: &[AstNode],
+    on: &AstNode,
+    diags: &mut Vec<Diagnostic>,
+) -> Scope {
+    let mut scope = Scope::new();
+    for t in tables {
+        scope.extend(validate_node(t, diags));
+    }
+    if let AstNode::Column(on, span) = on {
+        let span = *span;
+        let mut missing: Vec<String> = table_columns(&scope)
+            .into_iter()
+            .filter(|(_, cols)| !cols.contains(on))
+            .map(|(t, _)| t)
+            .collect();
+        missing.sort();
+        if !missing.is_empty() {
+            diags.push(Diagnostic::error(
+                format!(
+                    "{} column `{}` does not exist in table(s): {}",
+                    kind,
+                    on,
+                    missing.join(", ")
+                ),
+                span,
+            ));
+        }
+    }
+    scope
+}
+
+// Type of a term compared by a WHERE, None if it was already reported
+fn term_type(term: &AstNode, scope: &Scope, diags: &mut Vec<Diagnostic>) -> Option<ColumnType> {
+    match term {
+        AstNode::Column(c, span) => match resolve(scope, c) {
+            Some(info) => Some(info.ty),
+            None => {
+                diags.push(Diagnostic::error(
+                    format!("column `{}` does not exist", c),
+                    *span,
+                ));
+                None
+            }
+        },
+        AstNode::Value(..) => Some(ColumnType::Number),
+        _ => {
+            diags.push(Diagnostic::error(
+                String::from("expected a column or a number"),
+                term.span(),
+            ));
+            None
+        }
+    }
+}
+
+// Every column a WHERE compares must exist, and only be compared with values of the same type
+fn validate_predicate(node: &AstNode, scope: &Scope, diags: &mut Vec<Diagnostic>) {
+    match node {
+        AstNode::Predicate { lhs, op, rhs, span } => match op {
+            BooleanOp::And | BooleanOp::Or => {
+                validate_predicate(lhs, scope, diags);
+                validate_predicate(rhs, scope, diags);
+            }
+            BooleanOp::Not => validate_predicate(lhs, scope, diags),
+            _ => {
+                let types = (term_type(lhs, scope, diags), term_type(rhs, scope, diags));
+                if let (Some(l), Some(r)) = types {
+                    if (l == ColumnType::String) != (r == ColumnType::String) {
+                        diags.push(Diagnostic::error(
+                            format!("cannot compare `{}` with `{}`", lhs, rhs),
+                            *span,
+                        ));
+                    }
+                }
+            }
+        },
+        _ => diags.push(Diagnostic::error(
+            String::from("expected a predicate"),
+            node.span(),
+        )),
+    }
+}
+
+fn validate_node(node: &AstNode, diags: &mut Vec<Diagnostic>) -> Scope {
+    match node {
+        AstNode::Table(_, ps) => ps
+            .schema
+            .iter()
+            .map(|c| match c {
+                Column::String(sc) => (
+                    ps.output_table_name.clone() + "." + sc.name.as_str(),
+                    ColumnInfo {
+                        ty: ColumnType::String,
+                        bounded: false,
+                        key: false,
+                    },
+                ),
+                Column::Number(nc) => (
+                    ps.output_table_name.clone() + "." + nc.name.as_str(),
+                    ColumnInfo {
+                        ty: ColumnType::Number,
+                        bounded: false,
+                        key: false,
+                    },
+                ),
+                Column::Fixed(fc) => (
+                    fc.name.clone(),
+                    ColumnInfo {
+                        ty: ColumnType::Fixed,
+                        bounded: true,
+                        key: false,
+                    },
+                ),
+            })
+            .collect(),
+        AstNode::Tables(tables, _) => {
+            let mut scope = Scope::new();
+            for t in tables {
+                scope.extend(validate_node(t, diags));
+            }
+            scope
+        }
+        AstNode::Intersect { tables, on, .. } => validate_combine("INTERSECT", tables, on, diags),
+        AstNode::Union { tables, on, .. } => validate_combine("UNION", tables, on, diags),
+        AstNode::Equijoin { tables, on, .. } => validate_combine("EQUIJOIN", tables, on, diags),
+        AstNode::Select {
+            exprs,
+            from,
+            qualifiers,
+            ..
+        } => {
+            let mut base = validate_node(from, diags);
+            let mut ret = Scope::new();
+
+            for q in qualifiers {
+                let span = q.span();
+                match q {
+                    AstNode::GroupBy { attrs, .. } => {
+                        let table_to_col = table_columns(&base);
+                        for attr in attrs {
+                            match attr {
+                                GroupByAttr::Column(colname) => {
+                                    if base.contains_key(colname) {
+                                        continue;
+                                    }
+                                    let mut missing: Vec<&String> = table_to_col
+                                        .iter()
+                                        .filter(|(_, cs)| !cs.contains(colname))
+                                        .map(|(t, _)| t)
+                                        .collect();
+                                    missing.sort();
+                                    if table_to_col.is_empty() {
+                                        diags.push(Diagnostic::error(
+                                            format!("GROUP BY column `{}` does not exist", colname),
+                                            span,
+                                        ));
+                                    } else if !missing.is_empty() {
+                                        diags.push(Diagnostic::error(
+                                            format!(
+                                            "GROUP BY column `{}` does not exist in table(s): {}",
+                                            colname,
+                                            missing
+                                                .iter()
+                                                .map(|t| t.as_str())
+                                                .collect::<Vec<_>>()
+                                                .join(", ")
+                                        ),
+                                            span,
+                                        ));
+                                    }
+                                    // Still bring the column into scope so that later uses of
+                                    // it don't report the same problem again
+                                    if let Some(info) = resolve(&base, colname).copied() {
+                                        base.insert(colname.clone(), info);
+                                    }
+                                }
+                                GroupByAttr::Bin { column, alias, .. } => {
+                                    match base.get(column) {
+                                        Some(ColumnInfo {
+                                            ty: ColumnType::Fixed,
+                                            ..
+                                        }) => {}
+                                        Some(_) => diags.push(Diagnostic::error(format!(
+                                            "bin() requires a time column such as `chunk`, but `{}` is not one",
+                                            column
+                                        ), span)),
+                                        None => diags.push(Diagnostic::error(format!(
+                                            "bin() column `{}` does not exist",
+                                            column
+                                        ), span)),
+                                    }
+                                    base.insert(
+                                        alias.clone(),
+                                        ColumnInfo {
+                                            ty: ColumnType::Number,
+                                            bounded: true,
+                                            key: true,
+                                        },
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    AstNode::GroupByWithKeys { attr, .. } => {
+                        if qualifiers
+                            .iter()
+                            .any(|q| matches!(q, AstNode::GroupBy { .. }))
+                        {
+                            diags.push(Diagnostic::error(
+                                String::from(
+                                    "GROUP BY .. WITH KEYS can't be combined with another GROUP BY",
+                                ),
+                                span,
+                            ));
+                        }
+                        match resolve(&base, attr).copied() {
+                            Some(info) => {
+                                base.insert(attr.clone(), ColumnInfo { key: true, ..info });
+                            }
+                            None => diags.push(Diagnostic::error(
+                                format!("GROUP BY column `{}` does not exist", attr),
+                                span,
+                            )),
+                        }
+                    }
+                    // Checked by validate_epsilon
+                    AstNode::Limit { .. } | AstNode::Epsilon(..) => {}
+                    AstNode::Where { predicate, .. } => validate_predicate(predicate, &base, diags),
+                    AstNode::DurationMs(..) => diags.push(Diagnostic::error(
+                        String::from(
+                            "WITH WINDOW is not supported by the sensitivity calculation yet",
+                        ),
+                        span,
+                    )),
+                    _ => diags.push(Diagnostic::error(
+                        String::from(
+                            "qualifier is not supported by the sensitivity calculation yet",
+                        ),
+                        span,
+                    )),
+                }
+            }
+
+            for expr in exprs {
+                let span = expr.span();
+                match expr {
+                    AstNode::Column(c, _) => match base.get(c) {
+                        Some(info) => {
+                            ret.insert(c.clone(), *info);
+                        }
+                        None => {
+                            let qualified: Vec<&String> = base
+                                .keys()
+                                .filter(|k| k.split('.').nth(1) == Some(c.as_str()))
+                                .collect();
+                            diags.push(Diagnostic::error(match qualified.first() {
+                                Some(q) => format!(
+                                    "column `{}` must be qualified by its table (e.g. `{}`) or appear in GROUP BY",
+                                    c, q
+                                ),
+                                None => format!("column `{}` does not exist", c),
+                            }, span));
+                        }
+                    },
+                    AstNode::UserF {
+                        column,
+                        range,
+                        alias,
+                        ..
+                    } => {
+                        if resolve(&base, column).is_none() {
+                            diags.push(Diagnostic::error(
+                                format!("column `{}` does not exist", column),
+                                span,
+                            ));
+                        }
+                        ret.insert(
+                            alias.clone(),
+                            ColumnInfo {
+                                ty: ColumnType::Number,
+                                bounded: range.is_some(),
+                                key: false,
+                            },
+                        );
+                    }
+                    AstNode::Aggregation {
+                        function,
+                        inner,
+                        range,
+                        alias,
+                        ..
+                    } => {
+                        let function = function.to_lowercase();
+                        let info = resolve(&base, inner);
+                        if info.is_none() {
+                            diags.push(Diagnostic::error(
+                                format!("column `{}` does not exist", inner),
+                                span,
+                            ));
+                        }
+                        let bounded = match function.as_str() {
+                            "count" => true,
+                            "sum" | "mean" => {
+                                if let Some(ColumnInfo {
+                                    ty: ColumnType::String,
+                                    ..
+                                }) = info
+                                {
+                                    diags.push(Diagnostic::error(
+                                        format!(
+                                            "{}() requires a NUMBER column, but `{}` is a STRING",
+                                            function, inner
+                                        ),
+                                        span,
+                                    ));
+                                }
+                                // sum only uses explicit bounds, mean can also use the bounds
+                                // of its input column
+                                let column_bounded =
+                                    function == "mean" && info.is_some_and(|i| i.bounded);
+                                if range.is_none() && !column_bounded {
+                                    diags.push(Diagnostic::error(format!(
+                                        "{}(`{}`) is unbounded, add explicit bounds: {}({},l=..,u=..)",
+                                        function, inner, function, inner
+                                    ), span));
+                                }
+                                // mean divides by this number of rows rather than the ones it sees
+                                if function == "mean"
+                                    && !matches!(aggregated_rows(from, qualifiers), Some(n) if n > 0)
+                                {
+                                    diags.push(Diagnostic::error(format!(
+                                        "mean(`{}`) is over an unknown number of rows, add LIMIT to the statement",
+                                        inner
+                                    ), span));
+                                }
+                                true
+                            }
+                            "var" | "stddev" | "quantile" => {
+                                diags.push(Diagnostic::error(
+                                    format!(
+                                        "{}() is not supported by the sensitivity calculation yet",
+                                        function
+                                    ),
+                                    span,
+                                ));
+                                false
+                            }
+                            _ => {
+                                diags.push(Diagnostic::error(
+                                    format!("unknown aggregation function `{}`", function),
+                                    span,
+                                ));
+                                false
+                            }
+                        };
+                        ret.insert(
+                            alias.clone(),
+                            ColumnInfo {
+                                ty: ColumnType::Number,
+                                bounded,
+                                key: false,
+                            },
+                        );
+                    }
+                    _ => diags.push(Diagnostic::error(
+                        String::from(
+                            "expression is not supported by the sensitivity calculation yet",
+                        ),
+                        span,
+                    )),
+                }
+            }
+            ret
+        }
+        _ => {
+            let span = node.span();
+            diags.push(Diagnostic::error(
+                String::from("unexpected node in FROM clause"),
+                span,
+            ));
+            Scope::new()
+        }
+    }
+}
+
+// Columns of a statement are released either with noise or, for keys, as they are. Any other
+// column would publish raw data, such as the plates of a GROUP BY plate.
+fn validate_outputs(stmt: &AstNode, scope: &Scope, diags: &mut Vec<Diagnostic>) {
+    let exprs = match stmt {
+        AstNode::Select { exprs, .. } => exprs,
+        _ => return,
+    };
+    for expr in exprs {
+        match expr {
+            AstNode::Column(c, span) => match scope.get(c) {
+                Some(info) if !info.key => diags.push(Diagnostic::error(
+                    format!(
+                        "column `{}` would be released as it is, only bin() and GROUP BY {} WITH KEYS (..) keys can be",
+                        c, c
+                    ),
+                    *span,
+                )),
+                _ => {}
+            },
+            AstNode::UserF {
+                function,
+                column,
+                range: None,
+                span,
+                ..
+            } => diags.push(Diagnostic::error(
+                format!(
+                    "USER_{}(`{}`) is unbounded, add explicit bounds: USER_{}({},l=..,u=..)",
+                    function, column, function, column
+                ),
+                *span,
+            )),
+            _ => {}
+        }
+    }
+}
+
+// Epsilons are only set on the outermost SELECT, since nested ones don't release anything
+fn nested_epsilon(node: &AstNode, diags: &mut Vec<Diagnostic>) {
+    match node {
+        AstNode::Tables(tables, _)
+        | AstNode::Intersect { tables, .. }
+        | AstNode::Union { tables, .. }
+        | AstNode::Equijoin { tables, .. } => {
+            for t in tables {
+                nested_epsilon(t, diags);
+            }
+        }
+        AstNode::Select {
+            exprs,
+            from,
+            qualifiers,
+            ..
+        } => {
+            for node in exprs.iter().chain(qualifiers) {
+                if let AstNode::Epsilon(..)
+                | AstNode::Aggregation {
+                    epsilon: Some(_), ..
+                }
+                | AstNode::UserF {
+                    epsilon: Some(_), ..
+                } = node
+                {
+                    diags.push(Diagnostic::error(
+                        String::from("EPSILON can only be set on the outermost SELECT"),
+                        node.span(),
+                    ));
+                }
+            }
+            nested_epsilon(from, diags);
+        }
+        _ => {}
+    }
+}
+
+// WITH EPSILON and the EPSILON of each column of a statement
+fn validate_epsilon(stmt: &AstNode, diags: &mut Vec<Diagnostic>) {
+    let (exprs, from, qualifiers) = match stmt {
+        AstNode::Select {
+            exprs,
+            from,
+            qualifiers,
+            ..
+        } => (exprs, from, qualifiers),
+        _ => return,
+    };
+    nested_epsilon(from, diags);
+
+    let mut total = None;
+    for q in qualifiers {
+        if let AstNode::Epsilon(e, span) = q {
+            if total.is_some() {
+                diags.push(Diagnostic::error(
+                    String::from("WITH EPSILON is set more than once"),
+                    *span,
+                ));
+            } else if *e <= 0.0 {
+                diags.push(Diagnostic::error(
+                    String::from("WITH EPSILON must be positive"),
+                    *span,
+                ));
+            }
+            total.get_or_insert(*e);
+        }
+    }
+
+    for expr in exprs {
+        if let AstNode::Aggregation {
+            alias,
+            epsilon: Some(e),
+            ..
+        }
+        | AstNode::UserF {
+            alias,
+            epsilon: Some(e),
+            ..
+        } = expr
+        {
+            if *e <= 0.0 {
+                diags.push(Diagnostic::error(
+                    format!("EPSILON of `{}` must be positive", alias),
+                    expr.span(),
+                ));
+            }
+        }
+    }
+    match total {
+        Some(total) if total > 0.0 => validate_columns(stmt, total, diags),
+        _ => {}
+    }
+}
+
+// The columns with EPSILON must leave some of the statement's epsilon to the other ones
+fn validate_columns(stmt: &AstNode, epsilon: f64, diags: &mut Vec<Diagnostic>) {
+    let explicit = stmt.column_epsilons();
+    let set: f64 = explicit.values().sum();
+    let unset = match stmt {
+        AstNode::Select { exprs, .. } => exprs
+            .iter()
+            .filter(|e| {
+                matches!(
+                    e,
+                    AstNode::Aggregation { epsilon: None, .. }
+                        | AstNode::UserF { epsilon: None, .. }
+                )
+            })
+            .count(),
+        _ => 0,
+    };
+    let message = if set > epsilon {
+        format!(
+            "the EPSILON of the columns add up to {}, more than the statement's epsilon {}",
+            set, epsilon
+        )
+    } else if set == epsilon && unset > 0 {
+        format!(
+            "the EPSILON of the columns add up to the statement's epsilon {}, leaving nothing for the other columns",
+            epsilon
+        )
+    } else {
+        return;
+    };
+    diags.push(Diagnostic::error(message, stmt.span()));
+}
+
+// Every split must cover a time range in chunks of at least 1ms
+fn validate_splits(query: &PQLQuery, diags: &mut Vec<Diagnostic>) {
+    let mut splits: Vec<_> = query.split_stmts.values().collect();
+    splits.sort_by(|a, b| a.output_name.cmp(&b.output_name));
+    for ss in splits {
+        if ss.chunk_length_ms == 0 {
+            diags.push(Diagnostic::error(
+                format!(
+                    "split `{}` has a chunk length of 0, BY TIME must be at least 1ms",
+                    ss.output_name
+                ),
+                ss.span,
+            ));
+        }
+        if ss.end_time < ss.start_time {
+            diags.push(Diagnostic::error(
+                format!(
+                    "split `{}` ENDs at {}, before it BEGINs at {}",
+                    ss.output_name, ss.end_time, ss.start_time
+                ),
+                ss.span,
+            ));
+        }
+    }
+}
+
+/// Checks that `query` is well-formed and that its sensitivity can be computed, independently of
+/// any privacy policy
+pub fn validate_query(query: &PQLQuery) -> Vec<Diagnostic> {
+    let mut diags = vec![];
+    validate_splits(query, &mut diags);
+    for stmt in &query.select_stmts {
+        let scope = validate_node(stmt, &mut diags);
+        validate_outputs(stmt, &scope, &mut diags);
+        validate_epsilon(stmt, &mut diags);
+    }
+    diags
+}
+
+// The statements reading each camera (or the cameras of a site) must not spend more than its
+// policy epsilon in total
+fn validate_allocation(query: &PQLQuery, policies: &PolicyMap, diags: &mut Vec<Diagnostic>) {
+    let mut budgets: Vec<(String, f64)> = query
+        .select_stmts
+        .iter()
+        .flat_map(|stmt| statement_budgets(stmt, policies))
+        .collect();
+    budgets.sort_by(|a, b| a.0.cmp(&b.0));
+    budgets.dedup_by(|a, b| a.0 == b.0);
+    let sites: Vec<&str> = policies.values().filter_map(|p| p.site()).collect();
+    for (key, policy_epsilon) in budgets {
+        let camera = if sites.contains(&key.as_str()) {
+            format!("site `{}`", key)
+        } else {
+            format!("camera `{}`", key)
+        };
+        let stmts: Vec<&AstNode> = query
+            .select_stmts
+            .iter()
+            .filter(|stmt| {
+                statement_budgets(stmt, policies)
+                    .iter()
+                    .any(|(k, _)| *k == key)
+            })
+            .collect();
+        let explicit: f64 = stmts.iter().filter_map(|s| s.statement_epsilon()).sum();
+        let last = stmts.last().unwrap().span();
+        if explicit > policy_epsilon {
+            diags.push(Diagnostic::error(
+                format!(
+                    "the statements reading {} use epsilon {} in total, more than the {} of its policy",
+                    camera, explicit, policy_epsilon
+                ),
+                last,
+            ));
+        } else if explicit == policy_epsilon {
+            if let Some(stmt) = stmts.iter().find(|s| s.statement_epsilon().is_none()) {
+                diags.push(Diagnostic::error(
+                    format!(
+                        "no epsilon of {} is left for this statement, add WITH EPSILON to it and lower the others",
+                        camera
+                    ),
+                    stmt.span(),
+                ));
+            }
+        }
+    }
+
+    // Statements without WITH EPSILON only know their share once it has been allocated
+    for (stmt, epsilon) in query
+        .select_stmts
+        .iter()
+        .zip(allocate_epsilon(query, policies))
+    {
+        match (stmt.statement_epsilon(), epsilon) {
+            (None, Some(e)) if e > 0.0 => validate_columns(stmt, e, diags),
+            _ => {}
+        }
+    }
+}
+
+// Every camera of a site must have the same policy, since the bound of the site is that of each
+// of them
+fn validate_sites(query: &PQLQuery, policies: &PolicyMap, diags: &mut Vec<Diagnostic>) {
+    let mut splits: Vec<_> = query.split_stmts.values().collect();
+    splits.sort_by(|a, b| a.output_name.cmp(&b.output_name));
+    // Each site is only reported once
+    let mut reported = vec![];
+    for ss in splits {
+        let policy = match policies.get(&ss.camera_name) {
+            Some(p) => p,
+            None => continue,
+        };
+        let site = match policy.site() {
+            Some(site) if !reported.contains(&site) => site,
+            _ => continue,
+        };
+        let other = policies
+            .iter()
+            .filter(|(_, p)| p.site() == Some(site) && *p != policy)
+            .map(|(camera, _)| camera)
+            .min();
+        if let Some(other) = other {
+            reported.push(site);
+            diags.push(Diagnostic::error(
+                format!(
+                    "cameras `{}` and `{}` of site `{}` have different policies",
+                    ss.camera_name, other, site
+                ),
+                ss.span,
+            ));
+        }
+    }
+}
+
+/// Checks that `query` is well-formed and that its sensitivity can be computed under `policies`.
+/// Returns every problem found, so an empty list means the query can be passed to
+/// sensitivity_composition.
+pub fn validate(query: &PQLQuery, policies: &PolicyMap) -> Vec<Diagnostic> {
+    let mut diags = vec![];
+
+    let mut splits: Vec<_> = query.split_stmts.values().collect();
+    splits.sort_by(|a, b| a.output_name.cmp(&b.output_name));
+    for ss in splits {
+        let span = ss.span;
+        match policies.get(&ss.camera_name) {
+            None => diags.push(Diagnostic::error(
+                format!("no privacy policy for camera `{}`", ss.camera_name),
+                span,
+            )),
+            Some(PrivacyPolicy::Mask {}) => diags.push(Diagnostic::error(
+                format!(
+                    "camera `{}` uses a mask policy, which is not supported yet",
+                    ss.camera_name
+                ),
+                span,
+            )),
+            // A chunk length of 0 is reported by validate_splits
+            Some(_) if ss.chunk_length_ms > 0 => {
+                let mut tables: Vec<_> = query
+                    .process_stmts
+                    .values()
+                    .filter(|ps| ps.input_name == ss.output_name)
+                    .collect();
+                tables.sort_by(|a, b| a.output_table_name.cmp(&b.output_table_name));
+                for ps in tables {
+                    if let Err(message) = table_sensitivity(ss, ps, policies) {
+                        diags.push(Diagnostic::error(message, ps.span));
+                    }
+                }
+            }
+            Some(_) => {}
+        }
+    }
+    validate_sites(query, policies, &mut diags);
+
+    diags.extend(validate_query(query));
+    validate_allocation(query, policies, &mut diags);
+    diags
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::policy::{PolicyMap, PrivacyPolicy};
+    use crate::validate::*;
+
+    fn tables() -> &'static str {
+        "SPLIT cam1
+            BEGIN 0
+            END 60
+            BY TIME 5sec
+            INTO chunks1;
+
+            PROCESS chunks1 USING yolov3 TIMEOUT 1sec
+            PRODUCING 10 ROWS
+            WITH SCHEMA (plate:STRING=null, speed:NUMBER=0)
+            INTO table1;
+
+        SPLIT cam2
+            BEGIN 0
+            END 60
+            BY TIME 1sec
+            INTO chunks2;
+
+        PROCESS chunks2 USING yolov3 TIMEOUT 1sec
+            PRODUCING 2 ROWS
+            WITH SCHEMA (color:STRING=null, speed:NUMBER=0)
+            INTO table2;
+        "
+    }
+
+    fn policies() -> PolicyMap {
+        vec![
+            (
+                String::from("cam1"),
+                PrivacyPolicy::Static {
+                    k_segments: 1,
+                    rho_ms: 30_000,
+                    epsilon: 1.0,
+                },
+            ),
+            (
+                String::from("cam2"),
+                PrivacyPolicy::Static {
+                    k_segments: 1,
+                    rho_ms: 30_000,
+                    epsilon: 1.0,
+                },
+            ),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn check(select_str: &str, policies: PolicyMap) -> Vec<String> {
+        let query_string = format!("{} {}", tables(), select_str);
+        let query = crate::parser::build(query_string.as_str()).unwrap();
+        validate(&query, &policies)
+            .into_iter()
+            .map(|d| d.message)
+            .collect()
+    }
+
+    #[test]
+    fn valid_queries() {
+        for q in &[
+            "SELECT count(plate) FROM table1;",
+            "SELECT sum(speed,l=0,u=10) FROM table1;",
+            "SELECT count(speed) FROM (table1 UNION table2 ON speed);",
+            "SELECT mean(shift) FROM (SELECT plate,USER_shift(chunk,l=0,u=16) as shift FROM table1 GROUP BY plate);",
+            "SELECT plate, count(plate) FROM table1 GROUP BY plate WITH KEYS (ABC123, XYZ9);",
+            "SELECT hr, count(plate) FROM table1 GROUP BY bin(chunk, hr);",
+            "SELECT count(plate) FROM table1 WHERE (speed > 10) && (chunk < 30000);",
+        ] {
+            assert_eq!(check(q, policies()), Vec::<String>::new(), "{}", q);
+        }
+    }
+
+    #[test]
+    fn invalid_splits() {
+        let query = crate::parser::build(
+            "SPLIT cam1 BEGIN 60 END 0 BY TIME 0sec INTO chunks1;
+            PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 10 ROWS
+            WITH SCHEMA (plate:STRING=null) INTO table1;
+            SELECT count(plate) FROM table1;",
+        )
+        .unwrap();
+        assert_eq!(
+            validate(&query, &policies())
+                .into_iter()
+                .map(|d| d.message)
+                .collect::<Vec<_>>(),
+            vec![
+                "split `chunks1` has a chunk length of 0, BY TIME must be at least 1ms",
+                "split `chunks1` ENDs at 0, before it BEGINs at 60",
+            ]
+        );
+
+        let query = crate::parser::build(&format!(
+            "SPLIT cam1 BEGIN 0 END 60 BY TIME 1ms INTO chunks1;
+            PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING {} ROWS
+            WITH SCHEMA (plate:STRING=null) INTO table1;
+            SELECT count(plate) FROM table1;",
+            u64::MAX / 2
+        ))
+        .unwrap();
+        assert_eq!(
+            validate(&query, &policies())
+                .into_iter()
+                .map(|d| d.message)
+                .collect::<Vec<_>>(),
+            vec!["the rows of table `table1` an individual can appear in don't fit in 64 bits"]
+        );
+    }
+
+    #[test]
+    fn where_clause() {
+        assert_eq!(
+            check(
+                "SELECT count(plate) FROM table1 WHERE (plate > 10) || (size < 3);",
+                policies()
+            ),
+            vec![
+                "cannot compare `plate` with `10`",
+                "column `size` does not exist"
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_policy() {
+        let mut policies = policies();
+        policies.remove("cam2");
+        assert_eq!(
+            check("SELECT count(plate) FROM table1;", policies),
+            vec!["no privacy policy for camera `cam2`"]
+        );
+    }
+
+    #[test]
+    fn sum_over_string() {
+        assert_eq!(
+            check("SELECT sum(plate,l=0,u=1) FROM table1;", policies()),
+            vec!["sum() requires a NUMBER column, but `plate` is a STRING"]
+        );
+    }
+
+    #[test]
+    fn unbound_aggregation() {
+        assert_eq!(
+            check("SELECT mean(speed) FROM table1;", policies()),
+            vec!["mean(`speed`) is unbounded, add explicit bounds: mean(speed,l=..,u=..)"]
+        );
+    }
+
+    #[test]
+    fn group_by_missing_in_union() {
+        assert_eq!(
+            check(
+                "SELECT plate FROM (table1 UNION table2 ON speed) GROUP BY plate;",
+                policies()
+            ),
+            vec![
+                "GROUP BY column `plate` does not exist in table(s): table2",
+                "column `plate` would be released as it is, only bin() and GROUP BY plate WITH KEYS (..) keys can be"
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_outputs() {
+        assert_eq!(
+            check(
+                "SELECT plate, count(plate) FROM table1 GROUP BY plate;",
+                policies()
+            ),
+            vec!["column `plate` would be released as it is, only bin() and GROUP BY plate WITH KEYS (..) keys can be"]
+        );
+        assert_eq!(
+            check("SELECT USER_f(speed) as s FROM table1;", policies()),
+            vec!["USER_f(`speed`) is unbounded, add explicit bounds: USER_f(speed,l=..,u=..)"]
+        );
+        assert_eq!(
+            check(
+                "SELECT plate, count(plate) FROM table1 GROUP BY plate WITH KEYS (abc) GROUP BY bin(chunk, hr);",
+                policies()
+            ),
+            vec!["GROUP BY .. WITH KEYS can't be combined with another GROUP BY"]
+        );
+    }
+
+    #[test]
+    fn render_snippet() {
+        let query_string = format!("{}SELECT sum(plate,l=0,u=1) FROM table1;", tables());
+        let query = crate::parser::build(query_string.as_str()).unwrap();
+        let diags = validate(&query, &policies());
+        assert_eq!(diags.len(), 1);
+        assert_eq!(
+            diags[0].render(query_string.as_str()),
+            "error: sum() requires a NUMBER column, but `plate` is a STRING
+  --> 22:16
+   |
+22 |         SELECT sum(plate,l=0,u=1) FROM table1;
+   |                ^^^^^^^^^^^^^^^^^^
+"
+        );
+    }
+
+    #[test]
+    fn parse_error() {
+        let query_string = format!("{}SELECT count(plate) FROM table3;", tables());
+        let err = crate::parser::build(query_string.as_str()).unwrap_err();
+        let diag = Diagnostic::from(&err);
+        assert_eq!(diag.message, "unknown table used in aggregation: table3");
+        assert_eq!((diag.span.line, diag.span.col), (22, 34));
+        assert_eq!(&query_string[diag.span.start..diag.span.end], "table3");
+    }
+
+    #[test]
+    fn reports_all_problems() {
+        let mut policies = policies();
+        policies.remove("cam1");
+        assert_eq!(
+            check(
+                "SELECT count(missing), sum(plate) FROM (table1 UNION table2 ON plate);",
+                policies
+            ),
+            vec![
+                "no privacy policy for camera `cam1`",
+                "UNION column `plate` does not exist in table(s): table2",
+                "column `missing` does not exist",
+                "sum() requires a NUMBER column, but `plate` is a STRING",
+                "sum(`plate`) is unbounded, add explicit bounds: sum(plate,l=..,u=..)",
+            ]
+        );
+    }
+
+    #[test]
+    fn epsilon() {
+        for q in &[
+            "SELECT count(plate) EPSILON 0.2, sum(speed,l=0,u=10) FROM table1 WITH EPSILON 0.5;",
+            "SELECT count(plate) FROM table1 WITH EPSILON 0.5; SELECT count(plate) FROM table1;",
+        ] {
+            assert_eq!(check(q, policies()), Vec::<String>::new(), "{}", q);
+        }
+        assert_eq!(
+            check(
+                "SELECT count(plate) FROM table1 WITH EPSILON 0 WITH EPSILON 0.5;",
+                policies()
+            ),
+            vec![
+                "WITH EPSILON must be positive",
+                "WITH EPSILON is set more than once"
+            ]
+        );
+        assert_eq!(
+            check(
+                "SELECT count(plate) EPSILON 0.5, count(speed) FROM table1 WITH EPSILON 0.5;",
+                policies()
+            ),
+            vec!["the EPSILON of the columns add up to the statement's epsilon 0.5, leaving nothing for the other columns"]
+        );
+        assert_eq!(
+            check(
+                "SELECT count(plate) EPSILON 0.6, count(speed) EPSILON 0.6 FROM table1;",
+                policies()
+            ),
+            vec!["the EPSILON of the columns add up to 1.2, more than the statement's epsilon 1"]
+        );
+        assert_eq!(
+            check(
+                "SELECT count(shift) FROM (SELECT plate, USER_shift(chunk,l=0,u=16) as shift EPSILON 0.1 FROM table1 GROUP BY plate);",
+                policies()
+            ),
+            vec!["EPSILON can only be set on the outermost SELECT"]
+        );
+    }
+
+    #[test]
+    fn epsilon_across_statements() {
+        assert_eq!(
+            check(
+                "SELECT count(plate) FROM table1 WITH EPSILON 0.6; SELECT count(speed) FROM (table1 UNION table2 ON speed) WITH EPSILON 0.6;",
+                policies()
+            ),
+            vec!["the statements reading camera `cam1` use epsilon 1.2 in total, more than the 1 of its policy"]
+        );
+        assert_eq!(
+            check(
+                "SELECT count(plate) FROM table1; SELECT count(speed) FROM table1 WITH EPSILON 1;",
+                policies()
+            ),
+            vec!["no epsilon of camera `cam1` is left for this statement, add WITH EPSILON to it and lower the others"]
+        );
+    }
+
+    #[test]
+    fn sites() {
+        let site = |epsilon: f64| {
+            let mut policies = policies();
+            for camera in ["cam1", "cam2"] {
+                let policy = policies[camera].with_epsilon(epsilon);
+                policies.insert(
+                    String::from(camera),
+                    PrivacyPolicy::Site {
+                        site: String::from("street"),
+                        policy: Box::new(policy),
+                    },
+                );
+            }
+            policies
+        };
+        // Each camera alone has enough epsilon, but not the site
+        assert_eq!(
+            check(
+                "SELECT count(plate) FROM table1 WITH EPSILON 0.6; SELECT count(color) FROM table2 WITH EPSILON 0.6;",
+                site(1.0)
+            ),
+            vec!["the statements reading site `street` use epsilon 1.2 in total, more than the 1 of its policy"]
+        );
+
+        let mut policies = site(1.0);
+        policies.insert(String::from("cam2"), site(0.5)["cam2"].clone());
+        assert_eq!(
+            check("SELECT count(color) FROM table2;", policies),
+            vec!["cameras `cam1` and `cam2` of site `street` have different policies"]
+        );
+    }
+}
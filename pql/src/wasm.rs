@@ -0,0 +1,143 @@
+/*
+ * WebAssembly exports for the in-browser query editor. Build with
+ *
+ *   wasm-pack build --target web -- --no-default-features --features wasm
+ *
+ * Every function returns a JSON string in the same format as the `pql` command-line tool's
+ * `--json` output: `{"ok": true, ...}` on success, `{"ok": false, "diagnostics": [...]}` if the
+ * query is invalid (each diagnostic has a `message` and a `span`), and `{"ok": false, "error": ...}`
+ * if the policies are invalid. Policies are JSON policy files.
+ */
+use crate::config::PolicyConfig;
+use crate::parser;
+use crate::policy::PolicyMap;
+use crate::sensitivity::query_sensitivity;
+use crate::validate::{self, Diagnostic};
+
+use serde_json::{json, Value};
+use wasm_bindgen::prelude::*;
+
+fn diagnostics(diags: &[Diagnostic]) -> Value {
+    json!({ "ok": false, "diagnostics": diags })
+}
+
+fn load_policies(policies: &str) -> Result<PolicyMap, Value> {
+    PolicyConfig::from_json(policies)
+        .map(|config| config.policies)
+        .map_err(|e| json!({ "ok": false, "error": e.to_string() }))
+}
+
+fn build_json(query: &str) -> Value {
+    match parser::build(query) {
+        Ok(query) => json!({ "ok": true, "query": query }),
+        Err(e) => diagnostics(&[Diagnostic::from(&e)]),
+    }
+}
+
+fn validate_json(query: &str, policies: Option<String>) -> Value {
+    let query = match parser::build(query) {
+        Ok(query) => query,
+        Err(e) => return diagnostics(&[Diagnostic::from(&e)]),
+    };
+    let diags = match policies {
+        Some(policies) => match load_policies(&policies) {
+            Ok(policies) => validate::validate(&query, &policies),
+            Err(e) => return e,
+        },
+        None => validate::validate_query(&query),
+    };
+    json!({ "ok": diags.is_empty(), "diagnostics": diags })
+}
+
+fn sensitivity_json(query: &str, policies: &str) -> Value {
+    let policies = match load_policies(policies) {
+        Ok(policies) => policies,
+        Err(e) => return e,
+    };
+    let query = match parser::build(query) {
+        Ok(query) => query,
+        Err(e) => return diagnostics(&[Diagnostic::from(&e)]),
+    };
+    let diags = validate::validate(&query, &policies);
+    if !diags.is_empty() {
+        return diagnostics(&diags);
+    }
+    json!({ "ok": true, "statements": query_sensitivity(&query, &policies) })
+}
+
+/// Parses `query`, returning `{"ok": true, "query": <PQLQuery>}`
+#[wasm_bindgen(js_name = build)]
+pub fn wasm_build(query: &str) -> String {
+    build_json(query).to_string()
+}
+
+/// Returns `{"ok": <no problems>, "diagnostics": [...]}`, checking against `policies` if given
+#[wasm_bindgen(js_name = validate)]
+pub fn wasm_validate(query: &str, policies: Option<String>) -> String {
+    validate_json(query, policies).to_string()
+}
+
+/// Returns `{"ok": true, "statements": [...]}` with the sensitivity and noise scale of each
+/// released column
+#[wasm_bindgen(js_name = sensitivity)]
+pub fn wasm_sensitivity(query: &str, policies: &str) -> String {
+    sensitivity_json(query, policies).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wasm::*;
+
+    const QUERY: &str = "SPLIT cam1 BEGIN 0 END 60 BY TIME 5sec INTO chunks1;
+PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 10 ROWS
+    WITH SCHEMA (plate:STRING=null, speed:NUMBER=0) INTO table1;
+SELECT count(plate), sum(speed,l=0,u=10) FROM table1;
+";
+
+    const POLICIES: &str =
+        r#"{"cameras": {"cam1": {"k_segments": 1, "rho": "30sec", "epsilon": 0.5}}}"#;
+
+    fn call(out: String) -> Value {
+        serde_json::from_str(&out).unwrap()
+    }
+
+    #[test]
+    fn exports() {
+        assert_eq!(call(wasm_build(QUERY))["ok"], true);
+        assert_eq!(
+            call(wasm_validate(QUERY, Some(POLICIES.to_owned()))),
+            json!({ "ok": true, "diagnostics": [] })
+        );
+        let out = call(wasm_sensitivity(QUERY, POLICIES));
+        assert_eq!(out["statements"][0]["columns"][0]["noise_scale"], 280.0);
+    }
+
+    #[test]
+    fn diagnostic_spans() {
+        let out = call(wasm_validate(
+            &QUERY.replace("count(plate)", "sum(plate)"),
+            None,
+        ));
+        assert_eq!(out["ok"], false);
+        assert_eq!(
+            out["diagnostics"][0]["span"],
+            json!({ "start": 185, "end": 195, "line": 4, "col": 8 })
+        );
+
+        let out = call(wasm_build("SPLIT cam1 BEGIN;"));
+        assert_eq!(out["diagnostics"][0]["span"]["line"], 1);
+
+        let out = call(wasm_sensitivity(QUERY, "{}"));
+        assert_eq!(out["ok"], false);
+        assert_eq!(
+            out["diagnostics"][0]["message"],
+            "no privacy policy for camera `cam1`"
+        );
+
+        let out = call(wasm_sensitivity(QUERY, "not json"));
+        assert!(out["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("could not parse"));
+    }
+}
@@ -0,0 +1,51 @@
+/*
+ * Exercises the C API. Build the library with `cargo build --features ffi` first, then run
+ * `make -C tests/ffi`.
+ */
+#include <assert.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "pql.h"
+
+static const char *QUERY =
+    "SPLIT cam1 BEGIN 0 END 60 BY TIME 5sec INTO chunks1;\n"
+    "PROCESS chunks1 USING yolov3 TIMEOUT 1sec PRODUCING 10 ROWS\n"
+    "    WITH SCHEMA (plate:STRING=null, speed:NUMBER=0) INTO table1;\n"
+    "SELECT count(plate), sum(speed,l=0,u=10) FROM table1;\n";
+
+static const char *POLICIES =
+    "{\"cameras\": {\"cam1\": {\"k_segments\": 1, \"rho\": \"30sec\", \"epsilon\": 0.5}}}";
+
+static void test_parse(void) {
+    char *query = pql_parse(QUERY);
+    assert(query != NULL);
+    assert(pql_last_error() == NULL);
+    assert(strstr(query, "\"select_stmts\"") != NULL);
+    pql_free(query);
+
+    assert(pql_parse("SPLIT cam1 BEGIN;") == NULL);
+    assert(strstr(pql_last_error(), "--> 1:") != NULL);
+    assert(pql_parse(NULL) == NULL);
+    assert(strcmp(pql_last_error(), "query is NULL") == 0);
+}
+
+static void test_sensitivity(void) {
+    char *res = pql_sensitivity(QUERY, POLICIES);
+    assert(res != NULL);
+    assert(strstr(res, "\"noise_scale\":2800.0") != NULL);
+    pql_free(res);
+
+    assert(pql_sensitivity(QUERY, "{\"cameras\": {}}") == NULL);
+    assert(strstr(pql_last_error(), "no privacy policy for camera `cam1`") != NULL);
+    assert(pql_sensitivity(QUERY, "not json") == NULL);
+    assert(strstr(pql_last_error(), "could not parse policy file") != NULL);
+    pql_free(NULL);
+}
+
+int main(void) {
+    test_parse();
+    test_sensitivity();
+    printf("ok\n");
+    return 0;
+}